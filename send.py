@@ -1,5 +1,18 @@
-from scapy.all import Ether, sendp
+from scapy.all import Ether, Raw, sendp, sniff
 import psutil
+import random
+import struct
+
+# Request/response protocol of the kernel's ethmsg module, see src/ethmsg.rs
+VERSION = 1
+HEADER = struct.Struct(">BBHH")  # version, kind, id, body length
+KIND_UPTIME = 0x01
+KIND_HEAP_USAGE = 0x02
+KIND_PCI_DEVICES = 0x03
+RESPONSE = 0x80
+KIND_ERROR = 0xff
+ERRORS = {1: "unknown request", 2: "malformed request"}
+PCI_DEVICE = struct.Struct(">BBBHHBB")  # bus, slot, function, vendor id, device id, class, subclass
 
 def get_interfaces():
     interfaces = []
@@ -36,16 +49,69 @@ dst_mac = "ff:ff:ff:ff:ff:ff" # destination MAC address
 src_mac = get_mac_address(interface) # source MAC address
 ether_type = 0x1234 # EtherType
 
-try:
-    num_frames = int(input("Please enter the number of frames you want to send: "))
-    if num_frames <= 0:
-        print("Invalid number, please enter a number greater than 0.")
+def encode_request(kind, request_id):
+    return HEADER.pack(VERSION, kind, request_id, 0)
+
+def decode_response(payload):
+    """Returns (id, description) of a response, the padding of short frames is ignored"""
+    version, kind, response_id, length = HEADER.unpack_from(payload)
+    if version != VERSION:
+        raise ValueError(f"unsupported version {version}")
+    body = payload[HEADER.size:HEADER.size + length]
+    if kind == KIND_UPTIME | RESPONSE:
+        (millis,) = struct.unpack(">Q", body)
+        return response_id, f"uptime {millis / 1000:.3f} s"
+    if kind == KIND_HEAP_USAGE | RESPONSE:
+        size, used = struct.unpack(">QQ", body)
+        return response_id, f"heap {used} of {size} bytes in use"
+    if kind == KIND_PCI_DEVICES | RESPONSE:
+        lines = []
+        for i in range(body[0]):
+            bus, slot, function, vendor, device, cls, subclass = PCI_DEVICE.unpack_from(body, 1 + i * PCI_DEVICE.size)
+            lines.append(f"{bus:02x}:{slot:02x}.{function} {vendor:04x}:{device:04x} class {cls:02x}{subclass:02x}")
+        return response_id, "PCI devices:\n" + "\n".join(lines)
+    if kind == KIND_ERROR:
+        return response_id, f"error: {ERRORS.get(body[0], body[0])}"
+    raise ValueError(f"unknown kind {kind:#x}")
+
+def query(kind):
+    """Sends a request to the kernel and prints its response"""
+    request_id = random.randrange(1 << 16)
+    frame = Ether(dst=dst_mac, src=src_mac, type=ether_type) / Raw(encode_request(kind, request_id))
+
+    def is_response(packet):
+        return (packet.haslayer(Ether) and packet[Ether].type == ether_type
+                and packet[Ether].dst.lower() == src_mac.lower()
+                and bytes(packet[Ether].payload)[:1] == bytes([VERSION])
+                and HEADER.unpack_from(bytes(packet[Ether].payload))[2] == request_id)
+
+    answers = sniff(iface=interface, lfilter=is_response, count=1, timeout=2,
+                    started_callback=lambda: sendp(frame, iface=interface, verbose=False))
+    if not answers:
+        print("No response")
+        return
+    _, description = decode_response(bytes(answers[0][Ether].payload))
+    print(description)
+
+print("0. Send empty frames")
+print("1. Query uptime")
+print("2. Query heap usage")
+print("3. Query PCI devices")
+mode = input("Please choose what to do: ")
+
+if mode in ("1", "2", "3"):
+    query({"1": KIND_UPTIME, "2": KIND_HEAP_USAGE, "3": KIND_PCI_DEVICES}[mode])
+else:
+    try:
+        num_frames = int(input("Please enter the number of frames you want to send: "))
+        if num_frames <= 0:
+            print("Invalid number, please enter a number greater than 0.")
+            num_frames = int(input("Please enter the number of frames you want to send: "))
+    except ValueError:
+        print("Invalid input, please enter a number.")
         num_frames = int(input("Please enter the number of frames you want to send: "))
-except ValueError:
-    print("Invalid input, please enter a number.")
-    num_frames = int(input("Please enter the number of frames you want to send: "))
-
-# Creating that many frames and sending them at the end
-frames = [Ether(dst=dst_mac, src=src_mac, type=ether_type) for _ in range(num_frames)]
-print("Sending following frames: ", frames)
-sendp(frames, iface=interface)
\ No newline at end of file
+
+    # Creating that many frames and sending them at the end
+    frames = [Ether(dst=dst_mac, src=src_mac, type=ether_type) for _ in range(num_frames)]
+    print("Sending following frames: ", frames)
+    sendp(frames, iface=interface)
\ No newline at end of file
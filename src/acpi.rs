@@ -0,0 +1,121 @@
+use crate::memory;
+use core::slice;
+use spin::Once;
+use x86_64::PhysAddr;
+
+// Just enough of ACPI to find its tables, e.g. the MCFG table for PCIe, see
+// <https://wiki.osdev.org/RSDP> and <https://wiki.osdev.org/RSDT>
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+/// The segment of the Extended BIOS Data Area is stored at this address.
+const EBDA_POINTER: u64 = 0x40E;
+/// The RSDP lies in the first KiB of the EBDA or in the BIOS area, on a 16 byte boundary.
+const EBDA_SEARCH_SIZE: u64 = 1024;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// Size of the header every System Description Table starts with.
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// The root table, either the RSDT with 32-bit entries or the XSDT with 64-bit entries.
+#[derive(Debug, Clone, Copy)]
+struct RootTable {
+    address: u64,
+    entry_size: usize,
+}
+
+static ROOT_TABLE: Once<Option<RootTable>> = Once::new();
+
+/// Returns the ACPI table with the given signature, including its header,
+/// if the firmware provides it and its checksum is valid.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let root = (*ROOT_TABLE.call_once(find_root_table))?;
+    let root_table = table_at(root.address)?;
+    root_table[SDT_HEADER_SIZE..]
+        .chunks_exact(root.entry_size)
+        .map(|entry| {
+            let mut address = [0; 8];
+            address[..root.entry_size].copy_from_slice(entry);
+            u64::from_le_bytes(address)
+        })
+        .filter_map(table_at)
+        .find(|table| &table[0..4] == signature)
+}
+
+/// Locates the RSDP and returns the root table it points to,
+/// preferring the XSDT of ACPI 2.0 and later.
+fn find_root_table() -> Option<RootTable> {
+    let ebda_segment = unsafe { physical_bytes(EBDA_POINTER, 2) };
+    let ebda = (u16::from_le_bytes([ebda_segment[0], ebda_segment[1]]) as u64) << 4;
+    let rsdp = find_rsdp(ebda, ebda + EBDA_SEARCH_SIZE)
+        .or_else(|| find_rsdp(BIOS_AREA_START, BIOS_AREA_END))?;
+
+    if rsdp.len() == RSDP_V2_SIZE {
+        let mut xsdt = [0; 8];
+        xsdt.copy_from_slice(&rsdp[24..32]);
+        Some(RootTable { address: u64::from_le_bytes(xsdt), entry_size: 8 })
+    } else {
+        let mut rsdt = [0; 4];
+        rsdt.copy_from_slice(&rsdp[16..20]);
+        Some(RootTable { address: u32::from_le_bytes(rsdt) as u64, entry_size: 4 })
+    }
+}
+
+/// Searches the physical memory between `start` and `end` for a valid RSDP,
+/// returning it with the extended fields if its revision has them.
+fn find_rsdp(start: u64, end: u64) -> Option<&'static [u8]> {
+    if start == 0 {
+        return None;
+    }
+    (start..end).step_by(16).find_map(|address| {
+        let rsdp = unsafe { physical_bytes(address, RSDP_V1_SIZE) };
+        if &rsdp[0..8] != RSDP_SIGNATURE || !has_valid_checksum(rsdp) {
+            return None;
+        }
+        // revision 0 is ACPI 1.0, later revisions have the XSDT address and their own checksum
+        if rsdp[15] >= 2 {
+            let extended = unsafe { physical_bytes(address, RSDP_V2_SIZE) };
+            if has_valid_checksum(extended) {
+                return Some(extended);
+            }
+        }
+        Some(rsdp)
+    })
+}
+
+/// Returns the table at the given physical address if its checksum is valid.
+fn table_at(address: u64) -> Option<&'static [u8]> {
+    if address == 0 {
+        return None;
+    }
+    let header = unsafe { physical_bytes(address, SDT_HEADER_SIZE) };
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+    let table = unsafe { physical_bytes(address, length) };
+    if has_valid_checksum(table) { Some(table) } else { None }
+}
+
+/// All bytes of a table, including its checksum field, add up to zero.
+fn has_valid_checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Returns the bytes at the given physical address in the mapping of physical memory.
+///
+/// The caller has to make sure the memory is there and is never written to,
+/// which holds for the firmware's tables.
+unsafe fn physical_bytes(address: u64, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(memory::phys_to_virt(PhysAddr::new(address)).as_ptr(), len)
+}
+
+#[test_case]
+fn test_find_table() {
+    // QEMU always provides the FADT, whose signature is "FACP"
+    let fadt = find_table(b"FACP").expect("QEMU should provide a FADT");
+    assert_eq!(&fadt[0..4], b"FACP");
+    assert!(find_table(b"NONE").is_none());
+}
@@ -47,6 +47,21 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// Returns the number of bytes handed out and not yet freed.
+    ///
+    /// Freed blocks that wait in the block lists count as unused.
+    pub fn used(&self) -> usize {
+        let mut cached = 0;
+        for (index, head) in self.list_heads.iter().enumerate() {
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                cached += BLOCK_SIZES[index];
+                node = current.next.as_deref();
+            }
+        }
+        self.fallback_allocator.used() - cached
+    }
+
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
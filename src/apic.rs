@@ -0,0 +1,69 @@
+use crate::memory::MmioRegion;
+use spin::Once;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+// Just enough of the local APIC to receive Message Signaled Interrupts,
+// the PICs keep delivering the legacy interrupts through its LINT0 pin,
+// see <https://wiki.osdev.org/APIC>
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// Size of the register page of the local APIC.
+const REGISTERS_SIZE: usize = 0x400;
+
+// Registers
+const ID: usize = 0x20;
+const END_OF_INTERRUPT: usize = 0xB0;
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0xF0;
+
+/// Bit of the Spurious Interrupt Vector Register that enables the local APIC.
+const SOFTWARE_ENABLE: u32 = 0x100;
+/// Vector of the spurious interrupts of the local APIC, which must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+static LOCAL_APIC: Once<Result<MmioRegion, &'static str>> = Once::new();
+
+/// Maps the registers of the local APIC of this CPU and enables it.
+pub fn init() -> Result<(), &'static str> {
+    let registers = LOCAL_APIC
+        .call_once(|| {
+            let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_ADDRESS_MASK;
+            MmioRegion::map(PhysAddr::new(base), REGISTERS_SIZE)
+        })
+        .as_ref()?;
+    let svr = registers.read_32(SPURIOUS_INTERRUPT_VECTOR);
+    registers.write_32(SPURIOUS_INTERRUPT_VECTOR, svr | SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    Ok(())
+}
+
+/// Returns the ID of the local APIC of this CPU, the destination of its MSIs.
+pub fn id() -> Result<u8, &'static str> {
+    let registers = local_apic()?;
+    Ok((registers.read_32(ID) >> 24) as u8)
+}
+
+/// Acknowledges the interrupt being handled, called by the handlers of interrupts
+/// delivered by the local APIC, e.g. MSIs, but not by those of the PICs.
+pub fn end_of_interrupt() {
+    if let Ok(registers) = local_apic() {
+        registers.write_32(END_OF_INTERRUPT, 0);
+    }
+}
+
+fn local_apic() -> Result<&'static MmioRegion, &'static str> {
+    match LOCAL_APIC.r#try() {
+        Some(Ok(registers)) => Ok(registers),
+        Some(Err(err)) => Err(err),
+        None => Err("local APIC not initialized"),
+    }
+}
+
+#[test_case]
+fn test_local_apic() {
+    // `init` of the kernel enabled the local APIC
+    let registers = local_apic().expect("the local APIC should be mapped");
+    assert_ne!(registers.read_32(SPURIOUS_INTERRUPT_VECTOR) & SOFTWARE_ENABLE, 0);
+    assert_eq!(registers.read_32(SPURIOUS_INTERRUPT_VECTOR) as u8, SPURIOUS_VECTOR);
+    // QEMU runs the kernel on a single CPU with the local APIC ID 0
+    assert_eq!(id(), Ok(0));
+}
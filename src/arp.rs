@@ -0,0 +1,314 @@
+use crate::{ethernet, netconfig, netstat, time};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+const PROTOCOL_TYPE_IPV4: u16 = 0x0800;
+const HARDWARE_ADDRESS_LENGTH: u8 = 6;
+const PROTOCOL_ADDRESS_LENGTH: u8 = 4;
+
+// Operation
+pub const OPERATION_REQUEST: u16 = 1;
+pub const OPERATION_REPLY: u16 = 2;
+
+/// Size of an ARP packet for IPv4 over Ethernet
+const PACKET_SIZE: usize = 28;
+
+/// Time after which a neighbor has to be resolved again
+const ENTRY_LIFETIME: u64 = 60 * time::TICKS_PER_SECOND;
+/// Time to wait for a reply before a request is sent again
+const REQUEST_TIMEOUT: u64 = time::TICKS_PER_SECOND;
+const REQUEST_RETRIES: u32 = 3;
+/// Maximum number of datagrams waiting for their destination to be resolved,
+/// all fragments of a datagram count as one
+const MAX_PENDING_DATAGRAMS: usize = 16;
+
+/// ARP packet for resolving IPv4 addresses to Ethernet MAC addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: [u8; 4],
+    pub target_mac: [u8; 6],
+    pub target_ip: [u8; 4],
+}
+
+impl ArpPacket {
+    // returns the whole packet in bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::with_capacity(PACKET_SIZE);
+        result.extend_from_slice(&HARDWARE_TYPE_ETHERNET.to_be_bytes());
+        result.extend_from_slice(&PROTOCOL_TYPE_IPV4.to_be_bytes());
+        result.push(HARDWARE_ADDRESS_LENGTH);
+        result.push(PROTOCOL_ADDRESS_LENGTH);
+        result.extend_from_slice(&self.operation.to_be_bytes());
+        result.extend_from_slice(&self.sender_mac);
+        result.extend_from_slice(&self.sender_ip);
+        result.extend_from_slice(&self.target_mac);
+        result.extend_from_slice(&self.target_ip);
+
+        result
+    }
+
+    /// Parses an ARP packet for IPv4 over Ethernet,
+    /// ignoring any padding behind it
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < PACKET_SIZE {
+            return Err("ARP packet too short");
+        }
+        if u16::from_be_bytes([bytes[0], bytes[1]]) != HARDWARE_TYPE_ETHERNET
+            || u16::from_be_bytes([bytes[2], bytes[3]]) != PROTOCOL_TYPE_IPV4
+            || bytes[4] != HARDWARE_ADDRESS_LENGTH
+            || bytes[5] != PROTOCOL_ADDRESS_LENGTH
+        {
+            return Err("ARP packet is not IPv4 over Ethernet");
+        }
+
+        let mut packet = ArpPacket {
+            operation: u16::from_be_bytes([bytes[6], bytes[7]]),
+            sender_mac: [0; 6],
+            sender_ip: [0; 4],
+            target_mac: [0; 6],
+            target_ip: [0; 4],
+        };
+        packet.sender_mac.copy_from_slice(&bytes[8..14]);
+        packet.sender_ip.copy_from_slice(&bytes[14..18]);
+        packet.target_mac.copy_from_slice(&bytes[18..24]);
+        packet.target_ip.copy_from_slice(&bytes[24..28]);
+        Ok(packet)
+    }
+}
+
+/// Entry of the neighbor cache
+struct Neighbor {
+    mac: [u8; 6],
+    updated: u64,
+}
+
+impl Neighbor {
+    fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.updated) >= ENTRY_LIFETIME
+    }
+}
+
+// IPv4 addresses of neighbors and their MAC addresses
+static NEIGHBORS: Mutex<BTreeMap<[u8; 4], Neighbor>> = Mutex::new(BTreeMap::new());
+
+// Wakers of tasks waiting for a neighbor to be resolved
+static WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+/// Frames of a datagram that are sent as soon as the MAC address of their destination is known
+struct PendingDatagram {
+    ip: [u8; 4],
+    protocol: u16,
+    payloads: Vec<Vec<u8>>,
+    queued: u64,
+}
+
+// Datagrams waiting for their destination to be resolved
+static PENDING_DATAGRAMS: Mutex<Vec<PendingDatagram>> = Mutex::new(Vec::new());
+
+/// Registers ARP with `ethernet` to receive its frames
+pub fn init() {
+    ethernet::register_handler(ETHERTYPE_ARP, |frame| handle_packet(frame.payload))
+        .expect("ARP handler registered twice");
+}
+
+/// Handles an ARP packet received in an Ethernet frame:
+/// - Updates the neighbor cache with the sender's addresses
+/// - Replies to requests for our own IPv4 address
+pub fn handle_packet(payload: &[u8]) {
+    let packet = match ArpPacket::from_bytes(payload) {
+        Ok(packet) => packet,
+        Err(_) => {
+            netstat::ARP.count_dropped();
+            return;
+        }
+    };
+    netstat::ARP.count_received();
+    let own_ip = netconfig::ipv4_address();
+    let for_us = netconfig::is_configured() && packet.target_ip == own_ip;
+
+    // As in RFC 826, existing entries are updated by every packet,
+    // but new entries are only created for packets addressed to us
+    if packet.sender_ip != [0; 4] && (for_us || lookup(packet.sender_ip).is_some()) {
+        update_neighbor(packet.sender_ip, packet.sender_mac);
+    }
+
+    if for_us && packet.operation == OPERATION_REQUEST {
+        let reply = ArpPacket {
+            operation: OPERATION_REPLY,
+            sender_mac: ethernet::mac_address(),
+            sender_ip: own_ip,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        if ethernet::send_payload(packet.sender_mac, ETHERTYPE_ARP, reply.to_bytes()).is_ok() {
+            netstat::ARP.count_sent();
+        }
+    }
+}
+
+/// Inserts or refreshes a neighbor cache entry
+/// and wakes all tasks waiting for a resolution
+fn update_neighbor(ip: [u8; 4], mac: [u8; 6]) {
+    let now = time::ticks();
+    {
+        let mut neighbors = NEIGHBORS.lock();
+        neighbors.retain(|_, neighbor| !neighbor.is_expired(now));
+        neighbors.insert(ip, Neighbor { mac, updated: now });
+    }
+
+    let waiters: Vec<Waker> = WAITERS.lock().drain(..).collect();
+    for waker in waiters {
+        waker.wake();
+    }
+
+    let resolved: Vec<PendingDatagram> = {
+        let mut pending_datagrams = PENDING_DATAGRAMS.lock();
+        let (resolved, unresolved) = pending_datagrams.drain(..).partition(|datagram| datagram.ip == ip);
+        *pending_datagrams = unresolved;
+        resolved
+    };
+    for datagram in resolved {
+        for payload in datagram.payloads {
+            let _ = ethernet::send_payload(mac, datagram.protocol, payload);
+        }
+    }
+}
+
+/// Returns the cached MAC address of the given IPv4 address, if it has not expired
+pub fn lookup(ip: [u8; 4]) -> Option<[u8; 6]> {
+    let now = time::ticks();
+    NEIGHBORS
+        .lock()
+        .get(&ip)
+        .filter(|neighbor| !neighbor.is_expired(now))
+        .map(|neighbor| neighbor.mac)
+}
+
+/// Returns all unexpired neighbor cache entries
+/// together with their age in timer ticks
+pub fn neighbors() -> Vec<([u8; 4], [u8; 6], u64)> {
+    let now = time::ticks();
+    NEIGHBORS
+        .lock()
+        .iter()
+        .filter(|(_, neighbor)| !neighbor.is_expired(now))
+        .map(|(ip, neighbor)| (*ip, neighbor.mac, now - neighbor.updated))
+        .collect()
+}
+
+/// Removes all entries from the neighbor cache
+pub fn flush() {
+    NEIGHBORS.lock().clear();
+}
+
+/// Broadcasts an ARP request for the given IPv4 address
+pub fn send_request(ip: [u8; 4]) {
+    let request = ArpPacket {
+        operation: OPERATION_REQUEST,
+        sender_mac: ethernet::mac_address(),
+        sender_ip: netconfig::ipv4_address(),
+        target_mac: [0; 6],
+        target_ip: ip,
+    };
+    if ethernet::send_payload(ethernet::BROADCAST_MAC, ETHERTYPE_ARP, request.to_bytes()).is_ok() {
+        netstat::ARP.count_sent();
+    }
+}
+
+/// Sends the payload to the neighbor with the given IPv4 address
+///
+/// If the neighbor is not in the cache yet, the payload is queued
+/// and an ARP request is sent. The payload is sent once the reply arrives,
+/// or dropped if none arrives before the request times out.
+pub fn send_payload(ip: [u8; 4], protocol: u16, payload: Vec<u8>) {
+    send_payloads(ip, protocol, alloc::vec![payload]);
+}
+
+/// Sends the payloads, e.g. the fragments of a datagram, to the neighbor with the given IPv4 address
+///
+/// Like `send_payload`, but the payloads wait for the neighbor together
+/// and only take up one place in the queue.
+pub fn send_payloads(ip: [u8; 4], protocol: u16, payloads: Vec<Vec<u8>>) {
+    if let Some(mac) = lookup(ip) {
+        for payload in payloads {
+            let _ = ethernet::send_payload(mac, protocol, payload);
+        }
+        return;
+    }
+
+    let now = time::ticks();
+    {
+        let mut pending_datagrams = PENDING_DATAGRAMS.lock();
+        pending_datagrams.retain(|datagram| now - datagram.queued < REQUEST_TIMEOUT * REQUEST_RETRIES as u64);
+        if pending_datagrams.len() >= MAX_PENDING_DATAGRAMS {
+            pending_datagrams.remove(0);
+        }
+        pending_datagrams.push(PendingDatagram { ip, protocol, payloads, queued: now });
+    }
+    send_request(ip);
+}
+
+/// Resolves the given IPv4 address to a MAC address,
+/// using the neighbor cache or sending ARP requests
+///
+/// Returns `None` if no reply arrived after all retries.
+pub async fn resolve(ip: [u8; 4]) -> Option<[u8; 6]> {
+    if ip == [0xff; 4] {
+        return Some(ethernet::BROADCAST_MAC);
+    }
+
+    for _ in 0..REQUEST_RETRIES {
+        if let Some(mac) = lookup(ip) {
+            return Some(mac);
+        }
+        send_request(ip);
+        if let Some(mac) = time::timeout(REQUEST_TIMEOUT, NeighborResolved { ip }).await {
+            return Some(mac);
+        }
+    }
+    None
+}
+
+/// Future that completes once the neighbor cache contains the given IPv4 address
+struct NeighborResolved {
+    ip: [u8; 4],
+}
+
+impl Future for NeighborResolved {
+    type Output = [u8; 6];
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<[u8; 6]> {
+        if let Some(mac) = lookup(self.ip) {
+            return Poll::Ready(mac);
+        }
+        let mut waiters = WAITERS.lock();
+        if !waiters.iter().any(|waiter| waiter.will_wake(cx.waker())) {
+            waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_arp_packet_round_trip() {
+    let packet = ArpPacket {
+        operation: OPERATION_REPLY,
+        sender_mac: [0x00, 0x11, 0x22, 0x33, 0x44, 0x55],
+        sender_ip: [10, 0, 2, 15],
+        target_mac: [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02],
+        target_ip: [10, 0, 2, 2],
+    };
+    let bytes = packet.to_bytes();
+    assert_eq!(bytes.len(), PACKET_SIZE);
+    assert_eq!(ArpPacket::from_bytes(&bytes), Ok(packet));
+}
@@ -0,0 +1,474 @@
+use crate::{
+    arp, ethernet, ipv4,
+    netconfig::{self, InterfaceConfig},
+    netdev::{self, LinkStatus},
+    println, time,
+    udp::UdpSocket,
+};
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use futures_util::{future::select, pin_mut};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+// Operation
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+
+const HARDWARE_TYPE_ETHERNET: u8 = 1;
+/// Asks the server to broadcast its replies, as we cannot receive unicasts before having an address
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Size of the fixed part of a DHCP message, up to and including the magic cookie
+const FIXED_SIZE: usize = 240;
+
+// Option
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+// Message Type
+pub const DHCPDISCOVER: u8 = 1;
+pub const DHCPOFFER: u8 = 2;
+pub const DHCPREQUEST: u8 = 3;
+pub const DHCPDECLINE: u8 = 4;
+pub const DHCPACK: u8 = 5;
+pub const DHCPNAK: u8 = 6;
+pub const DHCPRELEASE: u8 = 7;
+
+/// Time to wait for the first reply, doubled with every retry (RFC 2131, 4.1)
+const INITIAL_TIMEOUT: u64 = 4 * time::TICKS_PER_SECOND;
+const MAX_TIMEOUT: u64 = 64 * time::TICKS_PER_SECOND;
+/// Requests sent during the REQUESTING state before starting over
+const REQUEST_RETRIES: u32 = 4;
+/// Minimum time between two requests while renewing or rebinding a lease
+const MIN_RENEW_INTERVAL: u64 = 60 * time::TICKS_PER_SECOND;
+
+/// DHCP message, a BOOTP message with DHCP options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpMessage {
+    pub op: u8,
+    pub xid: u32,
+    pub flags: u16,
+    /// Client address, if the client already has one
+    pub ciaddr: [u8; 4],
+    /// Address offered to or leased by the client
+    pub yiaddr: [u8; 4],
+    pub siaddr: [u8; 4],
+    pub giaddr: [u8; 4],
+    pub chaddr: [u8; 6],
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+
+impl DhcpMessage {
+    /// Creates a client request of the given DHCP message type
+    pub fn request(message_type: u8, xid: u32, mac: [u8; 6]) -> Self {
+        DhcpMessage {
+            op: BOOT_REQUEST,
+            xid,
+            flags: FLAG_BROADCAST,
+            ciaddr: [0; 4],
+            yiaddr: [0; 4],
+            siaddr: [0; 4],
+            giaddr: [0; 4],
+            chaddr: mac,
+            options: alloc::vec![(OPTION_MESSAGE_TYPE, alloc::vec![message_type])],
+        }
+    }
+
+    // returns the whole message in bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::with_capacity(FIXED_SIZE + 64);
+        result.push(self.op);
+        result.push(HARDWARE_TYPE_ETHERNET);
+        result.push(self.chaddr.len() as u8);
+        result.push(0);
+        result.extend_from_slice(&self.xid.to_be_bytes());
+        result.extend_from_slice(&[0, 0]);
+        result.extend_from_slice(&self.flags.to_be_bytes());
+        result.extend_from_slice(&self.ciaddr);
+        result.extend_from_slice(&self.yiaddr);
+        result.extend_from_slice(&self.siaddr);
+        result.extend_from_slice(&self.giaddr);
+        result.extend_from_slice(&self.chaddr);
+        // rest of chaddr, sname and file
+        result.resize(FIXED_SIZE - MAGIC_COOKIE.len(), 0);
+        result.extend_from_slice(&MAGIC_COOKIE);
+        for (code, value) in &self.options {
+            result.push(*code);
+            result.push(value.len() as u8);
+            result.extend_from_slice(value);
+        }
+        result.push(OPTION_END);
+
+        result
+    }
+
+    /// Parses a DHCP message, ignoring BOOTP messages without DHCP options
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < FIXED_SIZE {
+            return Err("DHCP message too short");
+        }
+        if bytes[FIXED_SIZE - MAGIC_COOKIE.len()..FIXED_SIZE] != MAGIC_COOKIE {
+            return Err("not a DHCP message");
+        }
+        if bytes[1] != HARDWARE_TYPE_ETHERNET || bytes[2] != 6 {
+            return Err("DHCP message is not for an Ethernet client");
+        }
+
+        let mut options = Vec::new();
+        let mut rest = &bytes[FIXED_SIZE..];
+        while let Some(&code) = rest.first() {
+            match code {
+                OPTION_END => break,
+                OPTION_PAD => rest = &rest[1..],
+                _ => {
+                    let length = *rest.get(1).ok_or("truncated DHCP option")? as usize;
+                    let value = rest.get(2..2 + length).ok_or("truncated DHCP option")?;
+                    options.push((code, Vec::from(value)));
+                    rest = &rest[2 + length..];
+                }
+            }
+        }
+
+        let address = |offset: usize| [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+        let mut chaddr = [0; 6];
+        chaddr.copy_from_slice(&bytes[28..34]);
+        Ok(DhcpMessage {
+            op: bytes[0],
+            xid: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            flags: u16::from_be_bytes([bytes[10], bytes[11]]),
+            ciaddr: address(12),
+            yiaddr: address(16),
+            siaddr: address(20),
+            giaddr: address(24),
+            chaddr,
+            options,
+        })
+    }
+
+    /// Returns the value of the first option with the given code
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == code)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the value of an option holding a single IPv4 address
+    pub fn address_option(&self, code: u8) -> Option<[u8; 4]> {
+        self.addresses_option(code).into_iter().next()
+    }
+
+    /// Returns the value of an option holding a list of IPv4 addresses
+    pub fn addresses_option(&self, code: u8) -> Vec<[u8; 4]> {
+        self.option(code)
+            .map(|value| {
+                value
+                    .chunks_exact(4)
+                    .map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of an option holding a time in seconds
+    pub fn seconds_option(&self, code: u8) -> Option<u32> {
+        self.option(code)
+            .filter(|value| value.len() == 4)
+            .map(|value| u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    pub fn message_type(&self) -> Option<u8> {
+        self.option(OPTION_MESSAGE_TYPE)
+            .and_then(|value| value.first().copied())
+    }
+}
+
+/// Address and configuration leased from a DHCP server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub address: [u8; 4],
+    pub netmask: [u8; 4],
+    pub gateway: Option<[u8; 4]>,
+    pub dns_servers: Vec<[u8; 4]>,
+    pub server: [u8; 4],
+    /// Tick count at which the lease was acknowledged
+    pub acquired: u64,
+    /// Lease time (T3), renewal time (T1) and rebinding time (T2) in seconds
+    pub lease_time: u32,
+    pub renewal_time: u32,
+    pub rebinding_time: u32,
+}
+
+impl Lease {
+    /// Creates a lease from a DHCPACK, using the defaults of RFC 2131, 4.4.5 for T1 and T2
+    fn from_ack(ack: &DhcpMessage, server: [u8; 4]) -> Self {
+        let lease_time = ack.seconds_option(OPTION_LEASE_TIME).unwrap_or(u32::MAX);
+        Lease {
+            address: ack.yiaddr,
+            netmask: ack
+                .address_option(OPTION_SUBNET_MASK)
+                .unwrap_or([255, 255, 255, 0]),
+            gateway: ack.address_option(OPTION_ROUTER),
+            dns_servers: ack.addresses_option(OPTION_DNS_SERVER),
+            server: ack.address_option(OPTION_SERVER_IDENTIFIER).unwrap_or(server),
+            acquired: time::ticks(),
+            lease_time,
+            renewal_time: ack
+                .seconds_option(OPTION_RENEWAL_TIME)
+                .unwrap_or(lease_time / 2),
+            rebinding_time: ack
+                .seconds_option(OPTION_REBINDING_TIME)
+                .unwrap_or((lease_time as u64 * 7 / 8) as u32),
+        }
+    }
+
+    /// Returns the tick count `seconds` after the lease was acquired
+    fn deadline(&self, seconds: u32) -> u64 {
+        self.acquired + seconds as u64 * time::TICKS_PER_SECOND
+    }
+}
+
+/// Outcome of the DHCPREQUEST messages of one transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RequestResult {
+    /// The server acknowledged the request with this lease
+    Ack(Lease),
+    /// The server refused the request, the address must no longer be used
+    Nak,
+    /// No server answered in time
+    NoReply,
+}
+
+/// Creates a transaction ID, which should differ between clients and boots
+fn new_xid() -> u32 {
+    let mac = ethernet::mac_address();
+    u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]) ^ (time::ticks() as u32).wrapping_mul(2_654_435_761)
+}
+
+/// Waits for a reply to the transaction `xid` until the given tick count
+async fn receive_reply(socket: &UdpSocket, xid: u32, deadline: u64) -> Option<(DhcpMessage, [u8; 4])> {
+    let mac = ethernet::mac_address();
+    loop {
+        let now = time::ticks();
+        if now >= deadline {
+            return None;
+        }
+        let (payload, src, _) = time::timeout(deadline - now, socket.recv_from()).await?;
+        if let Ok(message) = DhcpMessage::from_bytes(&payload) {
+            if message.op == BOOT_REPLY && message.xid == xid && message.chaddr == mac {
+                return Some((message, src));
+            }
+        }
+    }
+}
+
+/// Broadcasts DHCPDISCOVER messages until a server makes an offer
+async fn discover(socket: &UdpSocket) -> (DhcpMessage, [u8; 4]) {
+    let mut timeout = INITIAL_TIMEOUT;
+    loop {
+        let xid = new_xid();
+        let mut discover = DhcpMessage::request(DHCPDISCOVER, xid, ethernet::mac_address());
+        discover.options.push((
+            OPTION_PARAMETER_REQUEST_LIST,
+            alloc::vec![OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVER],
+        ));
+        let _ = socket
+            .send_to(&discover.to_bytes(), ipv4::BROADCAST_ADDRESS, SERVER_PORT)
+            .await;
+
+        let deadline = time::ticks() + timeout;
+        while let Some((offer, src)) = receive_reply(socket, xid, deadline).await {
+            if offer.message_type() == Some(DHCPOFFER) {
+                return (offer, src);
+            }
+        }
+        timeout = min(timeout * 2, MAX_TIMEOUT);
+    }
+}
+
+/// Sends DHCPREQUEST messages for the given address until a server answers
+/// with an ACK or NAK or `deadline` has passed
+///
+/// `renewing` is the address the request is sent to once the client is bound:
+/// the leasing server while renewing, the broadcast address while rebinding.
+/// `xid` is kept for retransmissions, while requesting it has to be the one of the offer.
+async fn request(
+    socket: &UdpSocket,
+    xid: u32,
+    requested: [u8; 4],
+    server_identifier: Option<[u8; 4]>,
+    renewing: Option<[u8; 4]>,
+    deadline: u64,
+) -> RequestResult {
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..REQUEST_RETRIES {
+        let now = time::ticks();
+        if now >= deadline {
+            return RequestResult::NoReply;
+        }
+        let mut request = DhcpMessage::request(DHCPREQUEST, xid, ethernet::mac_address());
+        match renewing {
+            // RENEWING and REBINDING: the client already has the address,
+            // so it can receive the reply sent to it by unicast
+            Some(_) => {
+                request.ciaddr = requested;
+                request.flags &= !FLAG_BROADCAST;
+            }
+            // REQUESTING: the address and server come from the offer
+            None => request.options.push((OPTION_REQUESTED_ADDRESS, Vec::from(&requested[..]))),
+        }
+        if let Some(server) = server_identifier {
+            request.options.push((OPTION_SERVER_IDENTIFIER, Vec::from(&server[..])));
+        }
+        request.options.push((
+            OPTION_PARAMETER_REQUEST_LIST,
+            alloc::vec![OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVER],
+        ));
+        let dst = renewing.unwrap_or(ipv4::BROADCAST_ADDRESS);
+        let _ = socket.send_to(&request.to_bytes(), dst, SERVER_PORT).await;
+
+        let reply_deadline = min(now + timeout, deadline);
+        while let Some((reply, src)) = receive_reply(socket, xid, reply_deadline).await {
+            match reply.message_type() {
+                Some(DHCPACK) => return RequestResult::Ack(Lease::from_ack(&reply, src)),
+                Some(DHCPNAK) => return RequestResult::Nak,
+                _ => {}
+            }
+        }
+        timeout = min(timeout * 2, MAX_TIMEOUT);
+    }
+    RequestResult::NoReply
+}
+
+/// Configures the interface with a lease
+fn apply_lease(lease: &Lease) {
+    netconfig::set_config(InterfaceConfig {
+        address: lease.address,
+        netmask: lease.netmask,
+        gateway: lease.gateway,
+        dns_servers: lease.dns_servers.clone(),
+    });
+}
+
+/// Returns the time to wait before the next request while renewing or rebinding:
+/// half the remaining time until `deadline`, but at least MIN_RENEW_INTERVAL (RFC 2131, 4.4.5)
+fn renew_interval(deadline: u64) -> u64 {
+    max(deadline.saturating_sub(time::ticks()) / 2, MIN_RENEW_INTERVAL)
+}
+
+/// Runs the DHCP client: leases an address, configures the interface with it
+/// and renews the lease for as long as the kernel runs
+///
+/// When the link of the default interface goes down, the configuration is dropped
+/// and the client starts over with a new DISCOVER once the link is back up.
+pub async fn run_client() {
+    let socket = match UdpSocket::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("DHCP: {}", err);
+            return;
+        }
+    };
+    let mut events = netdev::subscribe_link_events();
+
+    loop {
+        {
+            let client = lease_addresses(&socket);
+            let link_down = events.wait_for(0, LinkStatus::Down);
+            pin_mut!(client, link_down);
+            select(client, link_down).await;
+        }
+
+        println!("DHCP: link down, dropping configuration");
+        netconfig::set_config(InterfaceConfig::unconfigured());
+        arp::flush();
+        events.wait_for(0, LinkStatus::Up).await;
+        println!("DHCP: link up, restarting");
+    }
+}
+
+/// Leases addresses and keeps them renewed, starting over whenever a lease expires
+/// or the server refuses to extend it
+async fn lease_addresses(socket: &UdpSocket) {
+    loop {
+        // INIT and SELECTING
+        let (offer, offer_src) = discover(socket).await;
+        let server = offer.address_option(OPTION_SERVER_IDENTIFIER).unwrap_or(offer_src);
+
+        // REQUESTING
+        let mut lease = match request(socket, offer.xid, offer.yiaddr, Some(server), None, u64::MAX).await {
+            RequestResult::Ack(lease) => lease,
+            RequestResult::Nak | RequestResult::NoReply => continue,
+        };
+
+        // BOUND, RENEWING and REBINDING
+        loop {
+            apply_lease(&lease);
+            println!(
+                "DHCP: leased {}.{}.{}.{} from {}.{}.{}.{} for {} s",
+                lease.address[0], lease.address[1], lease.address[2], lease.address[3],
+                lease.server[0], lease.server[1], lease.server[2], lease.server[3],
+                lease.lease_time
+            );
+
+            let renewal = lease.deadline(lease.renewal_time);
+            let rebinding = lease.deadline(lease.rebinding_time);
+            let expiry = lease.deadline(lease.lease_time);
+
+            time::sleep_until(renewal).await;
+            let mut renewed = RequestResult::NoReply;
+            while renewed == RequestResult::NoReply && time::ticks() < rebinding {
+                renewed = request(socket, new_xid(), lease.address, None, Some(lease.server), rebinding).await;
+                if renewed == RequestResult::NoReply {
+                    time::sleep(min(renew_interval(rebinding), rebinding.saturating_sub(time::ticks()))).await;
+                }
+            }
+            while renewed == RequestResult::NoReply && time::ticks() < expiry {
+                renewed = request(socket, new_xid(), lease.address, None, Some(ipv4::BROADCAST_ADDRESS), expiry).await;
+                if renewed == RequestResult::NoReply {
+                    time::sleep(min(renew_interval(expiry), expiry.saturating_sub(time::ticks()))).await;
+                }
+            }
+
+            match renewed {
+                RequestResult::Ack(renewed) => lease = renewed,
+                RequestResult::Nak => {
+                    println!("DHCP: lease refused by server");
+                    break;
+                }
+                RequestResult::NoReply => {
+                    println!("DHCP: lease expired");
+                    break;
+                }
+            }
+        }
+
+        // back to INIT, the address must not be used anymore
+        netconfig::set_config(InterfaceConfig::unconfigured());
+    }
+}
+
+#[test_case]
+fn test_message_round_trip() {
+    let mut message = DhcpMessage::request(DHCPREQUEST, 0xdeadbeef, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    message.options.push((OPTION_REQUESTED_ADDRESS, alloc::vec![10, 0, 2, 15]));
+    message.options.push((OPTION_LEASE_TIME, 86400u32.to_be_bytes().to_vec()));
+
+    let parsed = DhcpMessage::from_bytes(&message.to_bytes()).unwrap();
+    assert_eq!(parsed, message);
+    assert_eq!(parsed.message_type(), Some(DHCPREQUEST));
+    assert_eq!(parsed.address_option(OPTION_REQUESTED_ADDRESS), Some([10, 0, 2, 15]));
+    assert_eq!(parsed.seconds_option(OPTION_LEASE_TIME), Some(86400));
+}
@@ -0,0 +1,393 @@
+use crate::{netconfig, time, udp::UdpSocket};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Mutex;
+
+pub const SERVER_PORT: u16 = 53;
+
+/// Size of the DNS header in bytes
+const HEADER_SIZE: usize = 12;
+/// Largest message sent over UDP without EDNS (RFC 1035, 4.2.1)
+const MAX_MESSAGE_SIZE: usize = 512;
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_LABEL_LENGTH: usize = 63;
+/// Compression pointers followed while reading a name, to catch loops
+const MAX_POINTERS: usize = 16;
+
+// Flags
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+
+// Response Code
+const RCODE_NO_ERROR: u16 = 0;
+const RCODE_NAME_ERROR: u16 = 3;
+
+// Type
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+// Class
+pub const CLASS_IN: u16 = 1;
+
+/// Time to wait for a response before sending the query again
+const QUERY_TIMEOUT: u64 = 2 * time::TICKS_PER_SECOND;
+/// Queries sent to each server before giving up on it
+const QUERY_RETRIES: u32 = 3;
+/// CNAME records followed before giving up on a name
+const MAX_CNAME_DEPTH: usize = 8;
+/// Number of names held in the cache before expired ones are evicted
+const MAX_CACHE_ENTRIES: usize = 64;
+
+// ID of the next query
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Resolved address of a name with the tick count it expires at
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    address: [u8; 4],
+    expires: u64,
+}
+
+// Resolved names, lowercased
+static CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+
+/// Resource record of a DNS response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceRecord {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+    /// Time the record may be cached for in seconds
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// Data of a resource record, for the types the resolver understands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A([u8; 4]),
+    Cname(String),
+    Other(Vec<u8>),
+}
+
+/// Parsed DNS response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsResponse {
+    pub id: u16,
+    pub rcode: u16,
+    pub answers: Vec<ResourceRecord>,
+}
+
+/// Builds a query with recursion desired for the A record of the given name
+pub fn build_query(id: u16, name: &str) -> Result<Vec<u8>, &'static str> {
+    let mut result: Vec<u8> = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    result.extend_from_slice(&id.to_be_bytes());
+    result.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // one question, no answer, authority or additional records
+    result.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(name, &mut result)?;
+    result.extend_from_slice(&TYPE_A.to_be_bytes());
+    result.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(result)
+}
+
+/// Appends the name as a sequence of length prefixed labels
+fn encode_name(name: &str, buffer: &mut Vec<u8>) -> Result<(), &'static str> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() + 2 > MAX_NAME_LENGTH {
+        return Err("invalid hostname length");
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            return Err("invalid hostname label");
+        }
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label.as_bytes());
+    }
+    buffer.push(0);
+    Ok(())
+}
+
+/// Reads the possibly compressed name at `offset` of the message
+///
+/// Returns the name in dotted form and the offset behind it.
+fn decode_name(message: &[u8], offset: usize) -> Result<(String, usize), &'static str> {
+    let mut name = String::new();
+    let mut position = offset;
+    // offset behind the name, known once the first pointer is read
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let length = *message.get(position).ok_or("truncated DNS name")? as usize;
+        match length {
+            0 => break,
+            // pointer to a name earlier in the message
+            _ if length & 0xc0 == 0xc0 => {
+                let low = *message.get(position + 1).ok_or("truncated DNS name")? as usize;
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err("DNS name compression loop");
+                }
+                end.get_or_insert(position + 2);
+                position = (length & 0x3f) << 8 | low;
+            }
+            _ if length > MAX_LABEL_LENGTH => return Err("invalid DNS label"),
+            _ => {
+                let label = message
+                    .get(position + 1..position + 1 + length)
+                    .ok_or("truncated DNS name")?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.extend(label.iter().map(|&byte| byte.to_ascii_lowercase() as char));
+                if name.len() > MAX_NAME_LENGTH {
+                    return Err("DNS name too long");
+                }
+                position += 1 + length;
+            }
+        }
+    }
+
+    Ok((name, end.unwrap_or(position + 1)))
+}
+
+/// Reads the big-endian u16 at `offset` of the message
+fn read_u16(message: &[u8], offset: usize) -> Result<u16, &'static str> {
+    message
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or("truncated DNS message")
+}
+
+impl DnsResponse {
+    /// Parses a response, skipping its questions and ignoring authority and additional records
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("DNS message too short");
+        }
+        let flags = read_u16(bytes, 2)?;
+        if flags & FLAG_RESPONSE == 0 {
+            return Err("DNS message is not a response");
+        }
+        let question_count = read_u16(bytes, 4)?;
+        let answer_count = read_u16(bytes, 6)?;
+
+        let mut offset = HEADER_SIZE;
+        for _ in 0..question_count {
+            let (_, end) = decode_name(bytes, offset)?;
+            // type and class
+            offset = end + 4;
+        }
+
+        let mut answers = Vec::with_capacity(answer_count as usize);
+        for _ in 0..answer_count {
+            let (name, end) = decode_name(bytes, offset)?;
+            let record_type = read_u16(bytes, end)?;
+            let class = read_u16(bytes, end + 2)?;
+            let ttl = u32::from(read_u16(bytes, end + 4)?) << 16 | u32::from(read_u16(bytes, end + 6)?);
+            let data_length = read_u16(bytes, end + 8)? as usize;
+            let data_offset = end + 10;
+            let data = bytes
+                .get(data_offset..data_offset + data_length)
+                .ok_or("truncated DNS record")?;
+
+            let data = match record_type {
+                TYPE_A if data.len() == 4 => RecordData::A([data[0], data[1], data[2], data[3]]),
+                TYPE_CNAME => RecordData::Cname(decode_name(bytes, data_offset)?.0),
+                _ => RecordData::Other(Vec::from(data)),
+            };
+            answers.push(ResourceRecord { name, record_type, class, ttl, data });
+            offset = data_offset + data_length;
+        }
+
+        Ok(DnsResponse {
+            id: read_u16(bytes, 0)?,
+            rcode: flags & RCODE_MASK,
+            answers,
+        })
+    }
+
+    /// Follows the CNAME records of the answer section from `name` to an address
+    ///
+    /// Returns the canonical name and, if the response contains one,
+    /// its address with the smallest TTL along the chain.
+    fn lookup(&self, name: &str) -> (String, Option<([u8; 4], u32)>) {
+        let mut name = String::from(name);
+        let mut ttl = u32::MAX;
+        for _ in 0..MAX_CNAME_DEPTH {
+            let mut next = None;
+            for record in self.answers.iter().filter(|record| record.class == CLASS_IN && record.name == name) {
+                match &record.data {
+                    RecordData::A(address) => return (name, Some((*address, ttl.min(record.ttl)))),
+                    RecordData::Cname(target) => {
+                        ttl = ttl.min(record.ttl);
+                        next = Some(target.clone());
+                    }
+                    RecordData::Other(_) => {}
+                }
+            }
+            match next {
+                Some(target) => name = target,
+                None => break,
+            }
+        }
+        (name, None)
+    }
+}
+
+/// Returns the cached address of a name, removing it if it expired
+fn cached(name: &str) -> Option<[u8; 4]> {
+    let mut cache = CACHE.lock();
+    let entry = *cache.get(name)?;
+    if entry.expires > time::ticks() {
+        Some(entry.address)
+    } else {
+        cache.remove(name);
+        None
+    }
+}
+
+/// Caches the address of a name for `ttl` seconds
+fn insert_cache(name: String, address: [u8; 4], ttl: u32) {
+    if ttl == 0 {
+        return;
+    }
+    let now = time::ticks();
+    let mut cache = CACHE.lock();
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        cache.retain(|_, entry| entry.expires > now);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            return;
+        }
+    }
+    cache.insert(name, CacheEntry {
+        address,
+        expires: now + ttl as u64 * time::TICKS_PER_SECOND,
+    });
+}
+
+/// Removes all names from the cache
+pub fn flush_cache() {
+    CACHE.lock().clear();
+}
+
+/// Sends a query for the name to the server until a response with the same ID arrives
+async fn query(socket: &UdpSocket, server: [u8; 4], name: &str) -> Result<DnsResponse, &'static str> {
+    for _ in 0..QUERY_RETRIES {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) ^ time::ticks() as u16;
+        socket.send_to(&build_query(id, name)?, server, SERVER_PORT).await?;
+
+        let deadline = time::ticks() + QUERY_TIMEOUT;
+        loop {
+            let now = time::ticks();
+            if now >= deadline {
+                break;
+            }
+            let (payload, src, src_port) = match time::timeout(deadline - now, socket.recv_from()).await {
+                Some(datagram) => datagram,
+                None => break,
+            };
+            if src != server || src_port != SERVER_PORT || payload.len() > MAX_MESSAGE_SIZE {
+                continue;
+            }
+            match DnsResponse::from_bytes(&payload) {
+                Ok(response) if response.id == id => return Ok(response),
+                _ => continue,
+            }
+        }
+    }
+    Err("DNS server did not respond")
+}
+
+/// Resolves a name to an IPv4 address using the DNS servers leased by DHCP
+///
+/// Addresses are cached for the TTL of their records. Dotted decimal
+/// addresses are returned as they are.
+pub async fn resolve(name: &str) -> Result<[u8; 4], &'static str> {
+    if let Some(address) = parse_address(name) {
+        return Ok(address);
+    }
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    if let Some(address) = cached(&name) {
+        return Ok(address);
+    }
+
+    let servers = netconfig::config().dns_servers;
+    if servers.is_empty() {
+        return Err("no DNS server configured");
+    }
+    let socket = UdpSocket::bind(0)?;
+
+    let mut error = "DNS server did not respond";
+    for server in servers {
+        let mut current = name.clone();
+        for _ in 0..MAX_CNAME_DEPTH {
+            let response = match query(&socket, server, &current).await {
+                Ok(response) => response,
+                Err(err) => {
+                    error = err;
+                    break;
+                }
+            };
+            match response.rcode {
+                RCODE_NO_ERROR => {}
+                RCODE_NAME_ERROR => return Err("hostname not found"),
+                _ => {
+                    error = "DNS server failure";
+                    break;
+                }
+            }
+
+            let (canonical, answer) = response.lookup(&current);
+            if let Some((address, ttl)) = answer {
+                insert_cache(name, address, ttl);
+                return Ok(address);
+            }
+            if canonical == current {
+                return Err("hostname has no IPv4 address");
+            }
+            // the CNAME target is not in the response, ask for it directly
+            current = canonical;
+        }
+    }
+    Err(error)
+}
+
+/// Parses an address in dotted decimal notation, e.g. `10.0.2.3`
+pub fn parse_address(text: &str) -> Option<[u8; 4]> {
+    let mut address = [0; 4];
+    let mut parts = text.split('.');
+    for byte in address.iter_mut() {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(address),
+    }
+}
+
+#[test_case]
+fn test_parse_response_with_cname() {
+    let query = build_query(0x1234, "www.Example.com").unwrap();
+    let mut response = query.clone();
+    // response with recursion desired and available, two answers
+    response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+    response[6..8].copy_from_slice(&2u16.to_be_bytes());
+    // www.example.com CNAME example.com, pointing into the question
+    response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 16]);
+    // example.com A 93.184.216.34
+    response.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 93, 184, 216, 34]);
+
+    let parsed = DnsResponse::from_bytes(&response).unwrap();
+    assert_eq!(parsed.id, 0x1234);
+    assert_eq!(parsed.rcode, RCODE_NO_ERROR);
+    assert_eq!(parsed.answers[0].data, RecordData::Cname(String::from("example.com")));
+    assert_eq!(
+        parsed.lookup("www.example.com"),
+        (String::from("example.com"), Some(([93, 184, 216, 34], 60)))
+    );
+    assert_eq!(parse_address("10.0.2.3"), Some([10, 0, 2, 3]));
+    assert_eq!(parse_address("example.com"), None);
+}
@@ -0,0 +1,608 @@
+#![allow(dead_code)]
+
+use crate::{
+    println,
+    pci,
+    memory::{DmaBuffer, MmioRegion},
+    interrupts,
+    netdev::{self, FrameQueue, FrameStream, LinkInfo, LinkStatus, NetDevice, NetDeviceStats, TransmitQueue},
+};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{
+    ptr::{addr_of, addr_of_mut, read_volatile, write_volatile},
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use x86_64::instructions::interrupts::without_interrupts;
+use spin::Mutex;
+
+// Register
+const CTRL: u32 = 0x0000;
+const STATUS: u32 = 0x0008;
+const EERD: u32 = 0x0014;
+const ICR: u32 = 0x00c0;
+const IMS: u32 = 0x00d0;
+const IMC: u32 = 0x00d8;
+const RCTL: u32 = 0x0100;
+const TCTL: u32 = 0x0400;
+const TIPG: u32 = 0x0410;
+const RDBAL: u32 = 0x2800;
+const RDBAH: u32 = 0x2804;
+const RDLEN: u32 = 0x2808;
+const RDH: u32 = 0x2810;
+const RDT: u32 = 0x2818;
+const TDBAL: u32 = 0x3800;
+const TDBAH: u32 = 0x3804;
+const TDLEN: u32 = 0x3808;
+const TDH: u32 = 0x3810;
+const TDT: u32 = 0x3818;
+const MTA: u32 = 0x5200;
+const RAL0: u32 = 0x5400;
+const RAH0: u32 = 0x5404;
+/// Size of the register space behind BAR0
+const REGISTER_SPACE_SIZE: u32 = 0x20000;
+
+// Control
+const SET_LINK_UP: u32 = 1 << 6;
+const RESET: u32 = 1 << 26;
+
+// Status
+const FULL_DUPLEX: u32 = 1 << 0;
+const LINK_UP: u32 = 1 << 1;
+const SPEED_SHIFT: u32 = 6;
+const SPEED_MASK: u32 = 0b11;
+
+// EepromRead
+const EEPROM_START: u32 = 1 << 0;
+const EEPROM_DONE: u32 = 1 << 4;
+const EEPROM_ADDRESS_SHIFT: u32 = 8;
+const EEPROM_DATA_SHIFT: u32 = 16;
+/// Polls of EERD before the EEPROM is considered missing
+const EEPROM_TIMEOUT: usize = 10_000;
+
+// Interrupt
+const TRANSMIT_DESCRIPTOR_WRITTEN_BACK: u32 = 1 << 0;
+const LINK_STATUS_CHANGE: u32 = 1 << 2;
+const RECEIVE_DESCRIPTOR_MINIMUM: u32 = 1 << 4;
+const RECEIVER_OVERRUN: u32 = 1 << 6;
+const RECEIVER_TIMER: u32 = 1 << 7;
+const INTERRUPTS: u32 = TRANSMIT_DESCRIPTOR_WRITTEN_BACK | LINK_STATUS_CHANGE | RECEIVE_DESCRIPTOR_MINIMUM
+    | RECEIVER_OVERRUN | RECEIVER_TIMER;
+
+// ReceiveControl
+const RECEIVER_ENABLE: u32 = 1 << 1;
+const UNICAST_PROMISCUOUS: u32 = 1 << 3;
+const MULTICAST_PROMISCUOUS: u32 = 1 << 4;
+const BROADCAST_ACCEPT: u32 = 1 << 15;
+/// Receive buffers of 2048 bytes
+const BUFFER_SIZE_2048: u32 = 0b00 << 16;
+const STRIP_CRC: u32 = 1 << 26;
+
+// TransmitControl
+const TRANSMITTER_ENABLE: u32 = 1 << 1;
+const PAD_SHORT_PACKETS: u32 = 1 << 3;
+const COLLISION_THRESHOLD: u32 = 0x0f << 4;
+const COLLISION_DISTANCE: u32 = 0x40 << 12;
+
+/// Inter packet gap recommended for copper links
+const TRANSMIT_IPG: u32 = 10 | (8 << 10) | (6 << 20);
+
+// ReceiveAddressHigh
+const ADDRESS_VALID: u32 = 1 << 31;
+
+// DescriptorStatus
+const DESCRIPTOR_DONE: u8 = 1 << 0;
+const END_OF_PACKET: u8 = 1 << 1;
+
+// TransmitCommand
+const COMMAND_END_OF_PACKET: u8 = 1 << 0;
+const COMMAND_INSERT_FCS: u8 = 1 << 1;
+const COMMAND_REPORT_STATUS: u8 = 1 << 3;
+
+const E1000_VENDOR_ID: u16 = 0x8086;
+const E1000_DEVICE_ID: u16 = 0x100E;
+
+/// Number of receive descriptors, the ring size must be a multiple of 128 bytes
+const RECEIVE_DESCRIPTOR_COUNT: usize = 32;
+/// Number of transmit descriptors, the ring size must be a multiple of 128 bytes
+const TRANSMIT_DESCRIPTOR_COUNT: usize = 16;
+/// Size of every receive and transmit buffer
+const BUFFER_SIZE: usize = 2048;
+/// Size of a receive or transmit descriptor
+const DESCRIPTOR_SIZE: usize = 16;
+
+/// Number of received frames that can wait in the frame queue for a task to pick them up
+const FRAME_QUEUE_SIZE: usize = 32;
+/// Largest frame (without CRC) that is passed on to the frame queue
+const MAX_FRAME_SIZE: usize = 1514;
+/// Smallest frame (without CRC) that is passed on to the frame queue
+const MIN_RECEIVE_SIZE: usize = 14;
+
+// The initialized e1000, if one was found
+static DEVICE: OnceCell<E1000> = OnceCell::uninit();
+
+/// Receive descriptor in the legacy format
+#[repr(C)]
+struct ReceiveDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+/// Transmit descriptor in the legacy format
+#[repr(C)]
+struct TransmitDescriptor {
+    addr: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+/// Ring of descriptors, every descriptor owning one buffer of BUFFER_SIZE bytes
+struct DescriptorRing {
+    descriptors: DmaBuffer,
+    buffers: DmaBuffer,
+    count: usize,
+}
+
+impl DescriptorRing {
+    fn new(count: usize) -> Option<DescriptorRing> {
+        Some(DescriptorRing {
+            descriptors: DmaBuffer::new(count * DESCRIPTOR_SIZE)?,
+            buffers: DmaBuffer::new(count * BUFFER_SIZE)?,
+            count,
+        })
+    }
+
+    fn descriptor_ptr<T>(&mut self, index: usize) -> *mut T {
+        self.descriptors.as_mut_slice()[index * DESCRIPTOR_SIZE..].as_mut_ptr() as *mut T
+    }
+
+    fn buffer_addr(&self, index: usize) -> u64 {
+        self.buffers.phys_addr().as_u64() + (index * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&self, index: usize) -> &[u8] {
+        &self.buffers.as_slice()[index * BUFFER_SIZE..(index + 1) * BUFFER_SIZE]
+    }
+
+    fn buffer_mut(&mut self, index: usize) -> &mut [u8] {
+        &mut self.buffers.as_mut_slice()[index * BUFFER_SIZE..(index + 1) * BUFFER_SIZE]
+    }
+}
+
+/// Intel 82540EM (e1000) network card, QEMU's default
+pub struct E1000 {
+    // Registers, mapped uncached
+    mmio: MmioRegion,
+    mac_address: [u8; 6],
+    // Only locked by the interrupt handler once the device is running
+    receiver: Mutex<Receiver>,
+    transmitter: Mutex<Transmitter>,
+    // Frames waiting for a transmit descriptor
+    transmit_queue: TransmitQueue,
+    // Received frames waiting for the receive stream
+    frames: FrameQueue,
+    transmitted_frames: AtomicU64,
+    transmitted_bytes: AtomicU64,
+    transmit_errors: AtomicU64,
+    receive_errors: AtomicU64,
+    receive_overruns: AtomicU64,
+}
+
+struct Receiver {
+    ring: DescriptorRing,
+    // Descriptor the e1000 fills next
+    next: usize,
+}
+
+struct Transmitter {
+    ring: DescriptorRing,
+    // Descriptor the next frame goes into
+    next: usize,
+    // Oldest descriptor the e1000 may still be sending from
+    oldest: usize,
+    // Number of descriptors handed to the e1000
+    in_flight: usize,
+    // Tasks waiting for a free descriptor
+    waiters: Vec<Waker>,
+}
+
+/// Initializes the e1000 Network Card, if it exists, with:
+/// - Getting the address of its registers
+/// - Bus Mastering and Memory-Space-Access
+/// - Software Reset
+/// - Reading the MAC address from the EEPROM
+/// - Setting up the receive and transmit descriptor rings
+/// - Interrupt Masking
+/// - Registering its Interrupt Line for the IDT
+/// - Registering it as a network interface
+pub fn init() {
+    let pci_device = match pci::get_pci_device_id(E1000_VENDOR_ID, E1000_DEVICE_ID) {
+        Some(pci_device) => pci_device,
+        None => return,
+    };
+    println!("Beginning initialisation of e1000!");
+
+    let bar = match pci_device.bar(0) {
+        Ok(bar) if bar.is_memory() && bar.size() >= REGISTER_SPACE_SIZE as u64 => bar,
+        _ => {
+            println!("Aborting e1000 initialisation: BAR0 is not a memory BAR for the registers");
+            return;
+        }
+    };
+    let mmio = match bar.map() {
+        Ok(mmio) => mmio,
+        Err(err) => {
+            println!("Aborting e1000 initialisation: {}", err);
+            return;
+        }
+    };
+
+    let (receive_ring, transmit_ring) = match (
+        DescriptorRing::new(RECEIVE_DESCRIPTOR_COUNT),
+        DescriptorRing::new(TRANSMIT_DESCRIPTOR_COUNT),
+    ) {
+        (Some(receive_ring), Some(transmit_ring)) => (receive_ring, transmit_ring),
+        _ => {
+            println!("Aborting e1000 initialisation: no memory for DMA buffers");
+            return;
+        }
+    };
+
+    pci_device.pci_set_command_register_bit(pci::BUS_MASTER);
+    pci_device.pci_set_command_register_bit(pci::MEMORY_SPACE);
+
+    let mut device = E1000 {
+        mmio,
+        mac_address: [0; 6],
+        receiver: Mutex::new(Receiver { ring: receive_ring, next: 0 }),
+        transmitter: Mutex::new(Transmitter {
+            ring: transmit_ring,
+            next: 0,
+            oldest: 0,
+            in_flight: 0,
+            waiters: Vec::new(),
+        }),
+        transmit_queue: TransmitQueue::new(),
+        frames: FrameQueue::new(FRAME_QUEUE_SIZE, MAX_FRAME_SIZE),
+        transmitted_frames: AtomicU64::new(0),
+        transmitted_bytes: AtomicU64::new(0),
+        transmit_errors: AtomicU64::new(0),
+        receive_errors: AtomicU64::new(0),
+        receive_overruns: AtomicU64::new(0),
+    };
+
+    println!("Performing software reset");
+    device.write(IMC, 0xffffffff);
+    device.write(CTRL, device.read(CTRL) | RESET);
+    while (device.read(CTRL) & RESET) != 0 {}
+    device.write(IMC, 0xffffffff);
+    device.read(ICR);
+    device.write(CTRL, device.read(CTRL) | SET_LINK_UP);
+
+    device.mac_address = device.read_mac_address();
+    let mac = device.mac_address;
+    device.write(RAL0, u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]));
+    device.write(RAH0, u16::from_le_bytes([mac[4], mac[5]]) as u32 | ADDRESS_VALID);
+    for i in 0..128 {
+        device.write(MTA + 4 * i, 0);
+    }
+
+    println!("Configuring descriptor rings");
+    device.setup_receiver();
+    device.setup_transmitter();
+
+    interrupts::regiser_interrupt("E1000", pci_device.int_line);
+    println!("Unmasking interrupts");
+    device.write(IMS, INTERRUPTS);
+
+    let device = DEVICE.get_or_init(move || device);
+    let id = netdev::register(device);
+    println!("e1000 init complete, registered as interface {}", id);
+}
+
+/// Returns the initialized e1000, if one was found
+pub fn device() -> Option<&'static E1000> {
+    DEVICE.try_get().ok()
+}
+
+/// Handles the interrupt of the e1000, if it was initialized
+/// To be called by a handler function in interrupts.rs
+pub fn handle_interrupt() {
+    if let Some(device) = device() {
+        device.handle_interrupt();
+    }
+}
+
+impl E1000 {
+    /// Handles every kind of interrupt that caused the e1000 to send an IRQ
+    fn handle_interrupt(&self) {
+        // reading the cause acknowledges it
+        let cause = self.read(ICR);
+
+        if (cause & RECEIVER_OVERRUN) != 0 {
+            self.receive_overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        if (cause & (RECEIVER_TIMER | RECEIVE_DESCRIPTOR_MINIMUM | RECEIVER_OVERRUN)) != 0 {
+            self.receive_frames();
+        }
+        if (cause & TRANSMIT_DESCRIPTOR_WRITTEN_BACK) != 0 {
+            self.release_descriptors();
+        }
+        if (cause & LINK_STATUS_CHANGE) != 0 {
+            netdev::notify_link_change();
+        }
+    }
+
+    /// Reads the MAC address from the EEPROM,
+    /// or from the receive address registers if there is no EEPROM
+    fn read_mac_address(&self) -> [u8; 6] {
+        let mut mac = [0; 6];
+        for word in 0..3 {
+            match self.read_eeprom(word) {
+                Some(value) => mac[2 * word as usize..2 * word as usize + 2].copy_from_slice(&value.to_le_bytes()),
+                None => {
+                    let low = self.read(RAL0).to_le_bytes();
+                    let high = self.read(RAH0).to_le_bytes();
+                    return [low[0], low[1], low[2], low[3], high[0], high[1]];
+                }
+            }
+        }
+        mac
+    }
+
+    /// Returns the given word of the EEPROM, if it answers
+    fn read_eeprom(&self, address: u32) -> Option<u16> {
+        self.write(EERD, EEPROM_START | (address << EEPROM_ADDRESS_SHIFT));
+        for _ in 0..EEPROM_TIMEOUT {
+            let value = self.read(EERD);
+            if (value & EEPROM_DONE) != 0 {
+                return Some((value >> EEPROM_DATA_SHIFT) as u16);
+            }
+        }
+        None
+    }
+
+    /// Hands all receive buffers to the e1000 and enables the receiver
+    fn setup_receiver(&self) {
+        let mut receiver = self.receiver.lock();
+        for index in 0..RECEIVE_DESCRIPTOR_COUNT {
+            let addr = receiver.ring.buffer_addr(index);
+            let descriptor = receiver.ring.descriptor_ptr::<ReceiveDescriptor>(index);
+            unsafe {
+                write_volatile(descriptor, ReceiveDescriptor {
+                    addr,
+                    length: 0,
+                    checksum: 0,
+                    status: 0,
+                    errors: 0,
+                    special: 0,
+                })
+            };
+        }
+
+        let ring = receiver.ring.descriptors.phys_addr().as_u64();
+        self.write(RDBAL, ring as u32);
+        self.write(RDBAH, (ring >> 32) as u32);
+        self.write(RDLEN, (RECEIVE_DESCRIPTOR_COUNT * DESCRIPTOR_SIZE) as u32);
+        self.write(RDH, 0);
+        // the descriptor at the tail stays with the driver, so head == tail means the ring is full
+        self.write(RDT, (RECEIVE_DESCRIPTOR_COUNT - 1) as u32);
+        self.write(RCTL, RECEIVER_ENABLE | BROADCAST_ACCEPT | BUFFER_SIZE_2048 | STRIP_CRC);
+    }
+
+    /// Points the e1000 to the empty transmit ring and enables the transmitter
+    fn setup_transmitter(&self) {
+        let transmitter = self.transmitter.lock();
+        let ring = transmitter.ring.descriptors.phys_addr().as_u64();
+        self.write(TDBAL, ring as u32);
+        self.write(TDBAH, (ring >> 32) as u32);
+        self.write(TDLEN, (TRANSMIT_DESCRIPTOR_COUNT * DESCRIPTOR_SIZE) as u32);
+        self.write(TDH, 0);
+        self.write(TDT, 0);
+        self.write(TIPG, TRANSMIT_IPG);
+        self.write(TCTL, TRANSMITTER_ENABLE | PAD_SHORT_PACKETS | COLLISION_THRESHOLD | COLLISION_DISTANCE);
+    }
+
+    /// Copies the frames the e1000 received into the frame queue
+    /// and hands their descriptors back to it
+    fn receive_frames(&self) {
+        let mut receiver = self.receiver.lock();
+        loop {
+            let index = receiver.next;
+            let descriptor = receiver.ring.descriptor_ptr::<ReceiveDescriptor>(index);
+            let (status, errors, length) = unsafe {
+                (
+                    read_volatile(addr_of!((*descriptor).status)),
+                    read_volatile(addr_of!((*descriptor).errors)),
+                    read_volatile(addr_of!((*descriptor).length)) as usize,
+                )
+            };
+            if (status & DESCRIPTOR_DONE) == 0 {
+                break;
+            }
+
+            // frames spanning several buffers are larger than MAX_FRAME_SIZE
+            if errors != 0 || (status & END_OF_PACKET) == 0 || length < MIN_RECEIVE_SIZE {
+                self.receive_errors.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.frames.push(&receiver.ring.buffer(index)[..length]);
+            }
+
+            unsafe { write_volatile(addr_of_mut!((*descriptor).status), 0) };
+            receiver.next = (index + 1) % RECEIVE_DESCRIPTOR_COUNT;
+            self.write(RDT, index as u32);
+        }
+    }
+
+    /// Takes back the transmit descriptors the e1000 is done with
+    fn release_descriptors(&self) {
+        let mut transmitter = self.transmitter.lock();
+        while transmitter.in_flight > 0 {
+            let index = transmitter.oldest;
+            let descriptor = transmitter.ring.descriptor_ptr::<TransmitDescriptor>(index);
+            if (unsafe { read_volatile(addr_of!((*descriptor).status)) } & DESCRIPTOR_DONE) == 0 {
+                break;
+            }
+            transmitter.oldest = (index + 1) % TRANSMIT_DESCRIPTOR_COUNT;
+            transmitter.in_flight -= 1;
+        }
+        for waker in transmitter.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Sends a frame, waiting for room in the transmit queue while all transmit descriptors are in use
+    pub async fn send(&self, frame: &[u8]) -> Result<(), &'static str> {
+        netdev::send_frame(self, frame).await
+    }
+
+    /// Copies the frame into the buffer of the next transmit descriptor
+    /// and hands it to the e1000
+    ///
+    /// Returns false if all transmit descriptors are in use,
+    /// after registering the waker to be woken once one is released.
+    fn try_send(&self, frame: &[u8], waker: Option<&Waker>) -> Result<bool, &'static str> {
+        if frame.len() > BUFFER_SIZE {
+            return Err("frame too large for the transmit buffer");
+        }
+
+        without_interrupts(|| {
+            let mut transmitter = self.transmitter.lock();
+            // the tail must not catch up with the head, so one descriptor always stays unused
+            if transmitter.in_flight == TRANSMIT_DESCRIPTOR_COUNT - 1 {
+                if let Some(waker) = waker {
+                    if !transmitter.waiters.iter().any(|waiter| waiter.will_wake(waker)) {
+                        transmitter.waiters.push(waker.clone());
+                    }
+                }
+                return Ok(false);
+            }
+
+            let index = transmitter.next;
+            transmitter.ring.buffer_mut(index)[..frame.len()].copy_from_slice(frame);
+            let addr = transmitter.ring.buffer_addr(index);
+            let descriptor = transmitter.ring.descriptor_ptr::<TransmitDescriptor>(index);
+            unsafe {
+                write_volatile(descriptor, TransmitDescriptor {
+                    addr,
+                    length: frame.len() as u16,
+                    checksum_offset: 0,
+                    command: COMMAND_END_OF_PACKET | COMMAND_INSERT_FCS | COMMAND_REPORT_STATUS,
+                    status: 0,
+                    checksum_start: 0,
+                    special: 0,
+                })
+            };
+
+            transmitter.next = (index + 1) % TRANSMIT_DESCRIPTOR_COUNT;
+            transmitter.in_flight += 1;
+            // moving the tail starts the transmission
+            self.write(TDT, transmitter.next as u32);
+
+            self.transmitted_frames.fetch_add(1, Ordering::Relaxed);
+            self.transmitted_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+            Ok(true)
+        })
+    }
+
+    // Returns the register at the given offset of the MMIO space of the e1000
+    fn read(&self, register: u32) -> u32 {
+        self.mmio.read_32(register as usize)
+    }
+
+    // Writes the register at the given offset of the MMIO space of the e1000
+    fn write(&self, register: u32, value: u32) {
+        self.mmio.write_32(register as usize, value)
+    }
+}
+
+impl NetDevice for E1000 {
+    fn name(&self) -> &str {
+        "e1000"
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    /// Sends the frame if a transmit descriptor is free, queues it otherwise
+    fn send_frame(&self, frame: &[u8]) -> Result<(), &'static str> {
+        let result = self.transmit_queue.send(frame, |frame, waker| self.try_send(frame, waker));
+        if result.is_err() {
+            self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Sends the frame if a transmit descriptor is free, queues it otherwise,
+    /// pending while the transmit queue is full
+    fn poll_send_frame(&self, frame: &[u8], cx: &mut Context) -> Poll<Result<(), &'static str>> {
+        let result = self.transmit_queue.poll_send(frame, cx, |frame, waker| self.try_send(frame, waker));
+        if let Poll::Ready(Err(_)) = result {
+            self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Sends queued frames as the interrupt handler releases transmit descriptors
+    fn flush_transmit_queue(&self, waker: &Waker) {
+        self.transmit_queue.flush(
+            waker,
+            |frame, waker| self.try_send(frame, waker),
+            |_| {
+                self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+            },
+        );
+    }
+
+    fn receive_stream(&'static self) -> FrameStream {
+        self.frames.stream()
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        if (self.read(STATUS) & LINK_UP) != 0 {
+            LinkStatus::Up
+        } else {
+            LinkStatus::Down
+        }
+    }
+
+    fn link_info(&self) -> LinkInfo {
+        let status = self.read(STATUS);
+        if (status & LINK_UP) == 0 {
+            return LinkInfo { status: LinkStatus::Down, speed_mbps: None, full_duplex: None };
+        }
+        let speed_mbps = match (status >> SPEED_SHIFT) & SPEED_MASK {
+            0 => 10,
+            1 => 100,
+            _ => 1000,
+        };
+        LinkInfo {
+            status: LinkStatus::Up,
+            speed_mbps: Some(speed_mbps),
+            full_duplex: Some((status & FULL_DUPLEX) != 0),
+        }
+    }
+
+    fn stats(&self) -> NetDeviceStats {
+        let mut stats = NetDeviceStats {
+            tx_frames: self.transmitted_frames.load(Ordering::Relaxed),
+            tx_bytes: self.transmitted_bytes.load(Ordering::Relaxed),
+            tx_errors: self.transmit_errors.load(Ordering::Relaxed),
+            rx_errors: self.receive_errors.load(Ordering::Relaxed),
+            rx_overflows: self.receive_overruns.load(Ordering::Relaxed),
+            ..NetDeviceStats::default()
+        };
+        self.frames.add_stats(&mut stats);
+        stats
+    }
+}
@@ -1,79 +1,280 @@
 #![allow(dead_code)]
 
-use crate::rtl8139;
+use crate::{netdev::{self, InterfaceId}, pcap};
 use alloc::vec::Vec;
-use x86_64::VirtAddr;
-use core::mem::size_of_val;
+use futures_util::stream::StreamExt;
+use spin::Mutex;
+
+pub const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+/// Size of the Ethernet header in bytes
+pub const HEADER_SIZE: usize = 14;
+/// Size of an 802.1Q tag in bytes
+pub const VLAN_TAG_SIZE: usize = 4;
+/// Smallest frame without CRC, shorter frames are padded with zeros
+pub const MIN_FRAME_SIZE: usize = 60;
+
+/// Ethertype of 802.1Q tagged frames
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+
+/// 802.1Q tag of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VlanTag {
+    /// Priority code point, 0 to 7
+    pub priority: u8,
+    pub drop_eligible: bool,
+    /// VLAN identifier, 0 to 4095
+    pub id: u16,
+}
+
+impl VlanTag {
+    pub fn new(id: u16) -> Self {
+        Self { priority: 0, drop_eligible: false, id: id & 0xfff }
+    }
+
+    /// Returns the tag control information as sent on the wire
+    pub fn tci(&self) -> u16 {
+        ((self.priority as u16 & 0x7) << 13) | ((self.drop_eligible as u16) << 12) | (self.id & 0xfff)
+    }
+
+    pub fn from_tci(tci: u16) -> Self {
+        Self {
+            priority: (tci >> 13) as u8,
+            drop_eligible: (tci & 0x1000) != 0,
+            id: tci & 0xfff,
+        }
+    }
+}
 
 /// Ethernet header, consisting of destination mac address,
-/// source mac address and protocol/ethertype
-#[derive(Debug)]
-#[repr(C)]
+/// source mac address, optional VLAN tag and protocol/ethertype
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct EthernetHeader {
-    dst_mac: [u8; 6],
-    src_mac: [u8; 6],
-    protocol: u16,
+    pub dst_mac: [u8; 6],
+    pub src_mac: [u8; 6],
+    pub vlan: Option<VlanTag>,
+    /// Ethertype of the payload, for tagged frames the one following the tag
+    pub protocol: u16,
 }
 
 impl EthernetHeader {
-    fn new(
-        dst_mac: [u8; 6], 
-        src_mac: [u8; 6], 
+    pub fn new(
+        dst_mac: [u8; 6],
+        src_mac: [u8; 6],
         protocol: u16
     ) -> Self {
-        Self {dst_mac, src_mac, protocol}
+        Self {dst_mac, src_mac, vlan: None, protocol}
+    }
+
+    /// Returns the header with an 802.1Q tag
+    pub fn with_vlan(self, vlan: VlanTag) -> Self {
+        Self {vlan: Some(vlan), ..self}
+    }
+
+    /// Returns the size of the header on the wire
+    pub fn size(&self) -> usize {
+        match self.vlan {
+            Some(_) => HEADER_SIZE + VLAN_TAG_SIZE,
+            None => HEADER_SIZE,
+        }
+    }
+
+    fn write_to(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.dst_mac);
+        buffer.extend_from_slice(&self.src_mac);
+        if let Some(vlan) = self.vlan {
+            buffer.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+            buffer.extend_from_slice(&vlan.tci().to_be_bytes());
+        }
+        buffer.extend_from_slice(&self.protocol.to_be_bytes());
     }
 }
 
 // Complete data-link-layer ethernet frame
 #[derive(Debug)]
-#[repr(C)]
 pub struct EthernetFrame {
     header: EthernetHeader,
     payload: Vec<u8>
 }
 impl EthernetFrame {
-    fn new(
-        header: EthernetHeader, 
+    pub fn new(
+        header: EthernetHeader,
         payload: Vec<u8>
     ) -> Self {
         Self {header, payload}
     }
 
-    // returns the whole frame in bytes
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::new();
-        result.extend_from_slice(&self.header.dst_mac);
-        result.extend_from_slice(&self.header.src_mac);
-        let protocol_upper = ((self.header.protocol >> 8) & 0xff) as u8;
-        let protocol_lower = (self.header.protocol & 0xff) as u8;
-        result.push(protocol_upper);
-        result.push(protocol_lower);
+    pub fn header(&self) -> &EthernetHeader {
+        &self.header
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    // returns the whole frame in bytes, padded to MIN_FRAME_SIZE
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.header.size() + self.payload.len());
+        self.header.write_to(&mut result);
         result.extend(self.payload.iter());
+        if result.len() < MIN_FRAME_SIZE {
+            result.resize(MIN_FRAME_SIZE, 0);
+        }
 
         result
     }
 }
 
-/// creates a buffer from given EthernetFrame 
-/// and passes the buffer's address and packets length to the RTL8139
-fn send_frame(frame: EthernetFrame) {
-    let mut buffer: Vec<u8> = Vec::with_capacity(size_of_val(&frame));
-    buffer.append(&mut frame.to_bytes());
+/// Received frame, with the header parsed and the payload borrowed from the frame
+///
+/// Padding of short frames cannot be told apart from the payload,
+/// protocols use their own length fields to strip it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetFrameRef<'a> {
+    pub header: EthernetHeader,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrameRef<'a> {
+    pub fn parse(frame: &'a [u8]) -> Result<Self, &'static str> {
+        if frame.len() < HEADER_SIZE {
+            return Err("frame shorter than the Ethernet header");
+        }
+        let mut dst_mac = [0; 6];
+        let mut src_mac = [0; 6];
+        dst_mac.copy_from_slice(&frame[0..6]);
+        src_mac.copy_from_slice(&frame[6..12]);
+        let mut header = EthernetHeader::new(dst_mac, src_mac, u16::from_be_bytes([frame[12], frame[13]]));
+
+        if header.protocol == ETHERTYPE_VLAN {
+            if frame.len() < HEADER_SIZE + VLAN_TAG_SIZE {
+                return Err("frame shorter than the 802.1Q header");
+            }
+            header.vlan = Some(VlanTag::from_tci(u16::from_be_bytes([frame[14], frame[15]])));
+            header.protocol = u16::from_be_bytes([frame[16], frame[17]]);
+        }
+
+        Ok(Self {payload: &frame[header.size()..], header})
+    }
+}
+
+/// Receives the frames of one Ethertype, registered with `register_handler`
+pub type Handler = fn(&EthernetFrameRef);
+
+// Handlers by Ethertype
+static HANDLERS: Mutex<Vec<(u16, Handler)>> = Mutex::new(Vec::new());
+
+/// Registers the handler that receives all frames with the given Ethertype
+pub fn register_handler(protocol: u16, handler: Handler) -> Result<(), &'static str> {
+    let mut handlers = HANDLERS.lock();
+    if handlers.iter().any(|(registered, _)| *registered == protocol) {
+        return Err("ethertype already has a handler");
+    }
+    handlers.push((protocol, handler));
+    Ok(())
+}
+
+/// Removes the handler of the given Ethertype, later frames of it are dropped
+pub fn unregister_handler(protocol: u16) {
+    HANDLERS.lock().retain(|(registered, _)| *registered != protocol);
+}
+
+fn handler(protocol: u16) -> Option<Handler> {
+    HANDLERS
+        .lock()
+        .iter()
+        .find(|(registered, _)| *registered == protocol)
+        .map(|(_, handler)| *handler)
+}
+
+/// creates a buffer from given EthernetFrame
+/// and passes it to the default network interface
+pub fn send_frame(frame: EthernetFrame) -> Result<(), &'static str> {
+    let device = netdev::default_interface().ok_or("no network interface")?;
+    let bytes = frame.to_bytes();
+    device.send_frame(&bytes)?;
+    pcap::capture(&bytes);
+    Ok(())
+}
+
+/// Like `send_frame`, but waits for room in the transmit queue of the interface
+/// instead of failing when it is full
+pub async fn send_frame_waiting(frame: EthernetFrame) -> Result<(), &'static str> {
+    let device = netdev::default_interface().ok_or("no network interface")?;
+    let bytes = frame.to_bytes();
+    netdev::send_frame(device, &bytes).await?;
+    pcap::capture(&bytes);
+    Ok(())
+}
+
+/// Returns the MAC address frames are sent from,
+/// or all zeros if there is no network interface
+pub fn mac_address() -> [u8; 6] {
+    netdev::default_interface()
+        .map(|device| device.mac_address())
+        .unwrap_or([0; 6])
+}
 
-    let buffer_virt_addr = VirtAddr::new(buffer.as_mut_ptr() as u64);
-    
-    rtl8139::send_packet(buffer_virt_addr, buffer.len() as u32)
+/// Sends the payload of a higher layer protocol to the given destination
+pub fn send_payload(dst_mac: [u8; 6], protocol: u16, payload: Vec<u8>) -> Result<(), &'static str> {
+    let header = EthernetHeader::new(dst_mac, mac_address(), protocol);
+    send_frame(EthernetFrame::new(header, payload))
 }
 
-/// creates basic ethernet frame with empty data to be sent by the RTL8139
-pub fn send_empty_frame() {
+/// Like `send_payload`, but waits for room in the transmit queue of the interface
+pub async fn send_payload_waiting(dst_mac: [u8; 6], protocol: u16, payload: Vec<u8>) -> Result<(), &'static str> {
+    let header = EthernetHeader::new(dst_mac, mac_address(), protocol);
+    send_frame_waiting(EthernetFrame::new(header, payload)).await
+}
+
+/// Passes every frame received on the given interface to the handler of its Ethertype
+pub async fn process_frames(interface: InterfaceId) {
+    let mut frames = match netdev::interface(interface) {
+        Some(device) => device.receive_stream(),
+        None => return,
+    };
+
+    while let Some(frame) = frames.next().await {
+        handle_frame(&frame);
+    }
+}
+
+/// Dispatches a received frame to the handler registered for its Ethertype
+fn handle_frame(frame: &[u8]) {
+    pcap::capture(frame);
+    let frame = match EthernetFrameRef::parse(frame) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+
+    // the lock is released before the handler runs, so handlers can register others
+    if let Some(handler) = handler(frame.header.protocol) {
+        handler(&frame);
+    }
+}
+
+/// creates basic ethernet frame with empty data to be sent by the default interface
+pub fn send_empty_frame() -> Result<(), &'static str> {
     let header = EthernetHeader::new(
-        [0xff; 6],
-        rtl8139::get_mac_address(),
+        BROADCAST_MAC,
+        mac_address(),
         0x1234);
     let payload = Vec::new();
     let empty_frame = EthernetFrame::new(header, payload);
 
-    send_frame(empty_frame);
-}
\ No newline at end of file
+    send_frame(empty_frame)
+}
+
+#[test_case]
+fn test_vlan_frame_round_trip() {
+    let header = EthernetHeader::new(BROADCAST_MAC, [0x02, 0, 0, 0, 0, 1], 0x0800)
+        .with_vlan(VlanTag { priority: 5, drop_eligible: true, id: 42 });
+    let bytes = EthernetFrame::new(header, alloc::vec![1, 2, 3]).to_bytes();
+    assert_eq!(bytes.len(), MIN_FRAME_SIZE);
+    assert_eq!(bytes[12..18], [0x81, 0x00, 0xb0, 0x2a, 0x08, 0x00]);
+
+    let parsed = EthernetFrameRef::parse(&bytes).unwrap();
+    assert_eq!(parsed.header, header);
+    assert_eq!(parsed.payload[..3], [1, 2, 3]);
+    assert_eq!(parsed.payload.len(), MIN_FRAME_SIZE - HEADER_SIZE - VLAN_TAG_SIZE);
+}
@@ -0,0 +1,270 @@
+use crate::{
+    allocator,
+    ethernet::{self, EthernetFrameRef},
+    netdev::ETHERNET_MTU,
+    pci, time,
+};
+use alloc::vec::Vec;
+
+// Request/response protocol for querying the kernel from the host, see send.py
+//
+// Every message is the payload of an Ethernet frame with ETHERTYPE_ETHMSG:
+//
+//   0      version, VERSION
+//   1      kind, requests are below 0x80, responses have RESPONSE set
+//   2..4   id, chosen by the requester and copied into the response
+//   4..6   length of the body
+//   6..    body, all numbers big endian
+//
+// Requests have no body. Responses are sent to the MAC address the request came from:
+//
+//   KIND_UPTIME       uptime in milliseconds (u64)
+//   KIND_HEAP_USAGE   heap size (u64), bytes in use (u64)
+//   KIND_PCI_DEVICES  count (u8), then per device bus, slot, function (u8),
+//                     vendor id, device id (u16), class, subclass (u8)
+//   KIND_ERROR        error code (u8)
+
+pub const ETHERTYPE_ETHMSG: u16 = 0x1234;
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 6;
+
+// Kind
+pub const KIND_UPTIME: u8 = 0x01;
+pub const KIND_HEAP_USAGE: u8 = 0x02;
+pub const KIND_PCI_DEVICES: u8 = 0x03;
+pub const RESPONSE: u8 = 0x80;
+pub const KIND_ERROR: u8 = 0xff;
+
+// Error code
+pub const ERROR_UNKNOWN_REQUEST: u8 = 1;
+pub const ERROR_MALFORMED: u8 = 2;
+
+/// Size of a device entry in a KIND_PCI_DEVICES response
+const PCI_DEVICE_SIZE: usize = 9;
+/// Devices that fit into one response, further devices are left out
+const MAX_PCI_DEVICES: usize = (ETHERNET_MTU - HEADER_SIZE - 1) / PCI_DEVICE_SIZE;
+
+/// PCI device as listed in a KIND_PCI_DEVICES response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDeviceInfo {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    UptimeRequest,
+    HeapUsageRequest,
+    PciDevicesRequest,
+    Uptime { millis: u64 },
+    HeapUsage { size: u64, used: u64 },
+    PciDevices(Vec<PciDeviceInfo>),
+    Error(u8),
+}
+
+impl Message {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Message::UptimeRequest => KIND_UPTIME,
+            Message::HeapUsageRequest => KIND_HEAP_USAGE,
+            Message::PciDevicesRequest => KIND_PCI_DEVICES,
+            Message::Uptime { .. } => KIND_UPTIME | RESPONSE,
+            Message::HeapUsage { .. } => KIND_HEAP_USAGE | RESPONSE,
+            Message::PciDevices(_) => KIND_PCI_DEVICES | RESPONSE,
+            Message::Error(_) => KIND_ERROR,
+        }
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.kind() & RESPONSE == 0
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Message::UptimeRequest | Message::HeapUsageRequest | Message::PciDevicesRequest => {}
+            Message::Uptime { millis } => body.extend_from_slice(&millis.to_be_bytes()),
+            Message::HeapUsage { size, used } => {
+                body.extend_from_slice(&size.to_be_bytes());
+                body.extend_from_slice(&used.to_be_bytes());
+            }
+            Message::PciDevices(devices) => {
+                body.push(devices.len() as u8);
+                for device in devices {
+                    body.extend_from_slice(&[device.bus, device.slot, device.function]);
+                    body.extend_from_slice(&device.vendor_id.to_be_bytes());
+                    body.extend_from_slice(&device.device_id.to_be_bytes());
+                    body.extend_from_slice(&[device.class, device.subclass]);
+                }
+            }
+            Message::Error(code) => body.push(*code),
+        }
+        body
+    }
+
+    fn from_body(kind: u8, body: &[u8]) -> Result<Self, &'static str> {
+        let u64_at = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&body[offset..offset + 8]);
+            u64::from_be_bytes(bytes)
+        };
+        let expect_len = |len: usize| {
+            if body.len() == len { Ok(()) } else { Err("invalid body length") }
+        };
+
+        match kind {
+            KIND_UPTIME => expect_len(0).map(|_| Message::UptimeRequest),
+            KIND_HEAP_USAGE => expect_len(0).map(|_| Message::HeapUsageRequest),
+            KIND_PCI_DEVICES => expect_len(0).map(|_| Message::PciDevicesRequest),
+            k if k == KIND_UPTIME | RESPONSE => {
+                expect_len(8)?;
+                Ok(Message::Uptime { millis: u64_at(0) })
+            }
+            k if k == KIND_HEAP_USAGE | RESPONSE => {
+                expect_len(16)?;
+                Ok(Message::HeapUsage { size: u64_at(0), used: u64_at(8) })
+            }
+            k if k == KIND_PCI_DEVICES | RESPONSE => {
+                let count = *body.first().ok_or("invalid body length")? as usize;
+                expect_len(1 + count * PCI_DEVICE_SIZE)?;
+                let devices = body[1..]
+                    .chunks(PCI_DEVICE_SIZE)
+                    .map(|entry| PciDeviceInfo {
+                        bus: entry[0],
+                        slot: entry[1],
+                        function: entry[2],
+                        vendor_id: u16::from_be_bytes([entry[3], entry[4]]),
+                        device_id: u16::from_be_bytes([entry[5], entry[6]]),
+                        class: entry[7],
+                        subclass: entry[8],
+                    })
+                    .collect();
+                Ok(Message::PciDevices(devices))
+            }
+            KIND_ERROR => {
+                expect_len(1)?;
+                Ok(Message::Error(body[0]))
+            }
+            _ => Err("unknown message kind"),
+        }
+    }
+}
+
+/// Message with the id that pairs requests and responses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub id: u16,
+    pub message: Message,
+}
+
+impl Packet {
+    pub fn new(id: u16, message: Message) -> Self {
+        Self { id, message }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body = self.message.body();
+        let mut result = Vec::with_capacity(HEADER_SIZE + body.len());
+        result.push(VERSION);
+        result.push(self.message.kind());
+        result.extend_from_slice(&self.id.to_be_bytes());
+        result.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        result.extend_from_slice(&body);
+        result
+    }
+
+    /// Parses a message, ignoring the padding of short Ethernet frames
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("message shorter than its header");
+        }
+        if bytes[0] != VERSION {
+            return Err("unsupported version");
+        }
+        let id = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let length = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        let body = bytes.get(HEADER_SIZE..HEADER_SIZE + length).ok_or("message shorter than its length")?;
+
+        Ok(Self { id, message: Message::from_body(bytes[1], body)? })
+    }
+}
+
+/// Registers the protocol with `ethernet` to receive its frames
+pub fn init() {
+    ethernet::register_handler(ETHERTYPE_ETHMSG, handle_frame)
+        .expect("ethmsg handler registered twice");
+}
+
+/// Answers a request received in an Ethernet frame
+///
+/// Frames with other versions, like the empty frames send.py sends by default, and responses are ignored.
+pub fn handle_frame(frame: &EthernetFrameRef) {
+    let payload = frame.payload;
+    let response = match Packet::from_bytes(payload) {
+        Ok(request) if request.message.is_request() => Packet::new(request.id, respond(&request.message)),
+        Ok(_) => return,
+        Err(_) if payload.len() >= HEADER_SIZE && payload[0] == VERSION => {
+            let code = match payload[1] {
+                KIND_UPTIME | KIND_HEAP_USAGE | KIND_PCI_DEVICES => ERROR_MALFORMED,
+                kind if kind & RESPONSE == 0 => ERROR_UNKNOWN_REQUEST,
+                // never answer responses, two kernels would keep answering each other
+                _ => return,
+            };
+            Packet::new(u16::from_be_bytes([payload[2], payload[3]]), Message::Error(code))
+        }
+        Err(_) => return,
+    };
+    let _ = ethernet::send_payload(frame.header.src_mac, ETHERTYPE_ETHMSG, response.to_bytes());
+}
+
+fn respond(request: &Message) -> Message {
+    match request {
+        Message::UptimeRequest => Message::Uptime {
+            millis: time::millis_from_ticks(time::ticks()),
+        },
+        Message::HeapUsageRequest => Message::HeapUsage {
+            size: allocator::HEAP_SIZE as u64,
+            used: allocator::heap_used() as u64,
+        },
+        Message::PciDevicesRequest => Message::PciDevices(
+            pci::pci_device_iter()
+                .take(MAX_PCI_DEVICES)
+                .map(|device| PciDeviceInfo {
+                    bus: device.location.bus(),
+                    slot: device.location.slot(),
+                    function: device.location.function(),
+                    vendor_id: device.vendor_id,
+                    device_id: device.device_id,
+                    class: device.class,
+                    subclass: device.subclass,
+                })
+                .collect(),
+        ),
+        _ => Message::Error(ERROR_UNKNOWN_REQUEST),
+    }
+}
+
+#[test_case]
+fn test_packet_round_trip() {
+    let device = PciDeviceInfo {
+        bus: 0,
+        slot: 3,
+        function: 0,
+        vendor_id: 0x10ec,
+        device_id: 0x8139,
+        class: 0x02,
+        subclass: 0x00,
+    };
+    let packet = Packet::new(7, Message::PciDevices(alloc::vec![device]));
+    let mut bytes = packet.to_bytes();
+    assert_eq!(bytes[..6], [VERSION, KIND_PCI_DEVICES | RESPONSE, 0, 7, 0, 10]);
+
+    // padding of short frames is ignored
+    bytes.resize(ethernet::MIN_FRAME_SIZE - ethernet::HEADER_SIZE, 0);
+    assert_eq!(Packet::from_bytes(&bytes), Ok(packet));
+}
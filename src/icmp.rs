@@ -0,0 +1,267 @@
+use crate::{ipv4, ipv4::Ipv4Header, netstat, println, time};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    cmp::min,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU16, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+// Type
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+
+// Code of destination unreachable messages
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+
+/// Size of the ICMP header, including the type specific rest of the header
+const HEADER_SIZE: usize = 8;
+
+/// Number of data bytes sent in an echo request
+const PING_DATA_SIZE: usize = 56;
+/// Time to wait for an echo reply
+const PING_TIMEOUT: u64 = time::TICKS_PER_SECOND;
+/// Time between two echo requests
+const PING_INTERVAL: u64 = time::TICKS_PER_SECOND;
+
+// Identifier of the next ping
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+/// ICMP message with its header fields and data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IcmpMessage {
+    pub icmp_type: u8,
+    pub code: u8,
+    /// Type specific part of the header, e.g. identifier and sequence number of echo messages
+    pub rest_of_header: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl IcmpMessage {
+    /// Creates an echo request or echo reply message
+    pub fn echo(icmp_type: u8, identifier: u16, sequence: u16, data: Vec<u8>) -> Self {
+        let id = identifier.to_be_bytes();
+        let seq = sequence.to_be_bytes();
+        IcmpMessage {
+            icmp_type,
+            code: 0,
+            rest_of_header: [id[0], id[1], seq[0], seq[1]],
+            data,
+        }
+    }
+
+    /// Returns the identifier of an echo message
+    pub fn identifier(&self) -> u16 {
+        u16::from_be_bytes([self.rest_of_header[0], self.rest_of_header[1]])
+    }
+
+    /// Returns the sequence number of an echo message
+    pub fn sequence(&self) -> u16 {
+        u16::from_be_bytes([self.rest_of_header[2], self.rest_of_header[3]])
+    }
+
+    // returns the whole message in bytes, including its checksum
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        result.push(self.icmp_type);
+        result.push(self.code);
+        result.extend_from_slice(&[0, 0]);
+        result.extend_from_slice(&self.rest_of_header);
+        result.extend_from_slice(&self.data);
+
+        let checksum = ipv4::checksum(&result);
+        result[2..4].copy_from_slice(&checksum.to_be_bytes());
+        result
+    }
+
+    /// Parses an ICMP message and validates its checksum
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("ICMP message too short");
+        }
+        if ipv4::checksum(bytes) != 0 {
+            return Err("invalid ICMP checksum");
+        }
+        let mut rest_of_header = [0; 4];
+        rest_of_header.copy_from_slice(&bytes[4..8]);
+        Ok(IcmpMessage {
+            icmp_type: bytes[0],
+            code: bytes[1],
+            rest_of_header,
+            data: Vec::from(&bytes[HEADER_SIZE..]),
+        })
+    }
+}
+
+/// Echo request sent by `ping` that is waiting for its reply
+struct PendingEcho {
+    received: Option<u64>,
+    waker: Option<Waker>,
+}
+
+// Outstanding echo requests by identifier and sequence number
+static PENDING_ECHOS: Mutex<BTreeMap<(u16, u16), PendingEcho>> = Mutex::new(BTreeMap::new());
+
+/// Handles an ICMP message received in an IPv4 datagram:
+/// - Answers echo requests addressed to us
+/// - Passes echo replies to the waiting `ping`
+pub fn handle_packet(header: &Ipv4Header, payload: &[u8]) {
+    let message = match IcmpMessage::from_bytes(payload) {
+        Ok(message) => message,
+        Err(_) => {
+            netstat::ICMP.count_dropped();
+            return;
+        }
+    };
+    netstat::ICMP.count_received();
+
+    match message.icmp_type {
+        TYPE_ECHO_REQUEST => {
+            // like most hosts, we do not answer broadcast pings
+            if ipv4::is_broadcast(header.dst) {
+                return;
+            }
+            let reply = IcmpMessage {
+                icmp_type: TYPE_ECHO_REPLY,
+                code: 0,
+                rest_of_header: message.rest_of_header,
+                data: message.data,
+            };
+            let _ = ipv4::send_packet(header.src, ipv4::PROTOCOL_ICMP, &reply.to_bytes());
+        }
+        TYPE_ECHO_REPLY => {
+            let received = time::ticks();
+            let mut pending_echos = PENDING_ECHOS.lock();
+            if let Some(echo) = pending_echos.get_mut(&(message.identifier(), message.sequence())) {
+                echo.received = Some(received);
+                if let Some(waker) = echo.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Tells the sender of the given datagram that it could not be delivered
+///
+/// The message contains the IPv4 header and the first 8 bytes of the payload
+/// of the datagram, so the sender can match it to the socket it came from.
+pub fn send_destination_unreachable(header: &Ipv4Header, payload: &[u8], code: u8) {
+    if ipv4::is_broadcast(header.dst) {
+        return;
+    }
+    let mut data = header.to_bytes();
+    data.extend_from_slice(&payload[..min(8, payload.len())]);
+    let message = IcmpMessage {
+        icmp_type: TYPE_DESTINATION_UNREACHABLE,
+        code,
+        rest_of_header: [0; 4],
+        data,
+    };
+    let _ = ipv4::send_packet(header.src, ipv4::PROTOCOL_ICMP, &message.to_bytes());
+}
+
+/// Future that completes with the tick count at which the echo reply arrived
+struct EchoReply {
+    identifier: u16,
+    sequence: u16,
+}
+
+impl Future for EchoReply {
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u64> {
+        let mut pending_echos = PENDING_ECHOS.lock();
+        let echo = pending_echos
+            .get_mut(&(self.identifier, self.sequence))
+            .expect("echo reply polled without pending echo request");
+        match echo.received {
+            Some(received) => Poll::Ready(received),
+            None => {
+                echo.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Summary of a `ping` run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingStatistics {
+    pub transmitted: u16,
+    pub received: u16,
+    /// Round-trip times of the received replies in milliseconds
+    pub round_trip_times: Vec<u64>,
+}
+
+/// Sends `count` echo requests to the given address, one per second,
+/// and prints the round-trip time of every reply
+pub async fn ping(addr: [u8; 4], count: u16) -> Result<PingStatistics, &'static str> {
+    let identifier = NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    let data: Vec<u8> = (0..PING_DATA_SIZE as u8).collect();
+    let mut statistics = PingStatistics {
+        transmitted: 0,
+        received: 0,
+        round_trip_times: Vec::new(),
+    };
+
+    println!("PING {}.{}.{}.{}: {} data bytes", addr[0], addr[1], addr[2], addr[3], PING_DATA_SIZE);
+    for sequence in 0..count {
+        let request = IcmpMessage::echo(TYPE_ECHO_REQUEST, identifier, sequence, data.clone());
+        PENDING_ECHOS.lock().insert(
+            (identifier, sequence),
+            PendingEcho { received: None, waker: None },
+        );
+
+        let sent = time::ticks();
+        let result = ipv4::send_packet(addr, ipv4::PROTOCOL_ICMP, &request.to_bytes());
+        let reply = match result {
+            Ok(()) => time::timeout(PING_TIMEOUT, EchoReply { identifier, sequence }).await,
+            Err(_) => None,
+        };
+        PENDING_ECHOS.lock().remove(&(identifier, sequence));
+        result?;
+        statistics.transmitted += 1;
+
+        match reply {
+            Some(received) => {
+                let round_trip_time = time::millis_from_ticks(received - sent);
+                println!(
+                    "{} bytes from {}.{}.{}.{}: icmp_seq={} time={} ms",
+                    HEADER_SIZE + PING_DATA_SIZE, addr[0], addr[1], addr[2], addr[3], sequence, round_trip_time
+                );
+                statistics.received += 1;
+                statistics.round_trip_times.push(round_trip_time);
+            }
+            None => println!("Request timeout for icmp_seq {}", sequence),
+        }
+
+        if sequence + 1 < count {
+            time::sleep_until(sent + PING_INTERVAL).await;
+        }
+    }
+
+    println!(
+        "{} packets transmitted, {} packets received",
+        statistics.transmitted, statistics.received
+    );
+    Ok(statistics)
+}
+
+#[test_case]
+fn test_echo_message_round_trip() {
+    let message = IcmpMessage::echo(TYPE_ECHO_REQUEST, 0x1234, 7, Vec::from(&b"blog_os"[..]));
+    let bytes = message.to_bytes();
+    assert_eq!(ipv4::checksum(&bytes), 0);
+
+    let parsed = IcmpMessage::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.identifier(), 0x1234);
+    assert_eq!(parsed.sequence(), 7);
+    assert_eq!(parsed, message);
+}
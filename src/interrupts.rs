@@ -1,12 +1,19 @@
-use crate::{gdt, hlt_loop, print, println};
+use crate::{apic, gdt, hlt_loop, print, println};
 use alloc::{vec::Vec, string::String};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 pub const PIC_1_OFFSET: u8 = 0x20;
 pub const PIC_2_OFFSET: u8 = 0x28;
+/// The vectors behind the ones of the PICs are handed out for Message Signaled Interrupts
+pub const MSI_VECTOR_OFFSET: u8 = PIC_2_OFFSET + 8;
+const MSI_VECTORS: usize = 8;
+
+/// Handlers of the MSI vectors, in the order of the vectors
+static MSI_HANDLERS: Mutex<[Option<fn()>; MSI_VECTORS]> = Mutex::new([None; MSI_VECTORS]);
 
 static mut COUNT_DOWN: u32 = 0;
 
@@ -78,6 +85,18 @@ lazy_static! {
         if rtl8139.is_some() {
             idt[rtl8139.unwrap() as usize].set_handler_fn(rtl8139_interrupt_handler);
         }
+        let e1000 = INDEX.lock().get("E1000");
+        if let Some(line) = e1000 {
+            idt[line as usize].set_handler_fn(e1000_interrupt_handler);
+        }
+        let virtio_net = INDEX.lock().get("VirtioNet");
+        if let Some(line) = virtio_net {
+            idt[line as usize].set_handler_fn(virtio_net_interrupt_handler);
+        }
+        for (i, handler) in MSI_INTERRUPT_HANDLERS.iter().enumerate() {
+            idt[MSI_VECTOR_OFFSET as usize + i].set_handler_fn(*handler);
+        }
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -105,6 +124,55 @@ pub fn regiser_interrupt(name: &str, line: u8) {
     INDEX.lock().insert(name, PIC_1_OFFSET + line)
 }
 
+/// Hands out a free MSI vector whose interrupts are passed to `handler`,
+/// to be configured with `PciDevice::enable_msi` or `MsixTable::set_entry`
+///
+/// The handler runs in interrupt context, so it must not allocate or block.
+/// The interrupt is acknowledged at the local APIC after it returns.
+pub fn register_msi_handler(handler: fn()) -> Result<u8, &'static str> {
+    without_interrupts(|| {
+        let mut handlers = MSI_HANDLERS.lock();
+        let index = handlers
+            .iter()
+            .position(|handler| handler.is_none())
+            .ok_or("no free MSI vector")?;
+        handlers[index] = Some(handler);
+        Ok(MSI_VECTOR_OFFSET + index as u8)
+    })
+}
+
+fn handle_msi(index: usize) {
+    let handler = MSI_HANDLERS.lock()[index];
+    if let Some(handler) = handler {
+        handler();
+    }
+    apic::end_of_interrupt();
+}
+
+// the vector of an interrupt is only known from the handler the IDT called,
+// so every MSI vector gets its own
+macro_rules! msi_interrupt_handlers {
+    ($($name:ident: $index:expr),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                handle_msi($index);
+            }
+        )*
+        const MSI_INTERRUPT_HANDLERS: [HandlerFunc; MSI_VECTORS] = [$($name),*];
+    };
+}
+
+msi_interrupt_handlers!(
+    msi_interrupt_handler_0: 0,
+    msi_interrupt_handler_1: 1,
+    msi_interrupt_handler_2: 2,
+    msi_interrupt_handler_3: 3,
+    msi_interrupt_handler_4: 4,
+    msi_interrupt_handler_5: 5,
+    msi_interrupt_handler_6: 6,
+    msi_interrupt_handler_7: 7
+);
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -131,6 +199,7 @@ extern "x86-interrupt" fn double_fault_handler(
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     // print!(".");
+    crate::time::tick();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(INDEX.lock().get("Timer").unwrap());
@@ -160,6 +229,27 @@ extern "x86-interrupt" fn rtl8139_interrupt_handler(_stack_frame: InterruptStack
     }
 }
 
+extern "x86-interrupt" fn e1000_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::e1000::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(INDEX.lock().get("E1000").unwrap());
+    }
+}
+
+extern "x86-interrupt" fn virtio_net_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::virtio_net::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(INDEX.lock().get("VirtioNet").unwrap());
+    }
+}
+
+// spurious interrupts of the local APIC are not acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
@@ -0,0 +1,442 @@
+use crate::{arp, ethernet, icmp, netconfig, netstat, tcp, time, udp};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicU16, Ordering},
+};
+use spin::Mutex;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const BROADCAST_ADDRESS: [u8; 4] = [0xff; 4];
+
+// Protocol
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const VERSION: u8 = 4;
+/// Size of an IPv4 header without options
+pub const HEADER_SIZE: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+/// Largest IPv4 packet that fits into an Ethernet frame
+pub const MTU: usize = 1500;
+const MAX_PACKET_SIZE: usize = 65535;
+
+// Flags and Fragment Offset
+const DONT_FRAGMENT: u16 = 0x4000;
+const MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+/// Time after which incomplete datagrams are discarded
+const REASSEMBLY_TIMEOUT: u64 = 30 * time::TICKS_PER_SECOND;
+/// Maximum number of datagrams being reassembled at the same time
+const MAX_REASSEMBLIES: usize = 8;
+
+// Identification of the next sent datagram
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+/// IPv4 header, without options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub type_of_service: u8,
+    /// Length of header and payload in bytes
+    pub total_length: u16,
+    pub identification: u16,
+    pub dont_fragment: bool,
+    pub more_fragments: bool,
+    /// Offset of the payload inside the original datagram in bytes
+    pub fragment_offset: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub src: [u8; 4],
+    pub dst: [u8; 4],
+}
+
+impl Ipv4Header {
+    // returns the header in bytes, including its checksum
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags_fragment_offset = (self.fragment_offset / 8) & FRAGMENT_OFFSET_MASK;
+        if self.dont_fragment {
+            flags_fragment_offset |= DONT_FRAGMENT;
+        }
+        if self.more_fragments {
+            flags_fragment_offset |= MORE_FRAGMENTS;
+        }
+
+        let mut result: Vec<u8> = Vec::with_capacity(HEADER_SIZE);
+        result.push(VERSION << 4 | (HEADER_SIZE / 4) as u8);
+        result.push(self.type_of_service);
+        result.extend_from_slice(&self.total_length.to_be_bytes());
+        result.extend_from_slice(&self.identification.to_be_bytes());
+        result.extend_from_slice(&flags_fragment_offset.to_be_bytes());
+        result.push(self.ttl);
+        result.push(self.protocol);
+        result.extend_from_slice(&[0, 0]);
+        result.extend_from_slice(&self.src);
+        result.extend_from_slice(&self.dst);
+
+        let checksum = checksum(&result);
+        result[10..12].copy_from_slice(&checksum.to_be_bytes());
+        result
+    }
+
+    /// Parses and validates an IPv4 header
+    ///
+    /// Returns the header and the payload behind it and its options,
+    /// without any padding of the Ethernet frame.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), &'static str> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("IPv4 packet too short");
+        }
+        if bytes[0] >> 4 != VERSION {
+            return Err("not an IPv4 packet");
+        }
+        let header_length = (bytes[0] & 0xf) as usize * 4;
+        let total_length = u16::from_be_bytes([bytes[2], bytes[3]]);
+        if header_length < HEADER_SIZE
+            || (total_length as usize) < header_length
+            || (total_length as usize) > bytes.len()
+        {
+            return Err("invalid IPv4 header or total length");
+        }
+        if checksum(&bytes[..header_length]) != 0 {
+            return Err("invalid IPv4 header checksum");
+        }
+        if bytes[8] == 0 {
+            return Err("IPv4 packet with expired TTL");
+        }
+
+        let flags_fragment_offset = u16::from_be_bytes([bytes[6], bytes[7]]);
+        let mut header = Ipv4Header {
+            type_of_service: bytes[1],
+            total_length,
+            identification: u16::from_be_bytes([bytes[4], bytes[5]]),
+            dont_fragment: flags_fragment_offset & DONT_FRAGMENT != 0,
+            more_fragments: flags_fragment_offset & MORE_FRAGMENTS != 0,
+            fragment_offset: (flags_fragment_offset & FRAGMENT_OFFSET_MASK) * 8,
+            ttl: bytes[8],
+            protocol: bytes[9],
+            src: [0; 4],
+            dst: [0; 4],
+        };
+        header.src.copy_from_slice(&bytes[12..16]);
+        header.dst.copy_from_slice(&bytes[16..20]);
+        Ok((header, &bytes[header_length..total_length as usize]))
+    }
+}
+
+/// Computes the Internet checksum (RFC 1071) of the given data
+///
+/// Computing it over data that contains a valid checksum results in 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Computes the checksum of a UDP or TCP segment, covering the pseudo header
+/// made of source and destination address, protocol and segment length
+///
+/// Computing it over a segment that contains a valid checksum results in 0.
+pub fn pseudo_header_checksum(src: [u8; 4], dst: [u8; 4], protocol: u8, segment: &[u8]) -> u16 {
+    let mut data: Vec<u8> = Vec::with_capacity(12 + segment.len());
+    data.extend_from_slice(&src);
+    data.extend_from_slice(&dst);
+    data.push(0);
+    data.push(protocol);
+    data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    data.extend_from_slice(segment);
+    checksum(&data)
+}
+
+fn apply_netmask(address: [u8; 4], netmask: [u8; 4]) -> [u8; 4] {
+    [
+        address[0] & netmask[0],
+        address[1] & netmask[1],
+        address[2] & netmask[2],
+        address[3] & netmask[3],
+    ]
+}
+
+/// Returns whether the given address is inside the configured subnet
+pub fn is_on_link(address: [u8; 4]) -> bool {
+    let config = netconfig::config();
+    apply_netmask(address, config.netmask) == apply_netmask(config.address, config.netmask)
+}
+
+/// Returns whether the given address is the limited broadcast address
+/// or the broadcast address of the configured subnet
+pub fn is_broadcast(address: [u8; 4]) -> bool {
+    let config = netconfig::config();
+    let mut subnet_broadcast = config.address;
+    for i in 0..4 {
+        subnet_broadcast[i] |= !config.netmask[i];
+    }
+    address == BROADCAST_ADDRESS || address == subnet_broadcast
+}
+
+/// Returns the address packets to the given destination are sent to:
+/// the destination itself if it is on link, otherwise the default gateway
+pub fn next_hop(dst: [u8; 4]) -> Result<[u8; 4], &'static str> {
+    if is_broadcast(dst) || is_on_link(dst) {
+        Ok(dst)
+    } else {
+        netconfig::config().gateway.ok_or("no route to host")
+    }
+}
+
+/// Sends the payload to the given destination,
+/// fragmenting it if it does not fit into a single Ethernet frame
+///
+/// If the next hop is not resolved yet, all fragments wait for it together.
+pub fn send_packet(dst: [u8; 4], protocol: u8, payload: &[u8]) -> Result<(), &'static str> {
+    let next_hop = next_hop(dst)?;
+    let packets = fragment(dst, protocol, payload)?;
+    let count = packets.len();
+
+    if is_broadcast(dst) {
+        for packet in packets {
+            ethernet::send_payload(ethernet::BROADCAST_MAC, ETHERTYPE_IPV4, packet)?;
+        }
+    } else {
+        arp::send_payloads(next_hop, ETHERTYPE_IPV4, packets);
+    }
+    for _ in 0..count {
+        netstat::IPV4.count_sent();
+    }
+    count_sent(protocol);
+    Ok(())
+}
+
+/// Like `send_packet`, but resolves the next hop first and waits for room
+/// in the transmit queue of the interface instead of dropping fragments
+pub async fn send_packet_waiting(dst: [u8; 4], protocol: u8, payload: &[u8]) -> Result<(), &'static str> {
+    let next_hop = next_hop(dst)?;
+    let packets = fragment(dst, protocol, payload)?;
+    let mac = if is_broadcast(dst) {
+        ethernet::BROADCAST_MAC
+    } else {
+        arp::resolve(next_hop).await.ok_or("destination unreachable")?
+    };
+
+    for packet in packets {
+        ethernet::send_payload_waiting(mac, ETHERTYPE_IPV4, packet).await?;
+        netstat::IPV4.count_sent();
+    }
+    count_sent(protocol);
+    Ok(())
+}
+
+/// Builds the packets carrying the payload, a single one if it fits into an Ethernet frame
+fn fragment(dst: [u8; 4], protocol: u8, payload: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+    if payload.len() > MAX_PACKET_SIZE - HEADER_SIZE {
+        return Err("payload too large for an IPv4 packet");
+    }
+    let src = netconfig::ipv4_address();
+    let identification = NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+    // all fragments but the last must carry a multiple of 8 bytes
+    let max_fragment_size = (MTU - HEADER_SIZE) & !0x7;
+
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    loop {
+        let end = min(offset + max_fragment_size, payload.len());
+        let header = Ipv4Header {
+            type_of_service: 0,
+            total_length: (HEADER_SIZE + end - offset) as u16,
+            identification,
+            dont_fragment: false,
+            more_fragments: end < payload.len(),
+            fragment_offset: offset as u16,
+            ttl: DEFAULT_TTL,
+            protocol,
+            src,
+            dst,
+        };
+        let mut packet = header.to_bytes();
+        packet.extend_from_slice(&payload[offset..end]);
+        packets.push(packet);
+
+        offset = end;
+        if offset >= payload.len() {
+            return Ok(packets);
+        }
+    }
+}
+
+fn count_sent(protocol: u8) {
+    match protocol {
+        PROTOCOL_ICMP => netstat::ICMP.count_sent(),
+        PROTOCOL_UDP => netstat::UDP.count_sent(),
+        PROTOCOL_TCP => netstat::TCP.count_sent(),
+        _ => {}
+    }
+}
+
+/// Registers IPv4 with `ethernet` to receive its frames
+pub fn init() {
+    ethernet::register_handler(ETHERTYPE_IPV4, |frame| handle_packet(frame.payload))
+        .expect("IPv4 handler registered twice");
+}
+
+/// Handles an IPv4 packet received in an Ethernet frame:
+/// - Validates its header
+/// - Drops packets that are not addressed to us
+/// - Reassembles fragmented datagrams
+/// - Passes the payload to the protocol given in the header
+pub fn handle_packet(payload: &[u8]) {
+    let (header, data) = match Ipv4Header::from_bytes(payload) {
+        Ok(packet) => packet,
+        Err(_) => {
+            netstat::IPV4.count_dropped();
+            return;
+        }
+    };
+    // until an address is leased, unicast datagrams to any address are accepted,
+    // as DHCP servers may send their offers to the offered address (RFC 1122)
+    if netconfig::is_configured() && header.dst != netconfig::ipv4_address() && !is_broadcast(header.dst) {
+        netstat::IPV4.count_dropped();
+        return;
+    }
+    netstat::IPV4.count_received();
+
+    if header.more_fragments || header.fragment_offset != 0 {
+        if let Some(datagram) = reassemble(&header, data) {
+            deliver(&header, &datagram);
+        }
+    } else {
+        deliver(&header, data);
+    }
+}
+
+/// Passes the payload of a complete datagram to its protocol
+fn deliver(header: &Ipv4Header, payload: &[u8]) {
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle_packet(header, payload),
+        PROTOCOL_UDP => udp::handle_packet(header, payload),
+        PROTOCOL_TCP => tcp::handle_packet(header, payload),
+        _ => icmp::send_destination_unreachable(header, payload, icmp::CODE_PROTOCOL_UNREACHABLE),
+    }
+}
+
+/// Identifies the fragments belonging to the same datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ReassemblyKey {
+    src: [u8; 4],
+    dst: [u8; 4],
+    protocol: u8,
+    identification: u16,
+}
+
+/// Fragments of a datagram that has not been received completely yet
+struct Reassembly {
+    started: u64,
+    fragments: Vec<(usize, Vec<u8>)>,
+    total_length: Option<usize>,
+}
+
+impl Reassembly {
+    /// Returns the complete datagram, if all fragments have been received
+    fn assemble(&mut self) -> Option<Vec<u8>> {
+        let total_length = self.total_length?;
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+
+        let mut covered = 0;
+        for (offset, data) in &self.fragments {
+            if *offset > covered {
+                return None;
+            }
+            covered = covered.max(offset + data.len());
+        }
+        if covered < total_length {
+            return None;
+        }
+
+        let mut datagram = vec![0; total_length];
+        for (offset, data) in &self.fragments {
+            let end = min(offset + data.len(), total_length);
+            if *offset < end {
+                datagram[*offset..end].copy_from_slice(&data[..end - offset]);
+            }
+        }
+        Some(datagram)
+    }
+}
+
+// Datagrams currently being reassembled
+static REASSEMBLIES: Mutex<BTreeMap<ReassemblyKey, Reassembly>> = Mutex::new(BTreeMap::new());
+
+/// Adds a fragment to its datagram
+///
+/// Returns the complete datagram once its last missing fragment arrived.
+fn reassemble(header: &Ipv4Header, data: &[u8]) -> Option<Vec<u8>> {
+    let offset = header.fragment_offset as usize;
+    if offset + data.len() > MAX_PACKET_SIZE - HEADER_SIZE {
+        return None;
+    }
+
+    let key = ReassemblyKey {
+        src: header.src,
+        dst: header.dst,
+        protocol: header.protocol,
+        identification: header.identification,
+    };
+    let now = time::ticks();
+    let mut reassemblies = REASSEMBLIES.lock();
+    reassemblies.retain(|_, reassembly| now - reassembly.started < REASSEMBLY_TIMEOUT);
+    if !reassemblies.contains_key(&key) && reassemblies.len() >= MAX_REASSEMBLIES {
+        return None;
+    }
+
+    let reassembly = reassemblies.entry(key).or_insert_with(|| Reassembly {
+        started: now,
+        fragments: Vec::new(),
+        total_length: None,
+    });
+    if !header.more_fragments {
+        reassembly.total_length = Some(offset + data.len());
+    }
+    reassembly.fragments.push((offset, data.to_vec()));
+
+    let datagram = reassembly.assemble()?;
+    reassemblies.remove(&key);
+    Some(datagram)
+}
+
+#[test_case]
+fn test_checksum() {
+    let header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+        0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(checksum(&header), 0xb861);
+}
+
+#[test_case]
+fn test_header_round_trip() {
+    let header = Ipv4Header {
+        type_of_service: 0,
+        total_length: HEADER_SIZE as u16 + 4,
+        identification: 42,
+        dont_fragment: false,
+        more_fragments: true,
+        fragment_offset: 1480,
+        ttl: DEFAULT_TTL,
+        protocol: PROTOCOL_UDP,
+        src: [10, 0, 2, 15],
+        dst: [10, 0, 2, 2],
+    };
+    let mut packet = header.to_bytes();
+    packet.extend_from_slice(&[1, 2, 3, 4, 0, 0]);
+    assert_eq!(Ipv4Header::from_bytes(&packet), Ok((header, &[1, 2, 3, 4][..])));
+}
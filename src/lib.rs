@@ -10,7 +10,9 @@ extern crate alloc;
 use core::panic::PanicInfo;
 use bootloader::BootInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
@@ -18,8 +20,24 @@ pub mod serial;
 pub mod task;
 pub mod vga_buffer;
 pub mod rtl8139;
+pub mod virtio_net;
+pub mod e1000;
 pub mod pci;
+pub mod netdev;
+pub mod loopback;
+pub mod netstat;
+pub mod ethmsg;
+pub mod pcap;
 pub mod ethernet;
+pub mod arp;
+pub mod ipv4;
+pub mod icmp;
+pub mod udp;
+pub mod tcp;
+pub mod dhcp;
+pub mod dns;
+pub mod netconfig;
+pub mod time;
 
 pub fn init(boot_info: &'static BootInfo) {
     use memory::BootInfoFrameAllocator;
@@ -30,9 +48,20 @@ pub fn init(boot_info: &'static BootInfo) {
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::set_frame_allocator(frame_allocator);
+    memory::set_mapper(mapper);
 
     gdt::init();
+    time::init();
     rtl8139::init();
+    virtio_net::init();
+    e1000::init();
+    arp::init();
+    ipv4::init();
+    ethmsg::init();
+    if let Err(err) = apic::init() {
+        println!("APIC: {}", err);
+    }
     interrupts::init_idt();
     unsafe {
         let mut pics = interrupts::PICS.lock();
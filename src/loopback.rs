@@ -0,0 +1,88 @@
+use crate::{
+    ethernet,
+    netdev::{self, FrameQueue, FrameStream, InterfaceId, LinkStatus, NetDevice, NetDeviceStats},
+};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Locally administered MAC address of the loopback device
+pub const MAC_ADDRESS: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+/// Number of sent frames that can wait to be received
+pub const FRAME_QUEUE_SIZE: usize = 32;
+/// Largest frame (without CRC) that is reflected
+const MAX_FRAME_SIZE: usize = netdev::ETHERNET_MTU + ethernet::HEADER_SIZE;
+
+static DEVICE: OnceCell<Loopback> = OnceCell::uninit();
+
+/// Network device that receives every frame it sends,
+/// for testing the protocol layers without a network card
+pub struct Loopback {
+    frames: FrameQueue,
+    sent_frames: AtomicU64,
+    sent_bytes: AtomicU64,
+    dropped_frames: AtomicU64,
+}
+
+/// Creates the loopback device and registers it as a network interface
+///
+/// Returns the id of the interface. Panics if called more than once.
+pub fn init() -> InterfaceId {
+    DEVICE
+        .try_init_once(|| Loopback {
+            frames: FrameQueue::new(FRAME_QUEUE_SIZE, MAX_FRAME_SIZE),
+            sent_frames: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+        })
+        .expect("loopback::init should only be called once");
+    netdev::register(device().unwrap())
+}
+
+/// Returns the loopback device, if it was created
+pub fn device() -> Option<&'static Loopback> {
+    DEVICE.try_get().ok()
+}
+
+impl NetDevice for Loopback {
+    fn name(&self) -> &str {
+        "loopback"
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        MAC_ADDRESS
+    }
+
+    /// Queues the frame for the receive stream
+    ///
+    /// Frames that do not fit into the queue are dropped, like on a real link.
+    fn send_frame(&self, frame: &[u8]) -> Result<(), &'static str> {
+        if frame.len() > MAX_FRAME_SIZE {
+            self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            return Err("frame too large");
+        }
+        self.sent_frames.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    fn receive_stream(&'static self) -> FrameStream {
+        self.frames.stream()
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        LinkStatus::Up
+    }
+
+    fn stats(&self) -> NetDeviceStats {
+        let mut stats = NetDeviceStats {
+            tx_frames: self.sent_frames.load(Ordering::Relaxed),
+            tx_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            tx_errors: self.dropped_frames.load(Ordering::Relaxed),
+            ..NetDeviceStats::default()
+        };
+        self.frames.add_stats(&mut stats);
+        stats
+    }
+}
@@ -7,7 +7,13 @@
 extern crate alloc;
 
 use blog_os::{
+    dhcp,
+    ethernet,
+    netdev,
+    pcap,
     println,
+    tcp,
+    udp,
     task::{
         executor::Executor, 
         keyboard, 
@@ -33,6 +39,15 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut executor = Executor::new();
     // executor.spawn(Task::new(example_task()));
     executor.spawn(Task::new(keyboard::print_keypresses()));
+    for (id, _) in netdev::interfaces() {
+        executor.spawn(Task::new(ethernet::process_frames(id)));
+    }
+    executor.spawn(Task::new(netdev::monitor_links()));
+    executor.spawn(Task::new(netdev::transmit_queued()));
+    executor.spawn(Task::new(pcap::write_records()));
+    executor.spawn(Task::new(tcp::process_timers()));
+    executor.spawn(Task::new(dhcp::run_client()));
+    executor.spawn(Task::new(udp::echo_server(udp::ECHO_PORT)));
     executor.run();
 }
 
@@ -3,9 +3,16 @@ use bootloader::bootinfo::{
     MemoryRegionType
 };
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
+use core::{
+    mem::size_of,
+    ptr::{read_volatile, write_volatile},
+    slice,
+};
 use spin::Mutex;
 use lazy_static::lazy_static;
 
@@ -83,6 +90,31 @@ impl BootInfoFrameAllocator {
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Returns the first of `count` physically contiguous usable frames
+    /// that all lie below `limit`.
+    ///
+    /// Usable frames that are skipped while searching are never handed out.
+    fn allocate_contiguous(&mut self, count: usize, limit: u64) -> Option<PhysFrame> {
+        let mut run_start: Option<(usize, PhysFrame)> = None;
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            if frame.start_address().as_u64() + 4096 > limit {
+                return None;
+            }
+            let start = match run_start {
+                Some((start_index, start))
+                    if start.start_address().as_u64() + 4096 * (index - start_index) as u64
+                        == frame.start_address().as_u64() => (start_index, start),
+                _ => (index, frame),
+            };
+            run_start = Some(start);
+            if index - start.0 + 1 == count {
+                self.next = index + 1;
+                return Some(start.1);
+            }
+        }
+        None
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -138,13 +170,202 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
+/// Hands the frame allocator over to the memory service once the heap is set up,
+/// so that drivers can allocate DMA buffers from it.
+pub fn set_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    MEMORY_SERVICE.lock().frame_allocator = Some(frame_allocator);
+}
+
+/// Hands the page table over to the memory service once the heap is set up,
+/// so that drivers can map the registers of their devices.
+pub fn set_mapper(mapper: OffsetPageTable<'static>) {
+    MEMORY_SERVICE.lock().mapper = Some(mapper);
+}
+
+/// Returns the virtual address the given physical address is mapped to
+/// in the complete mapping of physical memory.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    MEMORY_SERVICE.lock().physical_memory_offset + addr.as_u64()
+}
+
+/// Addresses of DMA buffers must fit into 32 bits for devices like the RTL8139.
+const DMA_LIMIT: u64 = 0x1_0000_0000;
+
+/// Physically contiguous memory below 4 GiB that a device can access directly.
+///
+/// The memory is accessed through the complete mapping of physical memory.
+/// Frames cannot be freed yet, so a buffer is never returned to the allocator.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a zeroed buffer of at least `size` bytes, rounded up to whole frames.
+    pub fn new(size: usize) -> Option<DmaBuffer> {
+        let count = (size + 4095) / 4096;
+        let phys = {
+            let mut memory_service = MEMORY_SERVICE.lock();
+            let frame_allocator = memory_service.frame_allocator.as_mut()?;
+            frame_allocator.allocate_contiguous(count, DMA_LIMIT)?.start_address()
+        };
+        let mut buffer = DmaBuffer {
+            phys,
+            virt: phys_to_virt(phys),
+            size: count * 4096,
+        };
+        buffer.as_mut_slice().fill(0);
+        Some(buffer)
+    }
+
+    /// The address the device uses to access the buffer.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size) }
+    }
+}
+
+/// Start of the virtual address range MMIO regions are mapped to.
+pub const MMIO_START: u64 = 0x_5555_0000_0000;
+/// Size of the virtual address range for MMIO regions.
+pub const MMIO_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// Registers or memory of a device, mapped with caching disabled.
+///
+/// Every access is volatile and checked against the bounds of the region.
+/// Regions are never unmapped, like DMA buffers are never freed.
+#[derive(Debug)]
+pub struct MmioRegion {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Maps `size` bytes of device memory starting at `phys` with `NO_CACHE | WRITE_THROUGH`
+    /// into the MMIO address range.
+    pub fn map(phys: PhysAddr, size: usize) -> Result<MmioRegion, &'static str> {
+        if size == 0 {
+            return Err("MMIO region is empty");
+        }
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (size - 1) as u64);
+        let mapped_size = (last_frame - first_frame + 1) * 4096;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH;
+
+        let mut memory_service = MEMORY_SERVICE.lock();
+        let memory_service = &mut *memory_service;
+        let mapper = memory_service.mapper.as_mut().ok_or("page table not handed to the memory service")?;
+        let frame_allocator = memory_service.frame_allocator.as_mut().ok_or("no frame allocator")?;
+        let start = memory_service.next_mmio;
+        if start.as_u64() + mapped_size > MMIO_START + MMIO_SIZE {
+            return Err("MMIO address range exhausted");
+        }
+
+        for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+            let page = Page::<Size4KiB>::containing_address(start + i as u64 * 4096);
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+                .map_err(|_| "mapping an MMIO page failed")?
+                .flush();
+        }
+        memory_service.next_mmio = start + mapped_size;
+
+        Ok(MmioRegion {
+            phys,
+            virt: start + (phys.as_u64() - first_frame.start_address().as_u64()),
+            size,
+        })
+    }
+
+    /// The physical address the region starts at.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn read_8(&self, offset: usize) -> u8 {
+        unsafe { read_volatile(self.register(offset)) }
+    }
+
+    pub fn read_16(&self, offset: usize) -> u16 {
+        unsafe { read_volatile(self.register(offset)) }
+    }
+
+    pub fn read_32(&self, offset: usize) -> u32 {
+        unsafe { read_volatile(self.register(offset)) }
+    }
+
+    pub fn read_64(&self, offset: usize) -> u64 {
+        unsafe { read_volatile(self.register(offset)) }
+    }
+
+    pub fn write_8(&self, offset: usize, value: u8) {
+        unsafe { write_volatile(self.register(offset), value) }
+    }
+
+    pub fn write_16(&self, offset: usize, value: u16) {
+        unsafe { write_volatile(self.register(offset), value) }
+    }
+
+    pub fn write_32(&self, offset: usize, value: u32) {
+        unsafe { write_volatile(self.register(offset), value) }
+    }
+
+    pub fn write_64(&self, offset: usize, value: u64) {
+        unsafe { write_volatile(self.register(offset), value) }
+    }
+
+    /// Returns a pointer to the register of type `T` at the given offset.
+    ///
+    /// Panics if the register is not naturally aligned or not completely inside the region.
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            matches!(offset.checked_add(size_of::<T>()), Some(end) if end <= self.size),
+            "MMIO access at {:#x} outside of the region of {:#x} bytes", offset, self.size
+        );
+        let addr = self.virt + offset as u64;
+        assert!(addr.is_aligned(size_of::<T>() as u64), "unaligned MMIO access at {:#x}", offset);
+        addr.as_mut_ptr()
+    }
+}
+
 struct MemoryService {
     physical_memory_offset: VirtAddr,
+    frame_allocator: Option<BootInfoFrameAllocator>,
+    mapper: Option<OffsetPageTable<'static>>,
+    // Start of the next MMIO region
+    next_mmio: VirtAddr,
 }
 
 impl MemoryService {
     fn new() -> MemoryService {
-        MemoryService { physical_memory_offset: VirtAddr::zero()}
+        MemoryService {
+            physical_memory_offset: VirtAddr::zero(),
+            frame_allocator: None,
+            mapper: None,
+            next_mmio: VirtAddr::new(MMIO_START),
+        }
     }
 
     fn set_physical_memory_offset(&mut self, offset: VirtAddr) {
@@ -0,0 +1,53 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Address of an interface that has not been configured yet
+pub const UNSPECIFIED_ADDRESS: [u8; 4] = [0; 4];
+
+/// IPv4 configuration of the network interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    /// The IPv4 address of this host
+    pub address: [u8; 4],
+    /// The netmask of the subnet this host is in
+    pub netmask: [u8; 4],
+    /// The router packets outside of the subnet are sent to
+    pub gateway: Option<[u8; 4]>,
+    /// The DNS servers to resolve hostnames with
+    pub dns_servers: Vec<[u8; 4]>,
+}
+
+impl InterfaceConfig {
+    /// Returns the configuration of an interface without an address,
+    /// until one is leased by DHCP
+    pub const fn unconfigured() -> Self {
+        InterfaceConfig {
+            address: UNSPECIFIED_ADDRESS,
+            netmask: UNSPECIFIED_ADDRESS,
+            gateway: None,
+            dns_servers: Vec::new(),
+        }
+    }
+}
+
+static CONFIG: Mutex<InterfaceConfig> = Mutex::new(InterfaceConfig::unconfigured());
+
+/// Returns a copy of the current interface configuration
+pub fn config() -> InterfaceConfig {
+    CONFIG.lock().clone()
+}
+
+/// Replaces the current interface configuration
+pub fn set_config(config: InterfaceConfig) {
+    *CONFIG.lock() = config;
+}
+
+/// Returns the IPv4 address of this host
+pub fn ipv4_address() -> [u8; 4] {
+    CONFIG.lock().address
+}
+
+/// Returns whether the interface has an IPv4 address
+pub fn is_configured() -> bool {
+    ipv4_address() != UNSPECIFIED_ADDRESS
+}
@@ -0,0 +1,582 @@
+use crate::time;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    future::poll_fn,
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use spin::Mutex;
+
+/// MTU of Ethernet devices, the largest payload of a frame in bytes
+pub const ETHERNET_MTU: usize = 1500;
+
+/// Index of an interface in the registry, in the order the interfaces were registered
+pub type InterfaceId = usize;
+
+/// Whether a device is connected to a network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStatus {
+    Up,
+    Down,
+    /// The device cannot report its link status
+    Unknown,
+}
+
+/// Link status with the speed and duplex mode the device negotiated,
+/// as far as it can report them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub status: LinkStatus,
+    pub speed_mbps: Option<u32>,
+    pub full_duplex: Option<bool>,
+}
+
+/// Change of the link status of an interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkEvent {
+    pub interface: InterfaceId,
+    pub status: LinkStatus,
+}
+
+/// Interval in which `monitor_links` checks the link status of interfaces
+/// that do not report changes by interrupt
+const LINK_POLL_INTERVAL: u64 = time::TICKS_PER_SECOND;
+/// Number of events a subscriber holds before the oldest ones are dropped
+const MAX_QUEUED_LINK_EVENTS: usize = 16;
+/// Number of frames a device queues while all its transmit descriptors are in use,
+/// enough for a fragmented 64 KiB datagram
+const MAX_QUEUED_TRANSMIT_FRAMES: usize = 64;
+
+/// Frame counters of a device
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetDeviceStats {
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    /// Received frames that were dropped because no buffer was free
+    pub rx_dropped: u64,
+    /// Received frames that were dropped because they were damaged
+    pub rx_errors: u64,
+    /// Received frames with a wrong CRC, included in `rx_errors`
+    pub rx_crc_errors: u64,
+    /// Times the receive buffers of the device overflowed
+    pub rx_overflows: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    /// Frames that could not be sent
+    pub tx_errors: u64,
+    /// Transmissions the device aborted, included in `tx_errors`
+    pub tx_aborts: u64,
+    /// Transmissions during which the carrier was lost
+    pub tx_carrier_lost: u64,
+}
+
+/// A network interface card, or anything that behaves like one
+///
+/// Devices are registered once and live for as long as the kernel runs,
+/// so every method takes `&self` and devices synchronize their state internally.
+pub trait NetDevice: Send + Sync {
+    /// Short name of the device, e.g. for printing
+    fn name(&self) -> &str;
+
+    fn mac_address(&self) -> [u8; 6];
+
+    /// Largest payload of a frame in bytes
+    fn mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
+
+    /// Sends a complete Ethernet frame without its CRC
+    ///
+    /// Does not wait: if the device cannot take the frame right now, it is queued
+    /// and sent by `transmit_queued` once the device has room again.
+    /// Fails if the frame is too large or the queue is full.
+    fn send_frame(&self, frame: &[u8]) -> Result<(), &'static str>;
+
+    /// Sends the frame like `send_frame`, but is pending while the device's queue is full,
+    /// registering the waker to be woken once there is room, used by `netdev::send_frame`
+    ///
+    /// Devices that never queue frames keep the default, which sends right away.
+    fn poll_send_frame(&self, frame: &[u8], _cx: &mut Context) -> Poll<Result<(), &'static str>> {
+        Poll::Ready(self.send_frame(frame))
+    }
+
+    /// Hands queued frames to the device until it is busy again,
+    /// then registers the waker to be woken once it has room or new frames are queued
+    ///
+    /// Devices that never queue frames keep the default, which does nothing.
+    fn flush_transmit_queue(&self, _waker: &Waker) {}
+
+    /// Returns the stream of frames received by the device
+    ///
+    /// Panics if called more than once, as every frame is only handed out once.
+    fn receive_stream(&'static self) -> FrameStream;
+
+    fn link_status(&self) -> LinkStatus;
+
+    /// Returns the link status with speed and duplex mode
+    fn link_info(&self) -> LinkInfo {
+        LinkInfo {
+            status: self.link_status(),
+            speed_mbps: None,
+            full_duplex: None,
+        }
+    }
+
+    fn stats(&self) -> NetDeviceStats;
+}
+
+// Registered interfaces, indexed by InterfaceId
+static INTERFACES: Mutex<Vec<&'static dyn NetDevice>> = Mutex::new(Vec::new());
+
+/// Adds a device to the registry and returns its id
+pub fn register(device: &'static dyn NetDevice) -> InterfaceId {
+    let mut interfaces = INTERFACES.lock();
+    interfaces.push(device);
+    interfaces.len() - 1
+}
+
+/// Returns the interface with the given id
+pub fn interface(id: InterfaceId) -> Option<&'static dyn NetDevice> {
+    INTERFACES.lock().get(id).copied()
+}
+
+/// Returns all registered interfaces with their ids
+pub fn interfaces() -> Vec<(InterfaceId, &'static dyn NetDevice)> {
+    INTERFACES.lock().iter().copied().enumerate().collect()
+}
+
+/// Returns the interface the protocol layers send through: the first one registered
+pub fn default_interface() -> Option<&'static dyn NetDevice> {
+    interface(0)
+}
+
+// Set by devices when their link may have changed
+static LINK_CHANGED: AtomicBool = AtomicBool::new(false);
+static LINK_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Subscriber of link events
+struct Subscriber {
+    events: VecDeque<LinkEvent>,
+    waker: Option<Waker>,
+}
+
+// Link status of every interface as `monitor_links` last saw it
+static LINK_STATUS: Mutex<BTreeMap<InterfaceId, LinkStatus>> = Mutex::new(BTreeMap::new());
+
+// Subscribers of link events by id
+static SUBSCRIBERS: Mutex<BTreeMap<usize, Subscriber>> = Mutex::new(BTreeMap::new());
+static NEXT_SUBSCRIBER: AtomicUsize = AtomicUsize::new(0);
+
+/// Tells `monitor_links` that the link of a device may have changed
+///
+/// Safe to call from an interrupt handler.
+pub fn notify_link_change() {
+    LINK_CHANGED.store(true, Ordering::Relaxed);
+    LINK_WAKER.wake();
+}
+
+/// Future that completes once `notify_link_change` was called
+struct LinkChange;
+
+impl Future for LinkChange {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if LINK_CHANGED.swap(false, Ordering::Relaxed) {
+            return Poll::Ready(());
+        }
+        LINK_WAKER.register(cx.waker());
+        if LINK_CHANGED.swap(false, Ordering::Relaxed) {
+            LINK_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Checks the link status of all interfaces whenever a device reports a change,
+/// or at least every second, and sends an event to all subscribers for every change
+pub async fn monitor_links() {
+    loop {
+        for (id, device) in interfaces() {
+            let status = device.link_status();
+            // held while publishing, so new subscribers get every change exactly once
+            let mut known = LINK_STATUS.lock();
+            if known.insert(id, status) != Some(status) {
+                publish(LinkEvent { interface: id, status });
+            }
+        }
+        time::timeout(LINK_POLL_INTERVAL, LinkChange).await;
+    }
+}
+
+/// Queues the event for every subscriber
+fn publish(event: LinkEvent) {
+    for subscriber in SUBSCRIBERS.lock().values_mut() {
+        if subscriber.events.len() >= MAX_QUEUED_LINK_EVENTS {
+            subscriber.events.pop_front();
+        }
+        subscriber.events.push_back(event);
+        if let Some(waker) = subscriber.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Subscribes to the link events of all interfaces
+///
+/// The first event of every interface is its current status, as far as `monitor_links`
+/// has checked it, followed by every change after subscribing.
+pub fn subscribe_link_events() -> LinkEvents {
+    let id = NEXT_SUBSCRIBER.fetch_add(1, Ordering::Relaxed);
+    let known = LINK_STATUS.lock();
+    let events = known
+        .iter()
+        .map(|(&interface, &status)| LinkEvent { interface, status })
+        .collect();
+    SUBSCRIBERS.lock().insert(id, Subscriber { events, waker: None });
+    LinkEvents { id }
+}
+
+/// Stream of link events, unsubscribed when dropped
+#[derive(Debug)]
+pub struct LinkEvents {
+    id: usize,
+}
+
+impl LinkEvents {
+    /// Waits until the given interface reaches the given status
+    pub async fn wait_for(&mut self, interface: InterfaceId, status: LinkStatus) {
+        while let Some(event) = self.next().await {
+            if event.interface == interface && event.status == status {
+                return;
+            }
+        }
+    }
+}
+
+impl Stream for LinkEvents {
+    type Item = LinkEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<LinkEvent>> {
+        let mut subscribers = SUBSCRIBERS.lock();
+        let subscriber = subscribers
+            .get_mut(&self.id)
+            .expect("link events polled after unsubscribing");
+        match subscriber.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => {
+                subscriber.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for LinkEvents {
+    fn drop(&mut self) {
+        SUBSCRIBERS.lock().remove(&self.id);
+    }
+}
+
+/// Sends the frame through the device, waiting for room in its transmit queue
+/// instead of failing when the queue is full
+pub async fn send_frame(device: &dyn NetDevice, frame: &[u8]) -> Result<(), &'static str> {
+    poll_fn(|cx| device.poll_send_frame(frame, cx)).await
+}
+
+/// Hands the frames devices queued in `send_frame` to them as transmit descriptors become free
+pub async fn transmit_queued() {
+    poll_fn(|cx| {
+        for (_, device) in interfaces() {
+            device.flush_transmit_queue(cx.waker());
+        }
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+/// Frames a device could not take right away, because all its transmit descriptors were in use
+///
+/// Frames are handed to the device in the order they were sent: a new frame only
+/// skips the queue if the queue is empty. Only used by tasks, never by interrupt handlers.
+pub struct TransmitQueue {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    waker: AtomicWaker,
+    // Tasks waiting for room in the queue
+    senders: Mutex<Vec<Waker>>,
+}
+
+impl TransmitQueue {
+    pub fn new() -> Self {
+        TransmitQueue {
+            frames: Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+            senders: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Hands the frame to the device with `try_send`, or queues it if the device is busy
+    ///
+    /// `try_send` returns false if the device has no free transmit descriptor.
+    pub fn send(
+        &self,
+        frame: &[u8],
+        try_send: impl Fn(&[u8], Option<&Waker>) -> Result<bool, &'static str>,
+    ) -> Result<(), &'static str> {
+        if self.push(frame, try_send, None)? {
+            Ok(())
+        } else {
+            Err("transmit queue full")
+        }
+    }
+
+    /// Like `send`, but pending while the queue is full, until `flush` makes room
+    pub fn poll_send(
+        &self,
+        frame: &[u8],
+        cx: &mut Context,
+        try_send: impl Fn(&[u8], Option<&Waker>) -> Result<bool, &'static str>,
+    ) -> Poll<Result<(), &'static str>> {
+        match self.push(frame, try_send, Some(cx.waker())) {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    /// Sends or queues the frame, returns false if the queue is full,
+    /// after registering the waker to be woken once there is room
+    fn push(
+        &self,
+        frame: &[u8],
+        try_send: impl Fn(&[u8], Option<&Waker>) -> Result<bool, &'static str>,
+        waker: Option<&Waker>,
+    ) -> Result<bool, &'static str> {
+        let mut frames = self.frames.lock();
+        if frames.is_empty() && try_send(frame, None)? {
+            return Ok(true);
+        }
+        if frames.len() >= MAX_QUEUED_TRANSMIT_FRAMES {
+            if let Some(waker) = waker {
+                let mut senders = self.senders.lock();
+                if !senders.iter().any(|sender| sender.will_wake(waker)) {
+                    senders.push(waker.clone());
+                }
+            }
+            return Ok(false);
+        }
+        frames.push_back(frame.to_vec());
+        self.waker.wake();
+        Ok(true)
+    }
+
+    /// Hands queued frames to the device with `try_send` until it is busy,
+    /// in which case `try_send` registers the waker with the device
+    ///
+    /// Frames the device rejects are dropped and passed to `on_error`.
+    pub fn flush(
+        &self,
+        waker: &Waker,
+        try_send: impl Fn(&[u8], Option<&Waker>) -> Result<bool, &'static str>,
+        on_error: impl Fn(&'static str),
+    ) {
+        let mut frames = self.frames.lock();
+        let queued = frames.len();
+        while let Some(frame) = frames.front() {
+            match try_send(frame, Some(waker)) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => on_error(err),
+            }
+            frames.pop_front();
+        }
+        if frames.len() < queued {
+            for sender in self.senders.lock().drain(..) {
+                sender.wake();
+            }
+        }
+        if frames.is_empty() {
+            // registered with the lock held, so `send` cannot queue a frame unnoticed
+            self.waker.register(waker);
+        }
+    }
+}
+
+impl Default for TransmitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Queue of received frames between the interrupt handler of a device and its FrameStream
+///
+/// Frames are copied into preallocated buffers, so pushing never allocates
+/// and is safe to do from an interrupt handler.
+pub struct FrameQueue {
+    frames: ArrayQueue<Vec<u8>>,
+    free_buffers: ArrayQueue<Vec<u8>>,
+    /// Capacity of every buffer, the largest frame that is queued
+    buffer_size: usize,
+    waker: AtomicWaker,
+    stream_taken: AtomicBool,
+    queued_frames: AtomicU64,
+    queued_bytes: AtomicU64,
+    dropped_frames: AtomicU64,
+}
+
+impl FrameQueue {
+    /// Creates a queue of `capacity` frames of up to `buffer_size` bytes each
+    pub fn new(capacity: usize, buffer_size: usize) -> Self {
+        let free_buffers = ArrayQueue::new(capacity);
+        for _ in 0..capacity {
+            let _ = free_buffers.push(Vec::with_capacity(buffer_size));
+        }
+        FrameQueue {
+            frames: ArrayQueue::new(capacity),
+            free_buffers,
+            buffer_size,
+            waker: AtomicWaker::new(),
+            stream_taken: AtomicBool::new(false),
+            queued_frames: AtomicU64::new(0),
+            queued_bytes: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+        }
+    }
+
+    /// Copies a received frame into a free buffer and queues it
+    ///
+    /// Returns false if the frame was dropped because it is too large
+    /// or no buffer was free.
+    pub fn push(&self, frame: &[u8]) -> bool {
+        self.push_wrapped(frame, &[])
+    }
+
+    /// Queues a frame that wrapped around the end of a ring buffer,
+    /// consisting of `first` followed by `second`
+    pub fn push_wrapped(&self, first: &[u8], second: &[u8]) -> bool {
+        let length = first.len() + second.len();
+        if length > self.buffer_size {
+            self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        match self.free_buffers.pop() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                buffer.extend_from_slice(first);
+                buffer.extend_from_slice(second);
+                match self.frames.push(buffer) {
+                    Ok(()) => {
+                        self.queued_frames.fetch_add(1, Ordering::Relaxed);
+                        self.queued_bytes.fetch_add(length as u64, Ordering::Relaxed);
+                        self.waker.wake();
+                        true
+                    }
+                    Err(crossbeam_queue::PushError(buffer)) => {
+                        let _ = self.free_buffers.push(buffer);
+                        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                        false
+                    }
+                }
+            }
+            Err(crossbeam_queue::PopError) => {
+                self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Returns the stream of queued frames
+    ///
+    /// Panics if called more than once.
+    pub fn stream(&'static self) -> FrameStream {
+        if self.stream_taken.swap(true, Ordering::Relaxed) {
+            panic!("FrameQueue::stream should only be called once");
+        }
+        FrameStream { queue: self }
+    }
+
+    /// Fills in the receive counters of the given statistics
+    pub fn add_stats(&self, stats: &mut NetDeviceStats) {
+        stats.rx_frames += self.queued_frames.load(Ordering::Relaxed);
+        stats.rx_bytes += self.queued_bytes.load(Ordering::Relaxed);
+        stats.rx_dropped += self.dropped_frames.load(Ordering::Relaxed);
+    }
+
+    /// Takes the next frame and replaces its buffer,
+    /// so the interrupt handler has as many free buffers as before
+    fn pop(&self) -> Option<Vec<u8>> {
+        let frame = self.frames.pop().ok()?;
+        let _ = self.free_buffers.push(Vec::with_capacity(self.buffer_size));
+        Some(frame)
+    }
+}
+
+/// Stream of the frames received by a device, without their CRC
+pub struct FrameStream {
+    queue: &'static FrameQueue,
+}
+
+impl Stream for FrameStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        // fast path
+        if let Some(frame) = self.queue.pop() {
+            return Poll::Ready(Some(frame));
+        }
+
+        self.queue.waker.register(&cx.waker());
+        match self.queue.pop() {
+            Some(frame) => {
+                self.queue.waker.take();
+                Poll::Ready(Some(frame))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_transmit_queue_keeps_order() {
+    use core::sync::atomic::AtomicUsize;
+    use futures_util::task::noop_waker_ref;
+
+    // a device with room for two frames at a time
+    let free = AtomicUsize::new(2);
+    let sent = Mutex::new(Vec::new());
+    let try_send = |frame: &[u8], _: Option<&Waker>| -> Result<bool, &'static str> {
+        if free.load(Ordering::Relaxed) == 0 {
+            return Ok(false);
+        }
+        free.fetch_sub(1, Ordering::Relaxed);
+        sent.lock().push(frame[0]);
+        Ok(true)
+    };
+
+    let queue = TransmitQueue::new();
+    for i in 0..4 {
+        queue.send(&[i], try_send).unwrap();
+    }
+    assert_eq!(*sent.lock(), [0, 1]);
+
+    // once the device has room again, queued frames go before new ones
+    free.store(1, Ordering::Relaxed);
+    queue.send(&[4], try_send).unwrap();
+    assert_eq!(*sent.lock(), [0, 1]);
+    free.store(8, Ordering::Relaxed);
+    queue.flush(noop_waker_ref(), try_send, |_| panic!("frame rejected"));
+    assert_eq!(*sent.lock(), [0, 1, 2, 3, 4]);
+}
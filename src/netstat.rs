@@ -0,0 +1,138 @@
+use crate::{
+    netdev::{self, InterfaceId, NetDeviceStats},
+    println, serial_println,
+};
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Packet counters of a protocol, updated by its module
+#[derive(Debug)]
+pub struct ProtocolCounters {
+    received: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl ProtocolCounters {
+    pub const fn new() -> Self {
+        ProtocolCounters {
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a packet that was received and passed the validation of the protocol
+    pub fn count_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a packet that was handed to the layer below
+    pub fn count_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a received packet that was invalid or had no receiver
+    pub fn count_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ProtocolStats {
+        ProtocolStats {
+            received: self.received.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Packet counters of a protocol at one point in time
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolStats {
+    pub received: u64,
+    pub sent: u64,
+    pub dropped: u64,
+}
+
+pub static ARP: ProtocolCounters = ProtocolCounters::new();
+pub static IPV4: ProtocolCounters = ProtocolCounters::new();
+pub static ICMP: ProtocolCounters = ProtocolCounters::new();
+pub static UDP: ProtocolCounters = ProtocolCounters::new();
+pub static TCP: ProtocolCounters = ProtocolCounters::new();
+
+/// Statistics of all interfaces and protocols at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkStats {
+    /// Id, name and counters of every registered interface
+    pub interfaces: Vec<(InterfaceId, String, NetDeviceStats)>,
+    /// Name and counters of every protocol
+    pub protocols: Vec<(&'static str, ProtocolStats)>,
+}
+
+/// Takes a snapshot of the counters of all interfaces and protocols
+pub fn snapshot() -> NetworkStats {
+    NetworkStats {
+        interfaces: netdev::interfaces()
+            .into_iter()
+            .map(|(id, device)| (id, String::from(device.name()), device.stats()))
+            .collect(),
+        protocols: [("ARP", &ARP), ("IPv4", &IPV4), ("ICMP", &ICMP), ("UDP", &UDP), ("TCP", &TCP)]
+            .iter()
+            .map(|(name, counters)| (*name, counters.snapshot()))
+            .collect(),
+    }
+}
+
+/// Prints the current statistics to the VGA buffer
+pub fn print() {
+    println!("{}", snapshot());
+}
+
+/// Prints the current statistics to the serial port
+pub fn serial_print() {
+    serial_println!("{}", snapshot());
+}
+
+/// Formats the statistics as tables of interfaces and protocols
+impl fmt::Display for NetworkStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<4} {:<10} {:>8} {:>10} {:>7} {:>7} {:>8} {:>10} {:>7}",
+            "If", "Name", "RX-OK", "RX-Bytes", "RX-Drp", "RX-Err", "TX-OK", "TX-Bytes", "TX-Err"
+        )?;
+        for (id, name, stats) in &self.interfaces {
+            writeln!(
+                f,
+                "{:<4} {:<10} {:>8} {:>10} {:>7} {:>7} {:>8} {:>10} {:>7}",
+                id, name, stats.rx_frames, stats.rx_bytes, stats.rx_dropped, stats.rx_errors,
+                stats.tx_frames, stats.tx_bytes, stats.tx_errors
+            )?;
+            writeln!(
+                f,
+                "     crc errors {}, overflows {}, tx aborts {}, carrier lost {}",
+                stats.rx_crc_errors, stats.rx_overflows, stats.tx_aborts, stats.tx_carrier_lost
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<10} {:>10} {:>10} {:>10}", "Protocol", "Received", "Sent", "Dropped")?;
+        for (name, stats) in &self.protocols {
+            writeln!(f, "{:<10} {:>10} {:>10} {:>10}", name, stats.received, stats.sent, stats.dropped)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_protocol_counters() {
+    let counters = ProtocolCounters::new();
+    counters.count_received();
+    counters.count_received();
+    counters.count_sent();
+    counters.count_dropped();
+    assert_eq!(counters.snapshot(), ProtocolStats { received: 2, sent: 1, dropped: 1 });
+}
@@ -0,0 +1,185 @@
+use crate::{ethernet::EthernetFrameRef, time};
+use alloc::vec::Vec;
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Poll,
+};
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+// Second serial port, the capture is streamed to
+const COM2: u16 = 0x2F8;
+const LINE_STATUS: u16 = 5;
+/// Set in the line status register once the transmit FIFO is empty
+const TRANSMITTER_EMPTY: u8 = 0x20;
+/// Bytes the transmit FIFO of a 16550 holds
+const FIFO_SIZE: usize = 16;
+/// Ticks between checks whether the transmit FIFO is empty
+const FIFO_POLL_INTERVAL: u64 = 1;
+
+// libpcap file format
+const MAGIC: u32 = 0xa1b2c3d4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Largest number of bytes captured per frame
+pub const SNAPLEN: u32 = 65535;
+pub const GLOBAL_HEADER_SIZE: usize = 24;
+pub const RECORD_HEADER_SIZE: usize = 16;
+
+/// Number of records that can wait for the serial port before frames are dropped
+const MAX_QUEUED_RECORDS: usize = 64;
+
+struct Capture {
+    capturing: bool,
+    /// Only frames with this Ethertype are captured
+    filter: Option<u16>,
+    /// Whether the global header was queued, it is only written once per boot
+    header_queued: bool,
+    /// Headers and records waiting to be written to the serial port
+    records: Vec<Vec<u8>>,
+}
+
+static CAPTURE: Mutex<Capture> = Mutex::new(Capture {
+    capturing: false,
+    filter: None,
+    header_queued: false,
+    records: Vec::new(),
+});
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Starts capturing the frames sent and received by `ethernet`,
+/// only those with the given Ethertype if a filter is given
+///
+/// Calling it while capturing replaces the filter.
+pub fn start(filter: Option<u16>) {
+    let mut capture = CAPTURE.lock();
+    if !capture.header_queued {
+        capture.records.push(global_header().to_vec());
+        capture.header_queued = true;
+        WAKER.wake();
+    }
+    capture.capturing = true;
+    capture.filter = filter;
+}
+
+/// Stops capturing, records that were already queued are still written
+pub fn stop() {
+    CAPTURE.lock().capturing = false;
+}
+
+pub fn is_capturing() -> bool {
+    CAPTURE.lock().capturing
+}
+
+/// Returns the number of frames that were dropped because the serial port was too slow
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Queues a record of the frame if capturing and the frame passes the filter
+///
+/// Called by `ethernet` for every frame it sends or receives.
+pub(crate) fn capture(frame: &[u8]) {
+    let mut capture = CAPTURE.lock();
+    if !capture.capturing {
+        return;
+    }
+    if let Some(ethertype) = capture.filter {
+        // tagged frames are filtered by the Ethertype following the VLAN tag
+        match EthernetFrameRef::parse(frame) {
+            Ok(parsed) if parsed.header.protocol == ethertype => {}
+            _ => return,
+        }
+    }
+    if capture.records.len() >= MAX_QUEUED_RECORDS {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let captured = &frame[..min(frame.len(), SNAPLEN as usize)];
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + captured.len());
+    record.extend_from_slice(&record_header(time::ticks(), captured.len() as u32, frame.len() as u32));
+    record.extend_from_slice(captured);
+    capture.records.push(record);
+    WAKER.wake();
+}
+
+/// Returns the libpcap global header for Ethernet frames
+pub fn global_header() -> [u8; GLOBAL_HEADER_SIZE] {
+    let mut header = [0; GLOBAL_HEADER_SIZE];
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&VERSION_MAJOR.to_le_bytes());
+    header[6..8].copy_from_slice(&VERSION_MINOR.to_le_bytes());
+    // thiszone and sigfigs stay zero
+    header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// Returns the libpcap record header of a frame captured at the given tick count
+pub fn record_header(ticks: u64, captured_len: u32, original_len: u32) -> [u8; RECORD_HEADER_SIZE] {
+    let seconds = (ticks / time::TICKS_PER_SECOND) as u32;
+    let micros = (time::millis_from_ticks(ticks % time::TICKS_PER_SECOND) * 1000) as u32;
+    let mut header = [0; RECORD_HEADER_SIZE];
+    header[0..4].copy_from_slice(&seconds.to_le_bytes());
+    header[4..8].copy_from_slice(&micros.to_le_bytes());
+    header[8..12].copy_from_slice(&captured_len.to_le_bytes());
+    header[12..16].copy_from_slice(&original_len.to_le_bytes());
+    header
+}
+
+/// Writes queued records to COM2
+///
+/// Timestamps count from boot. With `-serial file:capture.pcap` as the second
+/// serial port, QEMU writes a file Wireshark can open, with `-serial pipe:...`
+/// the capture can be followed live.
+pub async fn write_records() {
+    // sets up baud rate, framing and FIFOs
+    unsafe { SerialPort::new(COM2) }.init();
+    // uart_16550 rewrites backspace bytes, so the binary stream bypasses it
+    let mut data = Port::<u8>::new(COM2);
+    let mut line_status = PortReadOnly::<u8>::new(COM2 + LINE_STATUS);
+
+    loop {
+        let records = poll_fn(|cx| {
+            let mut capture = CAPTURE.lock();
+            if capture.records.is_empty() {
+                WAKER.register(cx.waker());
+                return Poll::Pending;
+            }
+            Poll::Ready(core::mem::take(&mut capture.records))
+        })
+        .await;
+
+        for record in records {
+            for chunk in record.chunks(FIFO_SIZE) {
+                // the FIFO takes about 1.4 ms to drain at 115200 baud, so checking
+                // every tick lets other tasks run and the CPU halt in the meantime
+                while unsafe { line_status.read() } & TRANSMITTER_EMPTY == 0 {
+                    time::sleep(FIFO_POLL_INTERVAL).await;
+                }
+                for &byte in chunk {
+                    unsafe { data.write(byte) };
+                }
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_headers() {
+    let header = global_header();
+    assert_eq!(header[0..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(header[20..24], [1, 0, 0, 0]);
+
+    let record = record_header(2 * time::TICKS_PER_SECOND + 5, 60, 1514);
+    assert_eq!(record[0..4], 2u32.to_le_bytes());
+    assert_eq!(record[4..8], 5000u32.to_le_bytes());
+    assert_eq!(record[8..12], 60u32.to_le_bytes());
+    assert_eq!(record[12..16], 1514u32.to_le_bytes());
+}
@@ -2,14 +2,14 @@
 
 extern crate alloc;
 
-use crate::println;
+use crate::{acpi, apic, interrupts, memory::MmioRegion, println};
 use core::{
     fmt,
     ops::{Deref, DerefMut}
 };
 use alloc::vec::Vec;
 use spin::{Once, Mutex};
-use x86_64::instructions::port::Port;
+use x86_64::{instructions::port::Port, PhysAddr};
 use lazy_static::lazy_static;
 
 // The below constants define the PCI configuration space. 
@@ -56,16 +56,236 @@ pub const SERR_ENABLE: u16 = 0x0100;
 pub const FAST_BACK_TO_BACK: u16 = 0x0200;
 pub const INTERRUPT_DISABLE: u16 = 0x0400;
 
+// Status
+pub const CAPABILITIES_LIST: u16 = 0x0010;
+
 #[repr(u8)]
 pub enum PciCapability {
     Msi  = 0x05,
     Msix = 0x11,
 }
 
+/// Capability pointers are dword aligned, the low two bits are reserved.
+const CAPABILITY_POINTER_MASK: u8 = 0xFC;
+/// The capability list lies in the 192 bytes behind the header, so a longer chain has to be a loop.
+const MAX_CAPABILITIES: usize = 48;
+
+// Message Signaled Interrupts, the message is a write to the local APIC of the destination CPU
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MSI_DESTINATION_SHIFT: u32 = 12;
+
+// MSI Message Control
+const MSI_ENABLE: u16 = 0x0001;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0x0070;
+const MSI_64_BIT_CAPABLE: u16 = 0x0080;
+
+// MSI-X Message Control
+const MSIX_TABLE_SIZE_MASK: u16 = 0x07FF;
+const MSIX_FUNCTION_MASK: u16 = 0x4000;
+const MSIX_ENABLE: u16 = 0x8000;
+/// The low three bits of the table offset select the BAR the table lies in.
+const MSIX_BIR_MASK: u32 = 0x7;
+const MSIX_ENTRY_SIZE: usize = 16;
+// MSI-X table entry
+const MSIX_ADDRESS_LOW: usize = 0x0;
+const MSIX_ADDRESS_HIGH: usize = 0x4;
+const MSIX_DATA: usize = 0x8;
+const MSIX_VECTOR_CONTROL: usize = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 0x1;
+
+/// An entry in the capability list of a PCI device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// The capability ID, e.g. `PciCapability::Msi as u8`.
+    pub id: u8,
+    /// The offset of the capability in the configuration space.
+    pub offset: u8,
+}
+
+/// Iterates over the capability list of a PCI device, see [`PciDevice::capabilities`].
+pub struct CapabilityIter {
+    location: PciLocation,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for CapabilityIter {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // offsets below 0x40 would point into the header and end the list as well
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next;
+        self.next = self.location.pci_read_8(offset + 1) & CAPABILITY_POINTER_MASK;
+        Some(Capability { id: self.location.pci_read_8(offset), offset })
+    }
+}
+
+/// The extended capability list starts right behind the configuration space of conventional PCI.
+const EXTENDED_CAPABILITIES: u16 = 0x100;
+/// Each extended capability takes at least a dword.
+const MAX_EXTENDED_CAPABILITIES: usize = ((PCIE_CONFIG_SPACE_SIZE - EXTENDED_CAPABILITIES) / 4) as usize;
+const EXTENDED_CAPABILITY_POINTER_MASK: u16 = 0xFFC;
+
+/// An entry in the extended capability list of a PCIe device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    /// The extended capability ID, e.g. 0x0001 for Advanced Error Reporting.
+    pub id: u16,
+    pub version: u8,
+    /// The offset of the capability in the extended configuration space.
+    pub offset: u16,
+}
+
+/// Iterates over the extended capability list of a PCIe device, see [`PciDevice::extended_capabilities`].
+pub struct ExtendedCapabilityIter {
+    location: PciLocation,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilityIter {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<ExtendedCapability> {
+        if self.next < EXTENDED_CAPABILITIES || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next;
+        let header = self.location.config_read_32(offset);
+        // conventional PCI functions read as all zeros or all ones here
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+        self.next = (header >> 20) as u16 & EXTENDED_CAPABILITY_POINTER_MASK;
+        Some(ExtendedCapability { id: header as u16, version: ((header >> 16) & 0xF) as u8, offset })
+    }
+}
+
+/// The MSI-X table of a device, mapped from the BAR it lies in.
+///
+/// All entries start out masked, an entry only delivers interrupts once it is set.
+#[derive(Debug)]
+pub struct MsixTable {
+    table: MmioRegion,
+    entries: u16,
+}
+
+impl MsixTable {
+    /// Returns the number of entries in the table.
+    pub fn entries(&self) -> u16 {
+        self.entries
+    }
+
+    /// Routes the interrupts of the given entry to `vector` on the CPU with the local APIC ID `destination`
+    /// and unmasks the entry.
+    pub fn set_entry(&self, index: u16, vector: u8, destination: u8) -> Result<(), &'static str> {
+        check_msi_vector(vector)?;
+        let entry = self.entry(index)?;
+        self.table.write_32(entry + MSIX_ADDRESS_LOW, msi_address(destination));
+        self.table.write_32(entry + MSIX_ADDRESS_HIGH, 0);
+        self.table.write_32(entry + MSIX_DATA, vector as u32);
+        self.table.write_32(entry + MSIX_VECTOR_CONTROL, 0);
+        Ok(())
+    }
+
+    /// Stops the given entry from delivering interrupts, the device keeps them pending.
+    pub fn mask(&self, index: u16) -> Result<(), &'static str> {
+        let entry = self.entry(index)?;
+        self.table.write_32(entry + MSIX_VECTOR_CONTROL, MSIX_ENTRY_MASKED);
+        Ok(())
+    }
+
+    fn entry(&self, index: u16) -> Result<usize, &'static str> {
+        if index >= self.entries {
+            return Err("MSI-X table entry out of range");
+        }
+        Ok(index as usize * MSIX_ENTRY_SIZE)
+    }
+}
+
+/// Returns the address of an MSI message to the local APIC with the given ID.
+fn msi_address(destination: u8) -> u32 {
+    MSI_ADDRESS_BASE | ((destination as u32) << MSI_DESTINATION_SHIFT)
+}
+
+/// Vectors below the ones of the PICs are CPU exceptions or taken by the PICs,
+/// the last one takes the spurious interrupts of the local APIC.
+fn check_msi_vector(vector: u8) -> Result<(), &'static str> {
+    if vector < interrupts::MSI_VECTOR_OFFSET {
+        return Err("MSI vector collides with CPU exceptions or PIC interrupts");
+    }
+    if vector == apic::SPURIOUS_VECTOR {
+        return Err("MSI vector is the spurious vector of the local APIC");
+    }
+    Ok(())
+}
+
 
 /// If a BAR's bits [2:1] equal this value, that BAR describes a 64-bit address.
 /// If not, that BAR describes a 32-bit address.
 const BAR_ADDRESS_IS_64_BIT: u32 = 2;
+/// If a BAR's bit 0 is set, that BAR describes an I/O port range instead of memory.
+const BAR_IS_IO: u32 = 0x1;
+/// If a memory BAR's bit 3 is set, reads from that memory have no side effects.
+const BAR_PREFETCHABLE: u32 = 0x8;
+const BAR_IO_ADDRESS_MASK: u32 = 0xFFFF_FFFC;
+const BAR_MEMORY_ADDRESS_MASK: u32 = 0xFFFF_FFF0;
+
+/// A decoded Base Address Register with the size of the region it describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// A range of I/O ports.
+    Io { port: u16, size: u32 },
+    /// A memory region below 4 GiB.
+    Memory32 { address: u32, size: u32, prefetchable: bool },
+    /// A memory region anywhere in the 64-bit address space, described by two consecutive BARs.
+    Memory64 { address: u64, size: u64, prefetchable: bool },
+}
+
+impl Bar {
+    /// Returns the first port or physical address of the region.
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Io { port, .. } => port as u64,
+            Bar::Memory32 { address, .. } => address as u64,
+            Bar::Memory64 { address, .. } => address,
+        }
+    }
+
+    /// Returns the size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Io { size, .. } => size as u64,
+            Bar::Memory32 { size, .. } => size as u64,
+            Bar::Memory64 { size, .. } => size,
+        }
+    }
+
+    pub fn is_memory(&self) -> bool {
+        !matches!(self, Bar::Io { .. })
+    }
+
+    /// Maps the memory region of a memory BAR uncached, for accessing the registers of the device.
+    pub fn map(&self) -> Result<MmioRegion, &'static str> {
+        if !self.is_memory() {
+            return Err("BAR describes I/O ports, not memory");
+        }
+        let size = usize::try_from(self.size()).map_err(|_| "BAR too large to map")?;
+        MmioRegion::map(PhysAddr::new(self.address()), size)
+    }
+
+    pub fn is_prefetchable(&self) -> bool {
+        match *self {
+            Bar::Io { .. } => false,
+            Bar::Memory32 { prefetchable, .. } | Bar::Memory64 { prefetchable, .. } => prefetchable,
+        }
+    }
+}
 
 /// There is a maximum of 256 PCI buses on one system.
 const MAX_PCI_BUSES: u16 = 256;
@@ -79,6 +299,79 @@ const PCI_CONFIG_ADDRESS_OFFSET_MASK: u8 = 0xFC;
 const CONFIG_ADDRESS: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
 
+/// Size of the configuration space of a function with port I/O or conventional PCI.
+pub const PCI_CONFIG_SPACE_SIZE: u16 = 256;
+/// Size of the configuration space of a function with ECAM, the extended capabilities start behind the first 256 bytes.
+pub const PCIE_CONFIG_SPACE_SIZE: u16 = 4096;
+
+// MCFG table, describing where the ECAM regions lie
+const MCFG_ENTRIES_OFFSET: usize = acpi::SDT_HEADER_SIZE + 8;
+const MCFG_ENTRY_SIZE: usize = 16;
+/// Each bus takes 1 MiB of an ECAM region, each function 4 KiB.
+const ECAM_BUS_SHIFT: usize = 20;
+const ECAM_SLOT_SHIFT: usize = 15;
+const ECAM_FUNCTION_SHIFT: usize = 12;
+
+/// The Enhanced Configuration Access Mechanism of PCIe: the configuration space
+/// of the buses `start_bus..=end_bus` of segment 0, memory mapped.
+#[derive(Debug)]
+struct Ecam {
+    region: MmioRegion,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl Ecam {
+    /// Returns the offset of the configuration space of the given function in the region,
+    /// if its bus is covered by the region.
+    fn function_offset(&self, location: PciLocation) -> Option<usize> {
+        if location.bus < self.start_bus || location.bus > self.end_bus {
+            return None;
+        }
+        Some(((location.bus - self.start_bus) as usize) << ECAM_BUS_SHIFT
+            | (location.slot as usize) << ECAM_SLOT_SHIFT
+            | (location.func as usize) << ECAM_FUNCTION_SHIFT)
+    }
+}
+
+static ECAM: Once<Option<Ecam>> = Once::new();
+
+/// Returns the ECAM region, if the firmware describes one in its MCFG table, like QEMU's q35 machine does.
+/// Otherwise the configuration space is accessed through port I/O.
+fn ecam() -> Option<&'static Ecam> {
+    ECAM.call_once(find_ecam).as_ref()
+}
+
+/// Maps the ECAM region of segment 0 described by the MCFG table.
+fn find_ecam() -> Option<Ecam> {
+    let mcfg = acpi::find_table(b"MCFG")?;
+    let entry = mcfg
+        .get(MCFG_ENTRIES_OFFSET..)?
+        .chunks_exact(MCFG_ENTRY_SIZE)
+        .find(|entry| u16::from_le_bytes([entry[8], entry[9]]) == 0)?;
+    let mut base = [0; 8];
+    base.copy_from_slice(&entry[0..8]);
+    let base = u64::from_le_bytes(base);
+    let (start_bus, end_bus) = (entry[10], entry[11]);
+    if end_bus < start_bus {
+        return None;
+    }
+
+    // the region starts at the address bus 0 would have
+    let address = base + ((start_bus as u64) << ECAM_BUS_SHIFT);
+    let size = ((end_bus - start_bus) as usize + 1) << ECAM_BUS_SHIFT;
+    match MmioRegion::map(PhysAddr::new(address), size) {
+        Ok(region) => {
+            println!("PCI: using ECAM at {:#x} for buses {} to {}", address, start_bus, end_bus);
+            Some(Ecam { region, start_bus, end_bus })
+        }
+        Err(err) => {
+            println!("PCI: falling back to port I/O, mapping ECAM failed: {}", err);
+            None
+        }
+    }
+}
+
 /// This port is used to specify the address in the PCI configuration space
 /// for the next read/write of the `PCI_CONFIG_DATA_PORT`.
 static PCI_CONFIG_ADDRESS_PORT: Mutex<Port<u32>> = Mutex::new(Port::new(CONFIG_ADDRESS));
@@ -226,34 +519,127 @@ impl PciLocation {
         0x8000_0000
     }
 
-    /// read 32-bit data at the specified `offset` from the PCI device specified by the given `bus`, `slot`, `func` set.
-    fn pci_read_32(&self, offset: u8) -> u32 {
-        unsafe { 
-            PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(offset)); 
+    /// Returns the size of the configuration space of this function:
+    /// `PCIE_CONFIG_SPACE_SIZE` if it is reached through ECAM, `PCI_CONFIG_SPACE_SIZE` otherwise.
+    pub fn config_space_size(&self) -> u16 {
+        if self.ecam_offset().is_some() { PCIE_CONFIG_SPACE_SIZE } else { PCI_CONFIG_SPACE_SIZE }
+    }
+
+    /// Read the 32-bit register at the dword aligned `offset`, which may lie in the extended configuration space.
+    pub fn pci_read_extended_32(&self, offset: u16) -> Result<u32, &'static str> {
+        self.check_extended_offset(offset)?;
+        Ok(self.config_read_32(offset))
+    }
+
+    /// Write the 32-bit register at the dword aligned `offset`, which may lie in the extended configuration space.
+    pub fn pci_write_extended_32(&self, offset: u16, value: u32) -> Result<(), &'static str> {
+        self.check_extended_offset(offset)?;
+        self.config_write_32(offset, value);
+        Ok(())
+    }
+
+    fn check_extended_offset(&self, offset: u16) -> Result<(), &'static str> {
+        if offset & 0x3 != 0 {
+            return Err("configuration space offset must be dword aligned");
+        }
+        if offset >= self.config_space_size() {
+            return Err("offset beyond the configuration space of the device");
+        }
+        Ok(())
+    }
+
+    /// Returns the offset of this function's configuration space in the ECAM region, if ECAM covers its bus.
+    fn ecam_offset(&self) -> Option<(&'static Ecam, usize)> {
+        let ecam = ecam()?;
+        Some((ecam, ecam.function_offset(*self)?))
+    }
+
+    /// Read the dword containing `offset`, through ECAM if possible and through port I/O otherwise.
+    fn config_read_32(&self, offset: u16) -> u32 {
+        let offset = offset & !0x3;
+        match self.ecam_offset() {
+            Some((ecam, function)) => ecam.region.read_32(function + offset as usize),
+            None => {
+                unsafe {
+                    PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(offset as u8));
+                }
+                Self::read_data_port()
+            }
+        }
+    }
+
+    /// Write the dword containing `offset`, through ECAM if possible and through port I/O otherwise.
+    fn config_write_32(&self, offset: u16, value: u32) {
+        let offset = offset & !0x3;
+        match self.ecam_offset() {
+            Some((ecam, function)) => ecam.region.write_32(function + offset as usize, value),
+            None => {
+                unsafe {
+                    PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(offset as u8));
+                }
+                Self::write_data_port(value);
+            }
         }
-        let val: u32 = Self::read_data_port();
-        let shift = (offset & (!PCI_CONFIG_ADDRESS_OFFSET_MASK)) * 8;
-        val >> shift
+    }
+
+    /// Read 32-bit data at the specified `offset` from this PCI device.
+    /// The `offset` must be 4-byte aligned.
+    pub fn pci_read_32(&self, offset: u8) -> u32 {
+        assert!(offset & 0x3 == 0, "unaligned 32-bit PCI config read at {:#x}", offset);
+        self.config_read_32(offset as u16)
     }
 
     /// Read 16-bit data at the specified `offset` from this PCI device.
-    fn pci_read_16(&self, offset: u8) -> u16 {
-        self.pci_read_32(offset) as u16
-    } 
+    /// The `offset` must be 2-byte aligned.
+    pub fn pci_read_16(&self, offset: u8) -> u16 {
+        assert!(offset & 0x1 == 0, "unaligned 16-bit PCI config read at {:#x}", offset);
+        (self.config_read_32(offset as u16) >> Self::shift(offset)) as u16
+    }
 
-    /// Read 8-bit data at the specified `offset` from the PCI device.
-    fn pci_read_8(&self, offset: u8) -> u8 {
-        self.pci_read_32(offset) as u8
+    /// Read 8-bit data at the specified `offset` from this PCI device.
+    pub fn pci_read_8(&self, offset: u8) -> u8 {
+        (self.config_read_32(offset as u16) >> Self::shift(offset)) as u8
     }
 
-    /// Write 32-bit data to the specified `offset` for the PCI device.
-    pub fn pci_write(&self, offset: u8, value: u32) {
-        unsafe {
-            PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(offset));
+    /// Write 32-bit data to the specified `offset` for this PCI device.
+    /// The `offset` must be 4-byte aligned.
+    pub fn pci_write_32(&self, offset: u8, value: u32) {
+        assert!(offset & 0x3 == 0, "unaligned 32-bit PCI config write at {:#x}", offset);
+        self.config_write_32(offset as u16, value);
+    }
+
+    /// Write 16-bit data to the specified `offset` for this PCI device,
+    /// leaving the other half of the dword containing it unchanged.
+    /// The `offset` must be 2-byte aligned.
+    pub fn pci_write_16(&self, offset: u8, value: u16) {
+        assert!(offset & 0x1 == 0, "unaligned 16-bit PCI config write at {:#x}", offset);
+        self.read_modify_write(offset, 0xFFFF, value as u32);
+    }
+
+    /// Write 8-bit data to the specified `offset` for this PCI device,
+    /// leaving the other bytes of the dword containing it unchanged.
+    pub fn pci_write_8(&self, offset: u8, value: u8) {
+        self.read_modify_write(offset, 0xFF, value as u32);
+    }
+
+    /// The configuration space can only be written in dwords, so the bytes of the dword around
+    /// the written ones are read and written back.
+    ///
+    /// Bits of the status register are cleared by writing ones, writing it back would clear
+    /// pending bits. So the status register is written as zero unless it is the target itself.
+    fn read_modify_write(&self, offset: u8, mask: u32, value: u32) {
+        let shift = Self::shift(offset);
+        let mut dword = self.config_read_32(offset as u16);
+        if offset & PCI_CONFIG_ADDRESS_OFFSET_MASK == PCI_COMMAND {
+            dword &= 0x0000_FFFF;
         }
-        let shift = (offset & 2) * 8;
-        let data = value << shift;
-        Self::write_data_port(data);
+        dword = (dword & !(mask << shift)) | ((value & mask) << shift);
+        self.config_write_32(offset as u16, dword);
+    }
+
+    /// Returns the shift of the register at `offset` within the dword containing it.
+    fn shift(offset: u8) -> u32 {
+        ((offset & !PCI_CONFIG_ADDRESS_OFFSET_MASK) as u32) * 8
     }
 
     /// Write 32-bit data to the PCI Config Data Register of the PCI device.
@@ -273,23 +659,38 @@ impl PciLocation {
     /// Write 16-bit data to the PCI Command Register of the PCI device.
     /// Prints states of the Register before and after writing to it.
     pub fn pci_set_command_register_bit(&self, data: u16) {
-        unsafe { 
-            PCI_CONFIG_ADDRESS_PORT.lock().write(self.pci_address(PCI_COMMAND));
-        }
-        let command = Self::read_data_port(); 
+        let command = self.pci_read_16(PCI_COMMAND);
         println!("pci_set_command_register_bit: PciDevice: {}, read value: {:#x}, data: {:#x}", 
                     self, command, data
                 );
-        if command & data as u32 == 0 {
-            Self::write_data_port(command | data as u32);
+        if command & data == 0 {
+            self.pci_write_16(PCI_COMMAND, command | data);
             println!("pci_set_command_register_bit: read value AFTER WRITE CMD: {:#x}", 
-                        Self::read_data_port()
+                        self.pci_read_16(PCI_COMMAND)
             );
         }
         else {
             println!("Bit already set!")
         }
     }
+
+    /// Clears the given bits of the PCI Command Register of the PCI device,
+    /// the counterpart of `pci_set_command_register_bit`.
+    pub fn pci_clear_command_register_bit(&self, data: u16) {
+        let command = self.pci_read_16(PCI_COMMAND);
+        if command & data != 0 {
+            self.pci_write_16(PCI_COMMAND, command & !data);
+        }
+    }
+
+    /// Sets the given bits of the PCI Command Register of the PCI device
+    /// like `pci_set_command_register_bit`, without printing its states.
+    fn set_command_bits(&self, data: u16) {
+        let command = self.pci_read_16(PCI_COMMAND);
+        if command & data != data {
+            self.pci_write_16(PCI_COMMAND, command | data);
+        }
+    }
 }
 
 impl fmt::Display for PciLocation {
@@ -337,23 +738,183 @@ pub struct PciDevice {
 }
 
 impl PciDevice {
-    /// Returns the base io address of the memory region specified by the given `BAR` 
-    /// (Base Address Register) for this PCI device. 
+    /// Returns the base io address of the I/O port range specified by the given `BAR`
+    /// (Base Address Register) for this PCI device.
     ///
     /// # Argument
-    /// * `bar_index` must be between `0` and `5` inclusively, as each PCI device 
+    /// * `bar_index` must be between `0` and `5` inclusively, as each PCI device
     ///   can only have 6 BARs at the most.
     pub fn determine_iobase(&self, bar_index: usize) -> Result<u32, &'static str> {
-        let bar = if let Some(bar_value) = self.bars.get(bar_index) {
-            *bar_value
-        } else {
-            return Err("BAR index must be between 0 and 5 inclusive");
+        let iobase = match self.bar(bar_index)? {
+            Bar::Io { port, .. } => port as u32,
+            _ => return Err("BAR describes memory, not I/O ports"),
         };
-
-        let iobase = bar & 0xFFFFFFFC;
         println!("iobase of PCI-Device {} for BAR {}: {:#x}", self.location, bar_index, iobase);
         Ok(iobase)
     }
+
+    /// Decodes the given `BAR` (Base Address Register) of this PCI device.
+    ///
+    /// The size is probed by writing all ones to the BAR and reading back which bits stuck,
+    /// with I/O and memory decoding of the device disabled in the meantime.
+    ///
+    /// # Argument
+    /// * `bar_index` must be between `0` and `5` inclusively and must not be
+    ///   the upper half of a 64-bit BAR.
+    pub fn bar(&self, bar_index: usize) -> Result<Bar, &'static str> {
+        if bar_index >= self.bars.len() {
+            return Err("BAR index must be between 0 and 5 inclusive");
+        }
+        // the BARs before tell whether this one is the upper half of a 64-bit BAR
+        let mut index = 0;
+        while index < bar_index {
+            index += if Self::is_64_bit_bar(self.bars[index]) { 2 } else { 1 };
+        }
+        if index != bar_index {
+            return Err("BAR is the upper half of a 64-bit BAR");
+        }
+
+        let value = self.bars[bar_index];
+        let offset = PCI_BAR0 + 4 * bar_index as u8;
+        let is_64_bit = Self::is_64_bit_bar(value);
+        if is_64_bit && bar_index == self.bars.len() - 1 {
+            return Err("64-bit BAR without an upper half");
+        }
+
+        let command = self.pci_read_16(PCI_COMMAND);
+        self.pci_write_16(PCI_COMMAND, command & !(IO_SPACE | MEMORY_SPACE));
+        let mask = self.probe_bar(offset);
+        let high_mask = if is_64_bit { self.probe_bar(offset + 4) } else { 0 };
+        self.pci_write_16(PCI_COMMAND, command);
+
+        if mask == 0 && high_mask == 0 {
+            return Err("BAR is not implemented");
+        }
+        let prefetchable = (value & BAR_PREFETCHABLE) != 0;
+        Ok(if (value & BAR_IS_IO) != 0 {
+            // only the low 16 bits of I/O BARs have to be implemented
+            let mask = (mask & BAR_IO_ADDRESS_MASK) | 0xFFFF_0000;
+            Bar::Io { port: (value & BAR_IO_ADDRESS_MASK) as u16, size: (!mask).wrapping_add(1) }
+        } else if is_64_bit {
+            let mask = ((high_mask as u64) << 32) | (mask & BAR_MEMORY_ADDRESS_MASK) as u64;
+            Bar::Memory64 {
+                address: ((self.bars[bar_index + 1] as u64) << 32) | (value & BAR_MEMORY_ADDRESS_MASK) as u64,
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+            }
+        } else {
+            Bar::Memory32 {
+                address: value & BAR_MEMORY_ADDRESS_MASK,
+                size: (!(mask & BAR_MEMORY_ADDRESS_MASK)).wrapping_add(1),
+                prefetchable,
+            }
+        })
+    }
+
+    fn is_64_bit_bar(value: u32) -> bool {
+        (value & BAR_IS_IO) == 0 && ((value >> 1) & 0b11) == BAR_ADDRESS_IS_64_BIT
+    }
+
+    /// Writes all ones to the BAR at the given offset, returning what was read back,
+    /// and restores the original value.
+    fn probe_bar(&self, offset: u8) -> u32 {
+        let original = self.pci_read_32(offset);
+        self.pci_write_32(offset, 0xFFFF_FFFF);
+        let mask = self.pci_read_32(offset);
+        self.pci_write_32(offset, original);
+        mask
+    }
+
+    /// Returns an iterator over the capability list of this PCI device,
+    /// which is empty if the device has none.
+    pub fn capabilities(&self) -> CapabilityIter {
+        let has_capabilities = (self.pci_read_16(PCI_STATUS) & CAPABILITIES_LIST) != 0;
+        CapabilityIter {
+            location: self.location,
+            next: if has_capabilities { self.pci_read_8(PCI_CAPABILITIES) & CAPABILITY_POINTER_MASK } else { 0 },
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Returns an iterator over the extended capability list of this PCIe device,
+    /// which is empty without ECAM or for conventional PCI devices.
+    pub fn extended_capabilities(&self) -> ExtendedCapabilityIter {
+        let has_extended_space = self.config_space_size() == PCIE_CONFIG_SPACE_SIZE;
+        ExtendedCapabilityIter {
+            location: self.location,
+            next: if has_extended_space { EXTENDED_CAPABILITIES } else { 0 },
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+
+    /// Returns the offset of the extended capability with the given ID, if the device has it.
+    pub fn find_extended_capability(&self, id: u16) -> Option<u16> {
+        self.extended_capabilities().find(|cap| cap.id == id).map(|cap| cap.offset)
+    }
+
+    /// Returns the offset of the given capability in the configuration space, if the device has it.
+    pub fn find_capability(&self, capability: PciCapability) -> Option<u8> {
+        let id = capability as u8;
+        self.capabilities().find(|cap| cap.id == id).map(|cap| cap.offset)
+    }
+
+    /// Enables Message Signaled Interrupts with a single message, which is delivered as `vector`
+    /// to the CPU with the local APIC ID `destination`, and disables the legacy interrupt line.
+    ///
+    /// The vector should come from `interrupts::register_msi_handler`, and the destination
+    /// from `apic::id`.
+    pub fn enable_msi(&self, vector: u8, destination: u8) -> Result<(), &'static str> {
+        check_msi_vector(vector)?;
+        let offset = self.find_capability(PciCapability::Msi).ok_or("device has no MSI capability")?;
+        // the Message Control register follows the ID and the next pointer
+        let control = self.pci_read_16(offset + 2);
+
+        self.pci_write_32(offset + 4, msi_address(destination));
+        let data_offset = if (control & MSI_64_BIT_CAPABLE) != 0 {
+            self.pci_write_32(offset + 8, 0);
+            offset + 12
+        } else {
+            offset + 8
+        };
+        self.pci_write_16(data_offset, vector as u16);
+
+        // one message only, with edge triggered fixed delivery
+        let control = (control & !MSI_MULTIPLE_MESSAGE_ENABLE) | MSI_ENABLE;
+        self.pci_write_16(offset + 2, control);
+        self.set_command_bits(INTERRUPT_DISABLE);
+        Ok(())
+    }
+
+    /// Enables MSI-X and disables the legacy interrupt line, returning the mapped MSI-X table
+    /// with all entries masked. Entries are routed to vectors with [`MsixTable::set_entry`].
+    pub fn enable_msix(&self) -> Result<MsixTable, &'static str> {
+        let offset = self.find_capability(PciCapability::Msix).ok_or("device has no MSI-X capability")?;
+        let control = self.pci_read_16(offset + 2);
+        let entries = (control & MSIX_TABLE_SIZE_MASK) + 1;
+
+        let table_location = self.pci_read_32(offset + 4);
+        let bar = self.bar((table_location & MSIX_BIR_MASK) as usize)?;
+        if !bar.is_memory() {
+            return Err("MSI-X table is not in a memory BAR");
+        }
+        let table_offset = (table_location & !MSIX_BIR_MASK) as u64;
+        let table_size = entries as usize * MSIX_ENTRY_SIZE;
+        if table_offset + table_size as u64 > bar.size() {
+            return Err("MSI-X table exceeds its BAR");
+        }
+        let table = MmioRegion::map(PhysAddr::new(bar.address() + table_offset), table_size)?;
+        self.set_command_bits(MEMORY_SPACE);
+
+        // keep the whole function masked until every entry is masked
+        self.pci_write_16(offset + 2, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        let table = MsixTable { table, entries };
+        for index in 0..entries {
+            table.mask(index)?;
+        }
+        self.pci_write_16(offset + 2, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+        self.set_command_bits(INTERRUPT_DISABLE);
+        Ok(table)
+    }
 }
 
 impl Deref for PciDevice {
@@ -366,4 +927,101 @@ impl DerefMut for PciDevice {
     fn deref_mut(&mut self) -> &mut PciLocation {
         &mut self.location
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_bar_decoding() {
+    // the standard VGA card of QEMU has a prefetchable framebuffer of 16 MiB in BAR0
+    let vga = get_pci_device_id(0x1234, 0x1111).expect("QEMU's VGA card should be present");
+    match vga.bar(0) {
+        Ok(Bar::Memory32 { size, prefetchable, .. }) => {
+            assert_eq!(size, 16 * 1024 * 1024);
+            assert!(prefetchable);
+        }
+        other => panic!("unexpected BAR0 of the VGA card: {:?}", other),
+    }
+    // a memory BAR has no I/O base
+    assert!(vga.determine_iobase(0).is_err());
+}
+
+#[test_case]
+fn test_bar_mapping() {
+    let vga = get_pci_device_id(0x1234, 0x1111).expect("QEMU's VGA card should be present");
+    let framebuffer = vga.bar(0).unwrap().map().unwrap();
+    assert_eq!(framebuffer.size(), 16 * 1024 * 1024);
+    framebuffer.write_32(0, 0xdead_beef);
+    assert_eq!(framebuffer.read_32(0), 0xdead_beef);
+    assert_eq!(framebuffer.read_16(2), 0xdead);
+    assert_eq!(framebuffer.read_8(0), 0xef);
+    framebuffer.write_64(8, u64::MAX);
+    assert_eq!(framebuffer.read_64(8), u64::MAX);
+}
+
+#[test_case]
+fn test_capabilities() {
+    // the host bridge of QEMU's i440FX machine has no capabilities, so there is nothing to enable
+    let host_bridge = get_pci_device_id(0x8086, 0x1237).expect("QEMU's host bridge should be present");
+    assert_eq!(host_bridge.capabilities().count(), 0);
+    assert_eq!(host_bridge.find_capability(PciCapability::Msi), None);
+    assert!(host_bridge.enable_msi(0x40, 0).is_err());
+    assert!(host_bridge.enable_msix().is_err());
+
+    // every capability of every device lies behind the header
+    for device in pci_device_iter() {
+        assert!(device.capabilities().all(|cap| cap.offset >= 0x40));
+    }
+    assert_eq!(msi_address(1), 0xFEE0_1000);
+    assert!(check_msi_vector(interrupts::PIC_1_OFFSET).is_err());
+    assert!(check_msi_vector(apic::SPURIOUS_VECTOR).is_err());
+    let vector = interrupts::register_msi_handler(|| {}).unwrap();
+    assert!(check_msi_vector(vector).is_ok());
+}
+
+#[test_case]
+fn test_extended_config_access() {
+    let host_bridge = get_pci_device_id(0x8086, 0x1237).expect("QEMU's host bridge should be present");
+    // the first register reads the same through both access mechanisms
+    assert_eq!(host_bridge.pci_read_extended_32(0), Ok(0x1237_8086));
+    assert!(host_bridge.pci_read_extended_32(2).is_err());
+
+    // the tests run on the i440FX machine, which has no ECAM
+    if ecam().is_none() {
+        assert_eq!(host_bridge.config_space_size(), PCI_CONFIG_SPACE_SIZE);
+        assert!(host_bridge.pci_read_extended_32(EXTENDED_CAPABILITIES).is_err());
+        assert_eq!(host_bridge.extended_capabilities().count(), 0);
+    }
+}
+
+#[test_case]
+fn test_config_writes() {
+    let host_bridge = get_pci_device_id(0x8086, 0x1237).expect("QEMU's host bridge should be present");
+    assert_eq!(host_bridge.pci_read_16(PCI_VENDOR_ID), 0x8086);
+    assert_eq!(host_bridge.pci_read_16(PCI_DEVICE_ID), 0x1237);
+    assert_eq!(host_bridge.pci_read_32(PCI_VENDOR_ID), 0x1237_8086);
+    assert_eq!(host_bridge.pci_read_8(PCI_CLASS), 0x06);
+
+    // the cache line size shares its dword with the latency timer, header type and BIST
+    let dword = host_bridge.pci_read_32(PCI_CACHE_LINE_SIZE);
+    let cache_line_size = host_bridge.pci_read_8(PCI_CACHE_LINE_SIZE);
+    host_bridge.pci_write_8(PCI_CACHE_LINE_SIZE, 0x10);
+    assert_eq!(host_bridge.pci_read_8(PCI_CACHE_LINE_SIZE), 0x10);
+    assert_eq!(host_bridge.pci_read_32(PCI_CACHE_LINE_SIZE) >> 8, dword >> 8);
+    host_bridge.pci_write_8(PCI_CACHE_LINE_SIZE, cache_line_size);
+
+    // the interrupt line shares its dword with the read-only interrupt pin
+    let interrupt = host_bridge.pci_read_32(PCI_INTERRUPT_LINE);
+    host_bridge.pci_write_32(PCI_INTERRUPT_LINE, (interrupt & !0xFF) | 0x0B);
+    assert_eq!(host_bridge.pci_read_8(PCI_INTERRUPT_LINE), 0x0B);
+    host_bridge.pci_write_32(PCI_INTERRUPT_LINE, interrupt);
+    assert_eq!(host_bridge.pci_read_32(PCI_INTERRUPT_LINE), interrupt);
+
+    // setting and clearing a command bit leaves the status register alone
+    let command = host_bridge.pci_read_16(PCI_COMMAND);
+    let status = host_bridge.pci_read_16(PCI_STATUS);
+    host_bridge.pci_set_command_register_bit(SERR_ENABLE);
+    assert_eq!(host_bridge.pci_read_16(PCI_COMMAND), command | SERR_ENABLE);
+    host_bridge.pci_clear_command_register_bit(SERR_ENABLE);
+    assert_eq!(host_bridge.pci_read_16(PCI_COMMAND), command & !SERR_ENABLE);
+    assert_eq!(host_bridge.pci_read_16(PCI_STATUS), status);
+    host_bridge.pci_write_16(PCI_COMMAND, command);
+}
@@ -3,13 +3,18 @@
 use crate::{
     println,
     pci,
-    memory, interrupts,
+    memory::DmaBuffer,
+    interrupts,
+    netdev::{self, FrameQueue, FrameStream, LinkInfo, LinkStatus, NetDevice, NetDeviceStats, TransmitQueue},
 };
 use alloc::vec::Vec;
-use x86_64::{
-    instructions::port::Port,
-    VirtAddr
+use conquer_once::spin::OnceCell;
+use core::{
+    cmp::max,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
 };
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
 
 use spin::Mutex;
 
@@ -20,6 +25,8 @@ const ID2: u8 = 0x02;
 const ID3: u8 = 0x03;
 const ID4: u8 = 0x04;
 const ID5: u8 = 0x05;
+const MULTICAST_ADDRESS_0: u8 = 0x08;
+const MULTICAST_ADDRESS_4: u8 = 0x0c;
 const TRANSMIT_STATUS: u8 = 0x10;
 const TRANSMIT_ADDRESS: u8 = 0x20;
 const COMMAND: u8 = 0x37;
@@ -29,6 +36,8 @@ const INTERRUPT_MASK: u8 = 0x3c;
 const INTERRUPT_STATUS: u8 = 0x3e;
 const RECEIVE_CONFIGURATION: u8 = 0x44;
 const CONFIG_1: u8 = 0x52;
+const MEDIA_STATUS: u8 = 0x58;
+const BASIC_MODE_CONTROL: u8 = 0x62;
 
 // Command
 const BUFFER_EMPTY: u8 = 0x01;
@@ -36,6 +45,15 @@ const ENABLE_TRANSMITTER: u8 = 0x04;
 const ENABLE_RECEIVER: u8 = 0x08;
 const RESET: u8 = 0x10;
 
+// MediaStatus
+/// Set while the link is down
+const LINK_DOWN: u8 = 0x04;
+/// Set while the link runs at 10 Mbps instead of 100 Mbps
+const SPEED_10: u8 = 0x08;
+
+// BasicModeControl
+const FULL_DUPLEX: u16 = 0x0100;
+
 // Interrupt
 const RECEIVE_OK: u16 = 0x0001;
 const RECEIVE_ERROR: u16 = 0x0002;
@@ -82,20 +100,191 @@ const MULTICAST: u16 = 0x8000;
 
 const RTL8139_VENDOR_ID: u16 = 0x10EC;
 const RTL8139_DEVICE_ID: u16 = 0x8139;
-const BUFFER_SIZE: u32 = 8 * 1024 + 16 + 1500;
+/// Size of the Receive Buffer, large enough for every RingSize: with WRAP set,
+/// the RTL8139 writes frames that cross the end of the ring into the 16 + 1500 bytes behind it
+const BUFFER_SIZE: u32 = 64 * 1024 + 16 + 1500;
+/// The RTL8139 keeps CURRENT_READ_ADDRESS 16 bytes behind the actual read index
+const READ_ADDRESS_OFFSET: u16 = 0x10;
+/// Size of the header the RTL8139 writes in front of every received frame
+const RECEIVE_HEADER_SIZE: usize = 4;
+/// Size of the CRC at the end of every received frame
+const CRC_SIZE: usize = 4;
+const RECEIVE_ERRORS: u16 = FRAME_ALIGNMENT_ERROR | CHECKSUM_ERROR | LONG_PACKET | RUNT_PACKET | INVALID_SYMBOL;
+const INTERRUPTS: u16 = RECEIVE_OK | RECEIVE_ERROR | TRANSMIT_OK | TRANSMIT_ERROR | RX_BUFFER_OVERFLOW
+    | RX_FIFO_OVERFLOW | PACKET_UNDERRUN_LINK_CHANGE;
 const TRANSMIT_DESCRIPTOR_COUNT: u8 = 4;
+/// Largest frame the RTL8139 can send from a transmit buffer
+const TRANSMIT_BUFFER_SIZE: usize = 1792;
+/// Frames shorter than this (without CRC) are padded with zeros
+const MIN_FRAME_SIZE: usize = 60;
+/// Bits of the Transmit Status Register that hold the size of the frame
+const TRANSMIT_SIZE_MASK: u32 = 0x1fff;
+
+/// Number of received frames that can wait in the frame queue for a task to pick them up
+const FRAME_QUEUE_SIZE: usize = 32;
+/// Largest frame (without CRC) that is passed on to the frame queue
+const MAX_FRAME_SIZE: usize = 1514;
+/// Smallest frame (without CRC) that is passed on to the frame queue
+const MIN_RECEIVE_SIZE: usize = 14;
+/// Largest length in a receive header, anything above means the ring is corrupt
+const MAX_RECEIVE_LENGTH: usize = 4 * 1024;
+
+// The initialized RTL8139, if one was found
+static DEVICE: OnceCell<Rtl8139> = OnceCell::uninit();
+
+/// Size of the Receive-Ringbuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingSize {
+    Kib8,
+    Kib16,
+    Kib32,
+    Kib64,
+}
+
+impl RingSize {
+    /// Returns the size of the ring in bytes
+    pub fn bytes(self) -> usize {
+        match self {
+            RingSize::Kib8 => 8 * 1024,
+            RingSize::Kib16 => 16 * 1024,
+            RingSize::Kib32 => 32 * 1024,
+            RingSize::Kib64 => 64 * 1024,
+        }
+    }
+
+    fn flag(self) -> u32 {
+        match self {
+            RingSize::Kib8 => LENGTH_8K,
+            RingSize::Kib16 => LENGTH_16K,
+            RingSize::Kib32 => LENGTH_32K,
+            RingSize::Kib64 => LENGTH_64K,
+        }
+    }
+}
+
+/// Which frames the RTL8139 receives and where it puts them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiveConfig {
+    /// Receive frames to every physical address, not just our own
+    pub promiscuous: bool,
+    /// Multicast groups whose frames are received
+    pub multicast_groups: Vec<[u8; 6]>,
+    pub ring_size: RingSize,
+}
+
+impl ReceiveConfig {
+    /// Returns the value of the Receive Configuration Register
+    fn register_value(&self) -> u32 {
+        let mut value = ACCEPT_PHYSICAL_MATCH | ACCEPT_BROADCAST | self.ring_size.flag();
+        // frames with errors and runts are received too, so `receive_packets` can count them before dropping them
+        value |= ACCEPT_ERROR | ACCEPT_RUNT;
+        // with a 64K ring, WRAP is ignored and frames always wrap around to the start
+        if self.ring_size != RingSize::Kib64 {
+            value |= WRAP;
+        }
+        if self.promiscuous {
+            value |= ACCEPT_ALL | ACCEPT_MULTICAST;
+        } else if !self.multicast_groups.is_empty() {
+            value |= ACCEPT_MULTICAST;
+        }
+        value
+    }
 
-// The Receive Buffer the RTL8139 uses to write received packets into memory
-static mut RECEIVE_BUFFER: [u8; BUFFER_SIZE as usize] = [0; BUFFER_SIZE as usize];
+    /// Returns the value of the Multicast Address Registers:
+    /// a bit for every hash of a group address
+    fn multicast_filter(&self) -> u64 {
+        if self.promiscuous {
+            return u64::MAX;
+        }
+        self.multicast_groups
+            .iter()
+            .fold(0, |filter, &group| filter | 1 << multicast_hash(group))
+    }
+}
 
-// The Transmit Descriptor points towards the currently active TSD-TSAD-pair
-static mut TRANSMIT_DESCRIPTOR: u8 = 0;
+/// Returns the bit of the Multicast Address Registers a group address is hashed to:
+/// the upper 6 bits of its big-endian Ethernet CRC
+pub fn multicast_hash(address: [u8; 6]) -> u32 {
+    let mut crc: u32 = 0xffff_ffff;
+    for &byte in address.iter() {
+        let mut octet = byte;
+        for _ in 0..8 {
+            let feedback = (crc >> 31) ^ (octet as u32 & 1);
+            crc <<= 1;
+            if feedback != 0 {
+                crc ^= 0x04c1_1db7;
+            }
+            octet >>= 1;
+        }
+    }
+    crc >> 26
+}
 
-// The I/O-Base-Address of the RTL8139, to be set by PciDevice::determine_iobase()
-static mut IO_BASE_ADDR: u16 = 0;
+/// State of an initialized RTL8139
+pub struct Rtl8139 {
+    // The I/O-Base-Address, as determined by PciDevice::determine_iobase()
+    io_base: u16,
+    mac_address: [u8; 6],
+    // The Receive Buffer the RTL8139 uses to write received packets into memory
+    receive_buffer: DmaBuffer,
+    // Current Index inside the Receive-Ringbuffer, only locked with interrupts disabled
+    receive_index: Mutex<usize>,
+    // Only locked with interrupts disabled, as the interrupt handler locks it too
+    receive_config: Mutex<ReceiveConfig>,
+    // Only locked with interrupts disabled, as the interrupt handler locks it too
+    transmitter: Mutex<Transmitter>,
+    // Frames waiting for a Transmit Descriptor
+    transmit_queue: TransmitQueue,
+    frames: FrameQueue,
+    transmitted_frames: AtomicU64,
+    transmitted_bytes: AtomicU64,
+    transmit_errors: AtomicU64,
+    transmit_aborts: AtomicU64,
+    carrier_lost: AtomicU64,
+    receive_errors: ReceiveErrors,
+}
+
+/// Counters of the received frames the RTL8139 dropped
+#[derive(Debug, Default)]
+pub struct ReceiveErrors {
+    /// Frames with a wrong CRC
+    pub crc: AtomicU64,
+    /// Frames that did not end on a byte boundary
+    pub alignment: AtomicU64,
+    /// Frames shorter than an Ethernet header
+    pub runt: AtomicU64,
+    /// Frames longer than the MTU allows
+    pub long: AtomicU64,
+    /// Frames with invalid symbols or a corrupt receive header
+    pub other: AtomicU64,
+    /// Receiver resets after the Receive Buffer or the receive FIFO overflowed
+    pub overflows: AtomicU64,
+}
 
-// Current Index inside the Receive-Ringbuffer of the RTL8139
-static mut RECEIVE_INDEX: i16 = 0;
+impl ReceiveErrors {
+    /// Returns the number of all frames dropped because of errors
+    pub fn total(&self) -> u64 {
+        self.crc.load(Ordering::Relaxed)
+            + self.alignment.load(Ordering::Relaxed)
+            + self.runt.load(Ordering::Relaxed)
+            + self.long.load(Ordering::Relaxed)
+            + self.other.load(Ordering::Relaxed)
+    }
+}
+
+/// Transmit buffers and the state of the TSD-TSAD-pairs they belong to
+struct Transmitter {
+    // One buffer per Transmit Descriptor, the RTL8139 reads the frames from
+    buffers: Vec<DmaBuffer>,
+    // The Transmit Descriptor the next frame is handed to
+    next: u8,
+    // The oldest Transmit Descriptor that has not completed yet
+    oldest: u8,
+    // Number of Transmit Descriptors the RTL8139 is still sending from
+    in_flight: u8,
+    // Tasks waiting for a free Transmit Descriptor
+    waiters: Vec<Waker>,
+}
 
 /// Initializes the RTL8139 Network Card, if it exists, with:
 /// - Getting its I/O-Address
@@ -106,6 +295,7 @@ static mut RECEIVE_INDEX: i16 = 0;
 /// - Interrupt Masking
 /// - Enabling of the Receiver and Transmitter
 /// - Configuring the Receive Buffer
+/// - Registering it as a network interface
 pub fn init() {
     println!("Beginning initialisation of RTL8139!");
 
@@ -114,187 +304,449 @@ pub fn init() {
     if opt_rtl8139.is_some() {
         let rtl8139_dev = opt_rtl8139.unwrap();
 
-        unsafe { IO_BASE_ADDR = rtl8139_dev.determine_iobase(0).unwrap() as u16; }
+        let io_base = rtl8139_dev.determine_iobase(0).unwrap() as u16;
+        let buffers: Option<Vec<DmaBuffer>> = (0..TRANSMIT_DESCRIPTOR_COUNT)
+            .map(|_| DmaBuffer::new(TRANSMIT_BUFFER_SIZE))
+            .collect();
+        let (buffers, receive_buffer) = match (buffers, DmaBuffer::new(BUFFER_SIZE as usize)) {
+            (Some(buffers), Some(receive_buffer)) => (buffers, receive_buffer),
+            _ => {
+                println!("Aborting RTL8139 initialisation: no memory for DMA buffers");
+                return;
+            }
+        };
         interrupts::regiser_interrupt("RTL8139", rtl8139_dev.int_line);
 
         rtl8139_dev.pci_set_command_register_bit(pci::BUS_MASTER);
         rtl8139_dev.pci_set_command_register_bit(pci::IO_SPACE);
 
+        let mut device = Rtl8139 {
+            io_base,
+            mac_address: [0; 6],
+            receive_buffer,
+            receive_index: Mutex::new(0),
+            receive_config: Mutex::new(ReceiveConfig {
+                promiscuous: false,
+                multicast_groups: Vec::new(),
+                ring_size: RingSize::Kib8,
+            }),
+            transmitter: Mutex::new(Transmitter {
+                buffers,
+                next: 0,
+                oldest: 0,
+                in_flight: 0,
+                waiters: Vec::new(),
+            }),
+            transmit_queue: TransmitQueue::new(),
+            frames: FrameQueue::new(FRAME_QUEUE_SIZE, MAX_FRAME_SIZE),
+            transmitted_frames: AtomicU64::new(0),
+            transmitted_bytes: AtomicU64::new(0),
+            transmit_errors: AtomicU64::new(0),
+            transmit_aborts: AtomicU64::new(0),
+            carrier_lost: AtomicU64::new(0),
+            receive_errors: ReceiveErrors::default(),
+        };
+
         println!("Powering on / Waking up RTL8139");
-        io_write_8(CONFIG_1, 0x0);
+        device.io_write_8(CONFIG_1, 0x0);
         
         println!("Performing software reset");
-        io_write_8(COMMAND, RESET);
-        while (io_read_8(COMMAND) & RESET) != 0 {
+        device.io_write_8(COMMAND, RESET);
+        while (device.io_read_8(COMMAND) & RESET) != 0 {
             println!("RST-Bit is still high (1)");
         }
 
+        device.mac_address = [
+            device.io_read_8(ID0),
+            device.io_read_8(ID1),
+            device.io_read_8(ID2),
+            device.io_read_8(ID3),
+            device.io_read_8(ID4),
+            device.io_read_8(ID5)
+        ];
+
         println!("Masking interrupts");
-        io_write_16(INTERRUPT_MASK, RECEIVE_OK | RECEIVE_ERROR | TRANSMIT_OK | TRANSMIT_ERROR);
+        device.io_write_16(INTERRUPT_MASK, INTERRUPTS);
 
         println!("Enabling receiver/transmitter");
-        io_write_8(COMMAND, ENABLE_RECEIVER | ENABLE_TRANSMITTER);
+        device.io_write_8(COMMAND, ENABLE_RECEIVER | ENABLE_TRANSMITTER);
 
         println!("Configuring receive buffer");
-        unsafe {
-            let rxbuf_virt = VirtAddr::new_unsafe(RECEIVE_BUFFER.as_ptr() as u64);
-            let virt_to_phys = memory::translate_addr(rxbuf_virt);
-            let rxbuf_phys = virt_to_phys.unwrap().as_u64();
-            io_write_32(RB_START, rxbuf_phys as u32);
-            io_write_32(RECEIVE_CONFIGURATION, WRAP | ACCEPT_PHYSICAL_MATCH | ACCEPT_BROADCAST | LENGTH_8K);
+        device.io_write_32(RB_START, device.receive_buffer.phys_addr().as_u64() as u32);
+        device.apply_receive_config(&device.receive_config.lock());
+
+        println!("Configuring transmit buffers");
+        for (descriptor, buffer) in device.transmitter.lock().buffers.iter().enumerate() {
+            device.set_transmit_buffer(descriptor as u8, buffer.phys_addr().as_u64() as u32);
         }
-        println!("RTL8139 init complete...");
+
+        let device = DEVICE.get_or_init(move || device);
+        let id = netdev::register(device);
+        println!("RTL8139 init complete, registered as interface {}", id);
     } else {
         println!("Aborting RTL8139 initialisation...")
     }
 }
 
-// Returns the MAC-Address of the RTL8139
-pub fn get_mac_address() -> [u8; 6] {
-    return [
-        io_read_8(ID0),
-        io_read_8(ID1),
-        io_read_8(ID2),
-        io_read_8(ID3),
-        io_read_8(ID4),
-        io_read_8(ID5)
-    ]
+/// Returns the initialized RTL8139, if one was found
+pub fn device() -> Option<&'static Rtl8139> {
+    DEVICE.try_get().ok()
 }
 
-/// Handles the kind of interrupt that caused the RTL8139 to send an IRQ
+/// Handles the interrupt of the RTL8139, if it was initialized
 /// To be called by a handler function in interrupts.rs
 pub fn handle_interrupt() {
-	let status = io_read_16(INTERRUPT_STATUS);
-	io_write_16(INTERRUPT_STATUS, RECEIVE_OK | TRANSMIT_OK | RECEIVE_ERROR | TRANSMIT_ERROR);
-	
-    if (status & RECEIVE_OK) != 0 {
-		// Received
-        println!("RTL8139: RECEIVE_OK");
-        while (io_read_8(COMMAND) & BUFFER_EMPTY) == 0 {
-            receive_packets();
-        }
-	}
-    else if (status & RECEIVE_ERROR) != 0 {
-        println!("RTL8139: RECEIVE_ERROR");
-	}
-    else if (status & TRANSMIT_OK) != 0 {
-		// Sent
-        println!("RTL8139: TRANSMIT_OK");
-	}
-    else if (status & TRANSMIT_ERROR) != 0 {
-        println!("RTL8139: TRANSMIT_ERROR");
-	}
+    if let Some(device) = device() {
+        device.handle_interrupt();
+    }
 }
 
-// Takes the virtual memory address of a packet to be sent and its length 
-pub fn send_packet(buffer_virt_addr: VirtAddr, len: u32) {
-    println!("sending packet");
-    println!("buffer virt addr: {:?}", buffer_virt_addr);
-    println!("buffer len: {}", len);
-
-    loop{
-        unsafe {
-            let status = io_read_32(TRANSMIT_STATUS + (4 * TRANSMIT_DESCRIPTOR));
-            println!("transmit status register of descriptor {}: {:x?}", TRANSMIT_DESCRIPTOR, status);
-            if (status & OWN) != 0 {
+impl Rtl8139 {
+    /// Handles every kind of interrupt that caused the RTL8139 to send an IRQ
+    fn handle_interrupt(&self) {
+        let status = self.io_read_16(INTERRUPT_STATUS);
+        self.io_write_16(INTERRUPT_STATUS, status & INTERRUPTS);
+
+        if (status & (RX_BUFFER_OVERFLOW | RX_FIFO_OVERFLOW)) != 0 {
+            // frames in the ring may be incomplete, start over with an empty ring
+            self.receive_errors.overflows.fetch_add(1, Ordering::Relaxed);
+            self.reset_receiver();
+        } else if (status & (RECEIVE_OK | RECEIVE_ERROR)) != 0 {
+            // Received, the frames with errors are counted and dropped
+            self.receive_packets();
+        }
+
+        if (status & (TRANSMIT_OK | TRANSMIT_ERROR)) != 0 {
+            // Sent or aborted
+            self.release_descriptors();
+        }
+
+        if (status & PACKET_UNDERRUN_LINK_CHANGE) != 0 {
+            // the same bit reports transmit underruns, netdev checks whether the link changed
+            netdev::notify_link_change();
+        }
+    }
+
+    /// Releases the Transmit Descriptors the RTL8139 has finished with
+    /// and wakes the tasks waiting for one
+    fn release_descriptors(&self) {
+        let mut transmitter = self.transmitter.lock();
+        while transmitter.in_flight > 0 {
+            let descriptor = transmitter.oldest;
+            let status = self.io_read_32(TRANSMIT_STATUS + (4 * descriptor));
+            if (status & (TRANSMIT_STATUS_OK | TRANSMIT_STATUS_ABORT)) == 0 {
                 break;
             }
+            if (status & TRANSMIT_STATUS_OK) != 0 {
+                self.transmitted_frames.fetch_add(1, Ordering::Relaxed);
+                self.transmitted_bytes.fetch_add((status & TRANSMIT_SIZE_MASK) as u64, Ordering::Relaxed);
+            } else {
+                self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+                self.transmit_aborts.fetch_add(1, Ordering::Relaxed);
+            }
+            if (status & CARRIER_SENSE_LOST) != 0 {
+                self.carrier_lost.fetch_add(1, Ordering::Relaxed);
+            }
+            transmitter.oldest = (descriptor + 1) % TRANSMIT_DESCRIPTOR_COUNT;
+            transmitter.in_flight -= 1;
+        }
+        for waker in transmitter.waiters.drain(..) {
+            waker.wake();
         }
     }
 
-    let virt_to_phys = unsafe {memory::translate_addr(buffer_virt_addr)};
-    let buffer_phys_addr = virt_to_phys.unwrap().as_u64() as u32;
-    
-    println!("buffer phys addr: {:x?}", buffer_phys_addr);
+    /// Copies the received packets the Receive Buffer holds into the frame queue
+    /// and updates the Index inside the Ringbuffer
+    ///
+    /// Frames with errors are dropped. A corrupt receive header resets the receiver,
+    /// as the position of the next frame is unknown then.
+    fn receive_packets(&self) {
+        let mut receive_index = self.receive_index.lock();
+        let ring_size = self.receive_config.lock().ring_size;
+        let ring = ring_size.bytes();
+        let buffer = self.receive_buffer.as_slice();
+
+        while (self.io_read_8(COMMAND) & BUFFER_EMPTY) == 0 {
+            let index = *receive_index;
+            let header = u16::from_le_bytes([buffer[index], buffer[index + 1]]);
+            // length of the frame including its CRC
+            let length = u16::from_le_bytes([buffer[index + 2], buffer[index + 3]]) as usize;
+
+            if (header & (ROK | RECEIVE_ERRORS)) == 0 || length < CRC_SIZE || length > MAX_RECEIVE_LENGTH {
+                self.receive_errors.other.fetch_add(1, Ordering::Relaxed);
+                drop(receive_index);
+                self.reset_receiver();
+                return;
+            }
 
-    set_transmit_buffer(buffer_phys_addr); 
-    set_transmit_status(len);
+            let frame_size = length - CRC_SIZE;
+            if (header & CHECKSUM_ERROR) != 0 {
+                self.receive_errors.crc.fetch_add(1, Ordering::Relaxed);
+            } else if (header & FRAME_ALIGNMENT_ERROR) != 0 {
+                self.receive_errors.alignment.fetch_add(1, Ordering::Relaxed);
+            } else if (header & RUNT_PACKET) != 0 || frame_size < MIN_RECEIVE_SIZE {
+                self.receive_errors.runt.fetch_add(1, Ordering::Relaxed);
+            } else if (header & LONG_PACKET) != 0 || frame_size > MAX_FRAME_SIZE {
+                self.receive_errors.long.fetch_add(1, Ordering::Relaxed);
+            } else if (header & (INVALID_SYMBOL | ROK)) != ROK {
+                self.receive_errors.other.fetch_add(1, Ordering::Relaxed);
+            } else if ring_size == RingSize::Kib64 {
+                // a frame crossing the end of the ring continues at its start
+                let start = (index + RECEIVE_HEADER_SIZE) % ring;
+                let end = start + frame_size;
+                if end <= ring {
+                    self.frames.push(&buffer[start..end]);
+                } else {
+                    self.frames.push_wrapped(&buffer[start..ring], &buffer[..end - ring]);
+                }
+            } else {
+                // with WRAP set, a frame crossing the end of the ring continues behind it,
+                // so it is always contiguous in the Receive Buffer
+                let start = index + RECEIVE_HEADER_SIZE;
+                self.frames.push(&buffer[start..start + frame_size]);
+            }
 
-    unsafe {
-        TRANSMIT_DESCRIPTOR = (TRANSMIT_DESCRIPTOR + 1) % TRANSMIT_DESCRIPTOR_COUNT
+            *receive_index = ((index + RECEIVE_HEADER_SIZE + length + 3) & !0x3) % ring;
+            self.io_write_16(CURRENT_READ_ADDRESS, (*receive_index as u16).wrapping_sub(READ_ADDRESS_OFFSET));
+        }
     }
-}
 
-/// Writes the address of the buffer that holds a packet to be sent
-/// in the currently active Transmit Address Register of the RTL8139
-fn set_transmit_buffer(buffer: u32) {
-    unsafe{
-        let offset = TRANSMIT_ADDRESS +(4 * TRANSMIT_DESCRIPTOR);
-        io_write_32(offset, buffer);
-        // println!("{}", io_read_32(offset))
+    /// Discards all frames in the Receive-Ringbuffer and restarts the receiver at its beginning
+    fn reset_receiver(&self) {
+        let mut receive_index = self.receive_index.lock();
+
+        self.io_write_8(COMMAND, ENABLE_TRANSMITTER);
+        *receive_index = 0;
+        self.io_write_16(CURRENT_READ_ADDRESS, 0u16.wrapping_sub(READ_ADDRESS_OFFSET));
+        self.io_write_8(COMMAND, ENABLE_RECEIVER | ENABLE_TRANSMITTER);
+        self.apply_receive_config(&self.receive_config.lock());
+        self.io_write_16(INTERRUPT_STATUS, RECEIVE_OK | RECEIVE_ERROR | RX_BUFFER_OVERFLOW | RX_FIFO_OVERFLOW);
     }
-}
 
-/// Writes the length of the packet to be sent
-/// in the currently active Transmit Status Register of the RTL8139
-fn set_transmit_status(size: u32) {
-    unsafe{
-        let offset = TRANSMIT_STATUS +(4 * TRANSMIT_DESCRIPTOR);
-        io_write_32(offset, size);
-        // println!("{}", io_read_32(offset))
+    /// Writes the Receive Configuration Register and the Multicast Address Registers
+    fn apply_receive_config(&self, config: &ReceiveConfig) {
+        let filter = config.multicast_filter();
+        self.io_write_32(MULTICAST_ADDRESS_0, filter as u32);
+        self.io_write_32(MULTICAST_ADDRESS_4, (filter >> 32) as u32);
+        self.io_write_32(RECEIVE_CONFIGURATION, config.register_value());
     }
-}
 
-/// Prints the received packets the Receive Buffer holds
-/// and updates the Index inside the Ringbuffer
-pub fn receive_packets() {
-    let header: u16 = unsafe {(RECEIVE_BUFFER[RECEIVE_INDEX as usize + 1] as u16) << 8 | (RECEIVE_BUFFER[RECEIVE_INDEX as usize] as u16)};
-    
-    println!("header: {:x}", header);
-    
-    if (header & ROK) != 0 {
-        let length: i16 = unsafe {(RECEIVE_BUFFER[RECEIVE_INDEX as usize + 3] as i16) << 8 | (RECEIVE_BUFFER[RECEIVE_INDEX as usize + 2] as i16)};
-        println!("PACKET LENGTH: {:?} (including 4 CRC)", length);
-        
-        let payload: Vec<u8> = Vec::from(unsafe {&RECEIVE_BUFFER[RECEIVE_INDEX as usize + 4..RECEIVE_INDEX as usize + (length as usize)]});
-        println!("PACKET PAYLOAD: {:x?}", payload);
-        
-        unsafe {
-            println!("PRE-ADJUST RECEIVE_INDEX: {}", RECEIVE_INDEX);
-            RECEIVE_INDEX += length + 4;
-            RECEIVE_INDEX = (RECEIVE_INDEX + 3) & !0x3;
-            RECEIVE_INDEX %= 0x2000;
-            io_write_16(CURRENT_READ_ADDRESS, (RECEIVE_INDEX - 0x10) as u16);
-            println!("POST-ADJUST RECEIVE_INDEX: {}", RECEIVE_INDEX);
+    /// Changes the receive configuration and applies it to the RTL8139
+    fn update_receive_config(&self, update: impl FnOnce(&mut ReceiveConfig)) {
+        without_interrupts(|| {
+            let mut config = self.receive_config.lock();
+            let ring_size = config.ring_size;
+            update(&mut config);
+            let ring_size_changed = config.ring_size != ring_size;
+            drop(config);
+
+            if ring_size_changed {
+                // the frames in the ring were written for the old size
+                self.reset_receiver();
+            } else {
+                self.apply_receive_config(&self.receive_config.lock());
+            }
+        })
+    }
+
+    /// Returns the current receive configuration
+    pub fn receive_config(&self) -> ReceiveConfig {
+        without_interrupts(|| self.receive_config.lock().clone())
+    }
+
+    /// Receives frames to every physical address, e.g. for sniffing traffic
+    pub fn set_promiscuous(&self, enabled: bool) {
+        self.update_receive_config(|config| config.promiscuous = enabled);
+    }
+
+    /// Receives the frames sent to the given multicast group
+    pub fn join_multicast_group(&self, group: [u8; 6]) -> Result<(), &'static str> {
+        if group[0] & 0x01 == 0 {
+            return Err("not a multicast address");
         }
+        self.update_receive_config(|config| {
+            if !config.multicast_groups.contains(&group) {
+                config.multicast_groups.push(group);
+            }
+        });
+        Ok(())
     }
-}
 
-// Returns 8-Bit data from the specified offset inside the IO-Space of the RTL8139
-fn io_read_8(offset: u8) -> u8 {
-    let io_port: Mutex<Port<u8>> = Mutex::new(unsafe {Port::new(IO_BASE_ADDR + offset as u16)});
-    let res = unsafe{io_port.lock().read()};
-    res
-}
+    /// Stops receiving the frames sent to the given multicast group
+    ///
+    /// Other groups with the same hash may still let some of them through.
+    pub fn leave_multicast_group(&self, group: [u8; 6]) {
+        self.update_receive_config(|config| config.multicast_groups.retain(|&joined| joined != group));
+    }
 
-// Returns 16-Bit data from the specified offset inside the IO-Space of the RTL8139
-fn io_read_16(offset: u8) -> u16 {
-    let io_port: Mutex<Port<u16>> = Mutex::new(unsafe {Port::new(IO_BASE_ADDR + offset as u16)});
-    let res = unsafe{io_port.lock().read()};
-    res
-}
+    /// Changes the size of the Receive-Ringbuffer, discarding the frames in it
+    pub fn set_ring_size(&self, ring_size: RingSize) {
+        self.update_receive_config(|config| config.ring_size = ring_size);
+    }
 
-// Returns 32-Bit data from the specified offset inside the IO-Space of the RTL8139
-fn io_read_32(offset: u8) -> u32 {
-    let io_port: Mutex<Port<u32>> = Mutex::new(unsafe {Port::new(IO_BASE_ADDR + offset as u16)});
-    let res = unsafe{io_port.lock().read()};
-    res
-}
+    /// Returns the counters of the received frames that were dropped because of errors
+    pub fn receive_errors(&self) -> &ReceiveErrors {
+        &self.receive_errors
+    }
 
-// Writes 8-Bit data to the specified offset inside the IO-Space of the RTL8139
-fn io_write_8(offset: u8, value: u8) {
-    let io_port: Mutex<Port<u8>> = Mutex::new(unsafe {Port::new(IO_BASE_ADDR + offset as u16)});
-    unsafe{io_port.lock().write(value);}
-}
+    /// Sends a frame, waiting for room in the transmit queue while all Transmit Descriptors are in use
+    pub async fn send(&self, frame: &[u8]) -> Result<(), &'static str> {
+        netdev::send_frame(self, frame).await
+    }
+
+    /// Copies the frame into the buffer of the next Transmit Descriptor
+    /// and hands it to the RTL8139
+    ///
+    /// Returns false if all Transmit Descriptors are in use,
+    /// after registering the waker to be woken once one is released.
+    fn try_send(&self, frame: &[u8], waker: Option<&Waker>) -> Result<bool, &'static str> {
+        if frame.len() > TRANSMIT_BUFFER_SIZE {
+            return Err("frame too large for the transmit buffer");
+        }
+
+        without_interrupts(|| {
+            let mut transmitter = self.transmitter.lock();
+            if transmitter.in_flight == TRANSMIT_DESCRIPTOR_COUNT {
+                if let Some(waker) = waker {
+                    if !transmitter.waiters.iter().any(|waiter| waiter.will_wake(waker)) {
+                        transmitter.waiters.push(waker.clone());
+                    }
+                }
+                return Ok(false);
+            }
+
+            let descriptor = transmitter.next;
+            let length = max(frame.len(), MIN_FRAME_SIZE);
+            let buffer = transmitter.buffers[descriptor as usize].as_mut_slice();
+            buffer[..frame.len()].copy_from_slice(frame);
+            buffer[frame.len()..length].fill(0);
+
+            // writing the size clears the OWN bit, which starts the transmission
+            self.set_transmit_status(descriptor, length as u32);
+            transmitter.next = (descriptor + 1) % TRANSMIT_DESCRIPTOR_COUNT;
+            transmitter.in_flight += 1;
+            Ok(true)
+        })
+    }
+
+    /// Writes the address of the buffer that holds a packet to be sent
+    /// in the given Transmit Address Register of the RTL8139
+    fn set_transmit_buffer(&self, descriptor: u8, buffer: u32) {
+        self.io_write_32(TRANSMIT_ADDRESS + (4 * descriptor), buffer);
+    }
+
+    /// Writes the length of the packet to be sent
+    /// in the given Transmit Status Register of the RTL8139
+    fn set_transmit_status(&self, descriptor: u8, size: u32) {
+        self.io_write_32(TRANSMIT_STATUS + (4 * descriptor), size);
+    }
+
+    // Returns 8-Bit data from the specified offset inside the IO-Space of the RTL8139
+    fn io_read_8(&self, offset: u8) -> u8 {
+        unsafe { Port::new(self.io_base + offset as u16).read() }
+    }
+
+    // Returns 16-Bit data from the specified offset inside the IO-Space of the RTL8139
+    fn io_read_16(&self, offset: u8) -> u16 {
+        unsafe { Port::new(self.io_base + offset as u16).read() }
+    }
 
-// Writes 16-Bit data to the specified offset inside the IO-Space of the RTL8139
-fn io_write_16(offset: u8, value: u16) {
-    let io_port: Mutex<Port<u16>> = Mutex::new(unsafe {Port::new(IO_BASE_ADDR + offset as u16)});
-    unsafe{io_port.lock().write(value);}
+    // Returns 32-Bit data from the specified offset inside the IO-Space of the RTL8139
+    fn io_read_32(&self, offset: u8) -> u32 {
+        unsafe { Port::new(self.io_base + offset as u16).read() }
+    }
+
+    // Writes 8-Bit data to the specified offset inside the IO-Space of the RTL8139
+    fn io_write_8(&self, offset: u8, value: u8) {
+        unsafe { Port::new(self.io_base + offset as u16).write(value) }
+    }
+
+    // Writes 16-Bit data to the specified offset inside the IO-Space of the RTL8139
+    fn io_write_16(&self, offset: u8, value: u16) {
+        unsafe { Port::new(self.io_base + offset as u16).write(value) }
+    }
+
+    // Writes 32-Bit data to the specified offset inside the IO-Space of the RTL8139
+    fn io_write_32(&self, offset: u8, value: u32) {
+        unsafe { Port::new(self.io_base + offset as u16).write(value) }
+    }
 }
 
-// Writes 32-Bit data to the specified offset inside the IO-Space of the RTL8139
-fn io_write_32(offset: u8, value: u32) {
-    let io_port: Mutex<Port<u32>> = Mutex::new(unsafe {Port::new(IO_BASE_ADDR + offset as u16)});
-    unsafe{io_port.lock().write(value);}
-}
\ No newline at end of file
+impl NetDevice for Rtl8139 {
+    fn name(&self) -> &str {
+        "rtl8139"
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    /// Sends the frame if a Transmit Descriptor is free, queues it otherwise
+    fn send_frame(&self, frame: &[u8]) -> Result<(), &'static str> {
+        let result = self.transmit_queue.send(frame, |frame, waker| self.try_send(frame, waker));
+        if result.is_err() {
+            self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Sends the frame if a Transmit Descriptor is free, queues it otherwise,
+    /// pending while the transmit queue is full
+    fn poll_send_frame(&self, frame: &[u8], cx: &mut Context) -> Poll<Result<(), &'static str>> {
+        let result = self.transmit_queue.poll_send(frame, cx, |frame, waker| self.try_send(frame, waker));
+        if let Poll::Ready(Err(_)) = result {
+            self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Sends queued frames as the interrupt handler releases Transmit Descriptors
+    fn flush_transmit_queue(&self, waker: &Waker) {
+        self.transmit_queue.flush(
+            waker,
+            |frame, waker| self.try_send(frame, waker),
+            |_| {
+                self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+            },
+        );
+    }
+
+    fn receive_stream(&'static self) -> FrameStream {
+        self.frames.stream()
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        if (self.io_read_8(MEDIA_STATUS) & LINK_DOWN) != 0 {
+            LinkStatus::Down
+        } else {
+            LinkStatus::Up
+        }
+    }
+
+    fn link_info(&self) -> LinkInfo {
+        let status = self.link_status();
+        let up = status == LinkStatus::Up;
+        let speed_mbps = if (self.io_read_8(MEDIA_STATUS) & SPEED_10) != 0 { 10 } else { 100 };
+        LinkInfo {
+            status,
+            speed_mbps: if up { Some(speed_mbps) } else { None },
+            full_duplex: if up { Some((self.io_read_16(BASIC_MODE_CONTROL) & FULL_DUPLEX) != 0) } else { None },
+        }
+    }
+
+    fn stats(&self) -> NetDeviceStats {
+        let mut stats = NetDeviceStats {
+            tx_frames: self.transmitted_frames.load(Ordering::Relaxed),
+            tx_bytes: self.transmitted_bytes.load(Ordering::Relaxed),
+            tx_errors: self.transmit_errors.load(Ordering::Relaxed),
+            rx_errors: self.receive_errors.total(),
+            rx_crc_errors: self.receive_errors.crc.load(Ordering::Relaxed),
+            rx_overflows: self.receive_errors.overflows.load(Ordering::Relaxed),
+            tx_aborts: self.transmit_aborts.load(Ordering::Relaxed),
+            tx_carrier_lost: self.carrier_lost.load(Ordering::Relaxed),
+            ..NetDeviceStats::default()
+        };
+        self.frames.add_stats(&mut stats);
+        stats
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{print, println, ethernet};
+use crate::{print, println, ethernet, netstat, pcap};
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
@@ -77,7 +77,17 @@ pub async fn print_keypresses() {
                     DecodedKey::Unicode(character) => print!("{}", character),
                     DecodedKey::RawKey(key) => 
                         if key == KeyCode::ArrowRight {
-                            ethernet::send_empty_frame();
+                            let _ = ethernet::send_empty_frame();
+                        } else if key == KeyCode::ArrowDown {
+                            netstat::print();
+                        } else if key == KeyCode::ArrowUp {
+                            if pcap::is_capturing() {
+                                pcap::stop();
+                                println!("pcap: capture stopped, {} frames dropped", pcap::dropped());
+                            } else {
+                                pcap::start(None);
+                                println!("pcap: capturing to COM2");
+                            }
                         } else {
                             print!("{:?}", key);
                         },
@@ -0,0 +1,1093 @@
+use crate::{ipv4, ipv4::Ipv4Header, netconfig, netstat, time};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+    task::{Poll, Waker},
+};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+// Flags
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+// Options
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Size of a TCP header without options
+const HEADER_SIZE: usize = 20;
+/// Largest segment we send and receive, so segments are never fragmented
+const MSS: usize = ipv4::MTU - ipv4::HEADER_SIZE - HEADER_SIZE;
+/// Segment size assumed if the peer does not announce one (RFC 1122)
+const DEFAULT_MSS: usize = 536;
+
+const SEND_BUFFER_SIZE: usize = 8 * 1024;
+const RECEIVE_BUFFER_SIZE: usize = 8 * 1024;
+/// Maximum number of connections waiting to be accepted per listener
+const MAX_BACKLOG: usize = 8;
+
+// Retransmission timeout (RFC 6298)
+const INITIAL_RTO: u64 = time::TICKS_PER_SECOND;
+const MIN_RTO: u64 = time::TICKS_PER_SECOND / 5;
+const MAX_RTO: u64 = 60 * time::TICKS_PER_SECOND;
+/// Retransmissions of a segment before the connection is aborted
+const MAX_RETRANSMISSIONS: u32 = 8;
+/// Time a closed connection stays in TIME-WAIT, twice the maximum segment lifetime
+const TIME_WAIT_DURATION: u64 = 10 * time::TICKS_PER_SECOND;
+/// Interval in which the timer task checks for expired timers
+const TIMER_INTERVAL: u64 = time::TICKS_PER_SECOND / 20;
+
+// Ports handed out to connecting streams
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(FIRST_EPHEMERAL_PORT);
+
+// Mixed into initial sequence numbers, so connections opened in the same tick differ
+static ISS_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// Returns whether sequence number `a` comes before `b`, modulo 2^32
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Returns whether sequence number `a` comes before or equals `b`, modulo 2^32
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// TCP header, with the MSS option as the only supported option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub urgent_pointer: u16,
+    pub mss: Option<u16>,
+}
+
+impl TcpHeader {
+    /// Returns the header followed by the payload in bytes, including its checksum
+    pub fn to_bytes(&self, src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let options_size = if self.mss.is_some() { 4 } else { 0 };
+        let header_size = HEADER_SIZE + options_size;
+
+        let mut result: Vec<u8> = Vec::with_capacity(header_size + payload.len());
+        result.extend_from_slice(&self.src_port.to_be_bytes());
+        result.extend_from_slice(&self.dst_port.to_be_bytes());
+        result.extend_from_slice(&self.seq.to_be_bytes());
+        result.extend_from_slice(&self.ack.to_be_bytes());
+        result.push(((header_size / 4) as u8) << 4);
+        result.push(self.flags);
+        result.extend_from_slice(&self.window.to_be_bytes());
+        result.extend_from_slice(&[0, 0]);
+        result.extend_from_slice(&self.urgent_pointer.to_be_bytes());
+        if let Some(mss) = self.mss {
+            result.push(OPTION_MSS);
+            result.push(4);
+            result.extend_from_slice(&mss.to_be_bytes());
+        }
+        result.extend_from_slice(payload);
+
+        let checksum = ipv4::pseudo_header_checksum(src, dst, ipv4::PROTOCOL_TCP, &result);
+        result[16..18].copy_from_slice(&checksum.to_be_bytes());
+        result
+    }
+
+    /// Parses a TCP header and validates the checksum of the segment
+    ///
+    /// Returns the header and the payload behind it and its options.
+    pub fn from_bytes(src: [u8; 4], dst: [u8; 4], bytes: &[u8]) -> Result<(Self, &[u8]), &'static str> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("TCP segment too short");
+        }
+        let header_size = (bytes[12] >> 4) as usize * 4;
+        if header_size < HEADER_SIZE || header_size > bytes.len() {
+            return Err("invalid TCP data offset");
+        }
+        if ipv4::pseudo_header_checksum(src, dst, ipv4::PROTOCOL_TCP, bytes) != 0 {
+            return Err("invalid TCP checksum");
+        }
+
+        let mut mss = None;
+        let mut options = &bytes[HEADER_SIZE..header_size];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let length = *options.get(1).ok_or("truncated TCP option")? as usize;
+                    if length < 2 || length > options.len() {
+                        return Err("invalid TCP option length");
+                    }
+                    if kind == OPTION_MSS && length == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[length..];
+                }
+            }
+        }
+
+        let header = TcpHeader {
+            src_port: u16::from_be_bytes([bytes[0], bytes[1]]),
+            dst_port: u16::from_be_bytes([bytes[2], bytes[3]]),
+            seq: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            ack: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            flags: bytes[13],
+            window: u16::from_be_bytes([bytes[14], bytes[15]]),
+            urgent_pointer: u16::from_be_bytes([bytes[18], bytes[19]]),
+            mss,
+        };
+        Ok((header, &bytes[header_size..]))
+    }
+
+    /// Returns the length of the segment in sequence space,
+    /// counting SYN and FIN as one each
+    fn segment_length(&self, payload_length: usize) -> u32 {
+        let mut length = payload_length as u32;
+        if self.flags & SYN != 0 {
+            length += 1;
+        }
+        if self.flags & FIN != 0 {
+            length += 1;
+        }
+        length
+    }
+}
+
+/// States of a TCP connection (RFC 793), except LISTEN which is a `TcpListener`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// Identifies a connection by its local port and remote address and port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ConnectionKey {
+    local_port: u16,
+    remote_addr: [u8; 4],
+    remote_port: u16,
+}
+
+/// Transmission Control Block, holding the complete state of a connection
+struct Tcb {
+    state: TcpState,
+    /// Port of the listener this connection was opened by, until it is established
+    listener: Option<u16>,
+    /// Whether no `TcpStream` owns this connection, so it can be removed once closed
+    detached: bool,
+    error: Option<&'static str>,
+
+    // send sequence space
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    /// Sequence and acknowledgment number of the segment that last updated `snd_wnd`
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// Whether a zero window probe was sent and neither acknowledged nor answered by an open window
+    probe_outstanding: bool,
+    mss: usize,
+    /// Unacknowledged and unsent data, starting at `snd_una`
+    send_buffer: VecDeque<u8>,
+    /// Whether the connection was closed for sending, so a FIN follows the data
+    fin_queued: bool,
+    fin_seq: Option<u32>,
+
+    // receive sequence space
+    rcv_nxt: u32,
+    receive_buffer: VecDeque<u8>,
+    fin_received: bool,
+
+    // timers
+    rto: u64,
+    srtt: Option<u64>,
+    rttvar: u64,
+    /// Sequence number and send time of the segment used for the next RTT measurement
+    rtt_sample: Option<(u32, u64)>,
+    retransmit_deadline: Option<u64>,
+    retransmissions: u32,
+    time_wait_deadline: Option<u64>,
+
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Tcb {
+    fn new(state: TcpState, listener: Option<u16>) -> Self {
+        let iss = (time::ticks() as u32)
+            .wrapping_mul(250_000)
+            .wrapping_add(ISS_COUNTER.fetch_add(64_000, Ordering::Relaxed));
+        Tcb {
+            state,
+            listener,
+            detached: listener.is_some(),
+            error: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: iss,
+            probe_outstanding: false,
+            mss: DEFAULT_MSS,
+            send_buffer: VecDeque::new(),
+            fin_queued: false,
+            fin_seq: None,
+            rcv_nxt: 0,
+            receive_buffer: VecDeque::new(),
+            fin_received: false,
+            rto: INITIAL_RTO,
+            srtt: None,
+            rttvar: 0,
+            rtt_sample: None,
+            retransmit_deadline: None,
+            retransmissions: 0,
+            time_wait_deadline: None,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    fn receive_window(&self) -> u16 {
+        min(RECEIVE_BUFFER_SIZE - self.receive_buffer.len(), u16::MAX as usize) as u16
+    }
+
+    /// Sends a segment with the given sequence number, flags and payload,
+    /// acknowledging everything received so far if ACK is set
+    fn send(&self, key: &ConnectionKey, seq: u32, flags: u8, payload: &[u8]) {
+        let header = TcpHeader {
+            src_port: key.local_port,
+            dst_port: key.remote_port,
+            seq,
+            ack: if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window: self.receive_window(),
+            urgent_pointer: 0,
+            mss: if flags & SYN != 0 { Some(MSS as u16) } else { None },
+        };
+        let segment = header.to_bytes(netconfig::ipv4_address(), key.remote_addr, payload);
+        let _ = ipv4::send_packet(key.remote_addr, ipv4::PROTOCOL_TCP, &segment);
+    }
+
+    fn send_ack(&self, key: &ConnectionKey) {
+        self.send(key, self.snd_nxt, ACK, &[]);
+    }
+
+    fn wake_all(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+
+    fn abort(&mut self, error: &'static str) {
+        self.error = Some(error);
+        self.state = TcpState::Closed;
+        self.retransmit_deadline = None;
+        self.wake_all();
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = TcpState::TimeWait;
+        self.retransmit_deadline = None;
+        self.time_wait_deadline = Some(time::ticks() + TIME_WAIT_DURATION);
+        self.wake_all();
+    }
+
+    /// Starts the retransmission timer, if it is not running already,
+    /// and times the segment starting at `seq` if no other one is timed
+    fn start_timer(&mut self, seq: u32) {
+        let now = time::ticks();
+        if self.retransmit_deadline.is_none() {
+            self.retransmit_deadline = Some(now + self.rto);
+        }
+        // Karn's algorithm: retransmitted segments are not timed
+        if self.rtt_sample.is_none() && self.retransmissions == 0 {
+            self.rtt_sample = Some((seq, now));
+        }
+    }
+
+    /// Updates the retransmission timeout with a round-trip time measurement (RFC 6298)
+    fn update_rto(&mut self, rtt: u64) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (3 * self.rttvar + deviation) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + 4 * self.rttvar;
+        self.rto = rto.max(MIN_RTO).min(MAX_RTO);
+    }
+
+    /// Sends everything the state, the send buffer and the peer's window allow:
+    /// the SYN during the handshake, then data segments and finally the FIN
+    fn output(&mut self, key: &ConnectionKey) {
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => {
+                if self.snd_nxt == self.iss {
+                    let flags = if self.state == TcpState::SynSent { SYN } else { SYN | ACK };
+                    self.send(key, self.iss, flags, &[]);
+                    self.snd_nxt = self.iss.wrapping_add(1);
+                    self.start_timer(self.iss);
+                }
+                return;
+            }
+            TcpState::FinWait2 | TcpState::TimeWait | TcpState::Closed => return,
+            _ => {}
+        }
+
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if in_flight >= self.send_buffer.len() {
+                break;
+            }
+            let window_left = (self.snd_wnd as usize).saturating_sub(in_flight);
+            let length = min(min(self.mss, window_left), self.send_buffer.len() - in_flight);
+            if length == 0 {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buffer.range(in_flight..in_flight + length).copied().collect();
+            self.send(key, self.snd_nxt, ACK | PSH, &payload);
+            self.start_timer(self.snd_nxt);
+            self.snd_nxt = self.snd_nxt.wrapping_add(length as u32);
+        }
+
+        let all_data_sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize == self.send_buffer.len();
+        if self.fin_queued && all_data_sent {
+            let fin_seq = self.snd_nxt;
+            self.send(key, fin_seq, FIN | ACK, &[]);
+            self.start_timer(fin_seq);
+            self.fin_seq = Some(fin_seq);
+            self.snd_nxt = fin_seq.wrapping_add(1);
+            self.state = match self.state {
+                TcpState::Established => TcpState::FinWait1,
+                TcpState::CloseWait => TcpState::LastAck,
+                state => state,
+            };
+        } else if !all_data_sent && self.snd_nxt == self.snd_una {
+            // the peer's window is closed, probe it once the timer expires
+            if self.retransmit_deadline.is_none() {
+                self.retransmit_deadline = Some(time::ticks() + self.rto);
+            }
+        }
+    }
+
+    /// Handles an acknowledgment of new data
+    fn acknowledge(&mut self, ack: u32) {
+        let now = time::ticks();
+        if let Some((seq, sent)) = self.rtt_sample {
+            if seq_lt(seq, ack) {
+                self.update_rto(now - sent);
+                self.rtt_sample = None;
+            }
+        }
+
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        let data_acked = min(acked, self.send_buffer.len());
+        self.send_buffer.drain(..data_acked);
+        self.snd_una = ack;
+        if seq_lt(self.snd_nxt, ack) {
+            // the byte of a zero window probe was accepted
+            self.snd_nxt = ack;
+        }
+        self.probe_outstanding = false;
+        self.retransmissions = 0;
+        self.retransmit_deadline = if self.snd_una == self.snd_nxt {
+            None
+        } else {
+            Some(now + self.rto)
+        };
+
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+
+    /// Takes the send window from the segment
+    fn update_window(&mut self, header: &TcpHeader) {
+        self.snd_wnd = header.window as u32;
+        self.snd_wl1 = header.seq;
+        self.snd_wl2 = header.ack;
+        if self.snd_wnd > 0 {
+            // the window reopened, output sends the probed byte as regular data
+            self.probe_outstanding = false;
+        }
+    }
+
+    /// Handles an expired retransmission timer by sending
+    /// all unacknowledged segments again (go-back-N)
+    fn on_timeout(&mut self, key: &ConnectionKey) {
+        self.retransmit_deadline = None;
+        self.rtt_sample = None;
+        self.rto = min(self.rto * 2, MAX_RTO);
+
+        if self.snd_una == self.snd_nxt && !self.send_buffer.is_empty() {
+            // zero window probe with the next byte of data
+            let probe = [self.send_buffer[0]];
+            self.send(key, self.snd_una, ACK, &probe);
+            self.probe_outstanding = true;
+            self.retransmit_deadline = Some(time::ticks() + self.rto);
+            return;
+        }
+
+        self.retransmissions += 1;
+        if self.retransmissions > MAX_RETRANSMISSIONS {
+            self.send(key, self.snd_nxt, RST | ACK, &[]);
+            self.abort("connection timed out");
+            return;
+        }
+
+        self.snd_nxt = self.snd_una;
+        self.output(key);
+    }
+
+    /// Returns whether a segment lies at least partly inside the receive window
+    fn is_acceptable(&self, seq: u32, length: u32) -> bool {
+        let window = self.receive_window() as u32;
+        let in_window = |s: u32| seq_le(self.rcv_nxt, s) && seq_lt(s, self.rcv_nxt.wrapping_add(window));
+        match (length, window) {
+            (0, 0) => seq == self.rcv_nxt,
+            (0, _) => in_window(seq),
+            (_, 0) => false,
+            _ => in_window(seq) || in_window(seq.wrapping_add(length - 1)),
+        }
+    }
+
+    /// Handles a segment in the SYN-SENT state
+    fn on_segment_syn_sent(&mut self, key: &ConnectionKey, header: &TcpHeader) {
+        if header.flags & ACK != 0 && (seq_le(header.ack, self.iss) || seq_lt(self.snd_nxt, header.ack)) {
+            if header.flags & RST == 0 {
+                send_reset(key, header, 0);
+            }
+            return;
+        }
+        if header.flags & RST != 0 {
+            if header.flags & ACK != 0 {
+                self.abort("connection refused");
+            }
+            return;
+        }
+        if header.flags & SYN == 0 {
+            return;
+        }
+
+        self.rcv_nxt = header.seq.wrapping_add(1);
+        self.update_window(header);
+        self.mss = header.mss.map_or(DEFAULT_MSS, |mss| min(mss as usize, MSS));
+        if header.flags & ACK != 0 {
+            self.acknowledge(header.ack);
+            self.state = TcpState::Established;
+            self.send_ack(key);
+            self.wake_all();
+        } else {
+            // simultaneous open
+            self.state = TcpState::SynReceived;
+            self.snd_nxt = self.iss;
+            self.retransmit_deadline = None;
+            self.output(key);
+        }
+    }
+
+    /// Handles a received segment as described in RFC 793, "SEGMENT ARRIVES"
+    ///
+    /// Returns the port of the listener if the connection just got established
+    /// and has to be put into its backlog.
+    fn on_segment(&mut self, key: &ConnectionKey, header: &TcpHeader, payload: &[u8]) -> Option<u16> {
+        match self.state {
+            TcpState::Closed => return None,
+            TcpState::SynSent => {
+                self.on_segment_syn_sent(key, header);
+                return None;
+            }
+            _ => {}
+        }
+
+        if !self.is_acceptable(header.seq, header.segment_length(payload.len())) {
+            if header.flags & RST == 0 {
+                self.send_ack(key);
+            }
+            return None;
+        }
+        if header.flags & RST != 0 {
+            self.abort("connection reset");
+            return None;
+        }
+        if header.flags & SYN != 0 {
+            self.send(key, self.snd_nxt, RST | ACK, &[]);
+            self.abort("connection reset");
+            return None;
+        }
+        if header.flags & ACK == 0 {
+            return None;
+        }
+
+        let mut established = None;
+        if self.state == TcpState::SynReceived {
+            if seq_lt(self.snd_una, header.ack) && seq_le(header.ack, self.snd_nxt) {
+                self.acknowledge(header.ack);
+                self.state = TcpState::Established;
+                established = self.listener.take();
+            } else {
+                send_reset(key, header, payload.len());
+                return None;
+            }
+        }
+
+        // a zero window probe sends the byte at snd_una without advancing snd_nxt,
+        // a peer whose window reopened acknowledges it
+        let ack_limit = if self.probe_outstanding { self.snd_nxt.wrapping_add(1) } else { self.snd_nxt };
+        if seq_lt(self.snd_una, header.ack) && seq_le(header.ack, ack_limit) {
+            self.acknowledge(header.ack);
+        } else if seq_lt(ack_limit, header.ack) {
+            self.send_ack(key);
+            return established;
+        }
+        // segments reordered behind the last window update must not change the window
+        if seq_le(self.snd_una, header.ack)
+            && (seq_lt(self.snd_wl1, header.seq) || (self.snd_wl1 == header.seq && seq_le(self.snd_wl2, header.ack)))
+        {
+            self.update_window(header);
+        }
+
+        let fin_acked = self.fin_seq.map_or(false, |fin_seq| seq_lt(fin_seq, self.snd_una));
+        if fin_acked {
+            match self.state {
+                TcpState::FinWait1 => self.state = TcpState::FinWait2,
+                TcpState::Closing => self.enter_time_wait(),
+                TcpState::LastAck => {
+                    self.state = TcpState::Closed;
+                    self.wake_all();
+                    return established;
+                }
+                _ => {}
+            }
+        }
+
+        let mut ack_needed = false;
+        let receiving = matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        );
+        if receiving && !payload.is_empty() {
+            // only in-order data is accepted, anything else is acknowledged again
+            if seq_le(header.seq, self.rcv_nxt) {
+                let offset = self.rcv_nxt.wrapping_sub(header.seq) as usize;
+                if offset < payload.len() {
+                    let room = RECEIVE_BUFFER_SIZE - self.receive_buffer.len();
+                    let accepted = &payload[offset..offset + min(room, payload.len() - offset)];
+                    self.receive_buffer.extend(accepted.iter());
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted.len() as u32);
+                    if let Some(waker) = self.reader.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            ack_needed = true;
+        }
+
+        if header.flags & FIN != 0 {
+            let fin_seq = header.seq.wrapping_add(payload.len() as u32);
+            if !self.fin_received && fin_seq == self.rcv_nxt {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.fin_received = true;
+                match self.state {
+                    TcpState::Established => self.state = TcpState::CloseWait,
+                    TcpState::FinWait1 => self.state = TcpState::Closing,
+                    TcpState::FinWait2 => self.enter_time_wait(),
+                    _ => {}
+                }
+                self.wake_all();
+            } else if self.state == TcpState::TimeWait {
+                // our last ACK got lost
+                self.enter_time_wait();
+            }
+            ack_needed = true;
+        }
+
+        if ack_needed {
+            self.send_ack(key);
+        }
+        self.output(key);
+        established
+    }
+}
+
+/// Listening port and its connections waiting to be accepted
+struct Listener {
+    backlog: VecDeque<ConnectionKey>,
+    waker: Option<Waker>,
+}
+
+// Lock order: CONNECTIONS before LISTENERS
+static CONNECTIONS: Mutex<BTreeMap<ConnectionKey, Tcb>> = Mutex::new(BTreeMap::new());
+static LISTENERS: Mutex<BTreeMap<u16, Listener>> = Mutex::new(BTreeMap::new());
+
+/// Answers a segment that does not belong to any connection with a reset
+fn send_reset(key: &ConnectionKey, header: &TcpHeader, payload_length: usize) {
+    let (seq, ack, flags) = if header.flags & ACK != 0 {
+        (header.ack, 0, RST)
+    } else {
+        let ack = header.seq.wrapping_add(header.segment_length(payload_length));
+        (0, ack, RST | ACK)
+    };
+    let reset = TcpHeader {
+        src_port: key.local_port,
+        dst_port: key.remote_port,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        urgent_pointer: 0,
+        mss: None,
+    };
+    let segment = reset.to_bytes(netconfig::ipv4_address(), key.remote_addr, &[]);
+    let _ = ipv4::send_packet(key.remote_addr, ipv4::PROTOCOL_TCP, &segment);
+}
+
+/// Handles a TCP segment received in an IPv4 datagram:
+/// - Passes it to the connection it belongs to
+/// - Opens a new connection for a SYN to a listening port
+/// - Resets everything else
+pub fn handle_packet(ip_header: &Ipv4Header, payload: &[u8]) {
+    if ipv4::is_broadcast(ip_header.dst) {
+        netstat::TCP.count_dropped();
+        return;
+    }
+    let (header, data) = match TcpHeader::from_bytes(ip_header.src, ip_header.dst, payload) {
+        Ok(segment) => segment,
+        Err(_) => {
+            netstat::TCP.count_dropped();
+            return;
+        }
+    };
+    netstat::TCP.count_received();
+    let key = ConnectionKey {
+        local_port: header.dst_port,
+        remote_addr: ip_header.src,
+        remote_port: header.src_port,
+    };
+
+    let mut connections = CONNECTIONS.lock();
+    if let Some(tcb) = connections.get_mut(&key) {
+        let established = tcb.on_segment(&key, &header, data);
+        if tcb.state == TcpState::Closed && tcb.detached {
+            connections.remove(&key);
+        }
+        if let Some(port) = established {
+            let mut listeners = LISTENERS.lock();
+            match listeners.get_mut(&port) {
+                Some(listener) => {
+                    listener.backlog.push_back(key);
+                    if let Some(waker) = listener.waker.take() {
+                        waker.wake();
+                    }
+                }
+                None => {
+                    if let Some(tcb) = connections.remove(&key) {
+                        tcb.send(&key, tcb.snd_nxt, RST | ACK, &[]);
+                    }
+                }
+            }
+        }
+        return;
+    }
+
+    if header.flags & RST != 0 {
+        return;
+    }
+    if header.flags & (SYN | ACK) == SYN {
+        let pending = connections
+            .values()
+            .filter(|tcb| tcb.listener == Some(key.local_port))
+            .count();
+        let listening = LISTENERS
+            .lock()
+            .get(&key.local_port)
+            .map_or(false, |listener| listener.backlog.len() + pending < MAX_BACKLOG);
+        if listening {
+            let mut tcb = Tcb::new(TcpState::SynReceived, Some(key.local_port));
+            tcb.rcv_nxt = header.seq.wrapping_add(1);
+            tcb.update_window(&header);
+            tcb.mss = header.mss.map_or(DEFAULT_MSS, |mss| min(mss as usize, MSS));
+            tcb.output(&key);
+            connections.insert(key, tcb);
+            return;
+        }
+    }
+    send_reset(&key, &header, data.len());
+}
+
+/// Drives the retransmission and TIME-WAIT timers of all connections
+///
+/// Has to be spawned as a task for connections to recover from lost segments.
+pub async fn process_timers() {
+    loop {
+        time::sleep(TIMER_INTERVAL).await;
+
+        let now = time::ticks();
+        let mut connections = CONNECTIONS.lock();
+        for (key, tcb) in connections.iter_mut() {
+            if tcb.retransmit_deadline.map_or(false, |deadline| now >= deadline) {
+                tcb.on_timeout(key);
+            }
+            if tcb.time_wait_deadline.map_or(false, |deadline| now >= deadline) {
+                tcb.time_wait_deadline = None;
+                tcb.state = TcpState::Closed;
+                tcb.wake_all();
+            }
+        }
+        connections.retain(|_, tcb| !(tcb.state == TcpState::Closed && tcb.detached));
+    }
+}
+
+/// Socket listening for incoming connections on a local port
+///
+/// The port is released and all connections not accepted yet are reset
+/// when the listener is dropped.
+#[derive(Debug)]
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    /// Starts listening on the given port
+    pub fn bind(port: u16) -> Result<TcpListener, &'static str> {
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return Err("port already in use");
+        }
+        listeners.insert(port, Listener {
+            backlog: VecDeque::new(),
+            waker: None,
+        });
+        Ok(TcpListener { port })
+    }
+
+    /// Returns the local port this listener is bound to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for the next established connection
+    pub async fn accept(&self) -> TcpStream {
+        let key = poll_fn(|cx| {
+            let mut listeners = LISTENERS.lock();
+            let listener = listeners
+                .get_mut(&self.port)
+                .expect("accepting on a port that is not bound");
+            match listener.backlog.pop_front() {
+                Some(key) => Poll::Ready(key),
+                None => {
+                    listener.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+
+        if let Some(tcb) = CONNECTIONS.lock().get_mut(&key) {
+            tcb.detached = false;
+        }
+        TcpStream { key }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock();
+        let backlog = match LISTENERS.lock().remove(&self.port) {
+            Some(listener) => listener.backlog,
+            None => return,
+        };
+        let port = self.port;
+        connections.retain(|key, tcb| {
+            if tcb.listener == Some(port) || backlog.contains(key) {
+                tcb.send(key, tcb.snd_nxt, RST | ACK, &[]);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Returns a free local port for a new outgoing connection
+fn next_ephemeral_port(connections: &BTreeMap<ConnectionKey, Tcb>) -> Result<u16, &'static str> {
+    let listeners = LISTENERS.lock();
+    let ephemeral_port_count = u16::MAX - FIRST_EPHEMERAL_PORT + 1;
+    (0..ephemeral_port_count)
+        .map(|_| {
+            let port = NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed);
+            if port < FIRST_EPHEMERAL_PORT {
+                NEXT_EPHEMERAL_PORT.store(FIRST_EPHEMERAL_PORT + 1, Ordering::Relaxed);
+                FIRST_EPHEMERAL_PORT
+            } else {
+                port
+            }
+        })
+        .find(|port| {
+            !listeners.contains_key(port) && !connections.keys().any(|key| key.local_port == *port)
+        })
+        .ok_or("no free ephemeral port")
+}
+
+/// Reliable byte stream to a remote host
+///
+/// Dropping the stream closes the connection gracefully.
+#[derive(Debug)]
+pub struct TcpStream {
+    key: ConnectionKey,
+}
+
+impl TcpStream {
+    /// Opens a connection to the given address and port
+    /// and waits until the handshake is complete
+    pub async fn connect(addr: [u8; 4], port: u16) -> Result<TcpStream, &'static str> {
+        let key = {
+            let mut connections = CONNECTIONS.lock();
+            let key = ConnectionKey {
+                local_port: next_ephemeral_port(&connections)?,
+                remote_addr: addr,
+                remote_port: port,
+            };
+            let mut tcb = Tcb::new(TcpState::SynSent, None);
+            tcb.output(&key);
+            connections.insert(key, tcb);
+            key
+        };
+        // created before waiting, so the connection is cleaned up if connecting fails
+        let stream = TcpStream { key };
+
+        poll_fn(|cx| {
+            let mut connections = CONNECTIONS.lock();
+            let tcb = match connections.get_mut(&key) {
+                Some(tcb) => tcb,
+                None => return Poll::Ready(Err("connection closed")),
+            };
+            if let Some(error) = tcb.error {
+                return Poll::Ready(Err(error));
+            }
+            match tcb.state {
+                TcpState::SynSent | TcpState::SynReceived => {
+                    tcb.writer = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(Ok(())),
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Returns the address and port of the remote host
+    pub fn peer_addr(&self) -> ([u8; 4], u16) {
+        (self.key.remote_addr, self.key.remote_port)
+    }
+
+    /// Returns the local port of this connection
+    pub fn local_port(&self) -> u16 {
+        self.key.local_port
+    }
+
+    /// Returns the current state of the connection
+    pub fn state(&self) -> TcpState {
+        CONNECTIONS
+            .lock()
+            .get(&self.key)
+            .map_or(TcpState::Closed, |tcb| tcb.state)
+    }
+
+    /// Waits for received data and copies as much of it as fits into `buffer`
+    ///
+    /// Returns the number of bytes read, or 0 once the remote host closed the connection.
+    pub async fn read(&self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let key = self.key;
+        poll_fn(|cx| {
+            let mut connections = CONNECTIONS.lock();
+            let tcb = match connections.get_mut(&key) {
+                Some(tcb) => tcb,
+                None => return Poll::Ready(Err("connection closed")),
+            };
+
+            if !tcb.receive_buffer.is_empty() {
+                let window_before = tcb.receive_window() as usize;
+                let length = min(buffer.len(), tcb.receive_buffer.len());
+                for (byte, received) in buffer.iter_mut().zip(tcb.receive_buffer.drain(..length)) {
+                    *byte = received;
+                }
+                // tell the peer once the window opened up far enough for a full segment
+                if window_before < tcb.mss && tcb.receive_window() as usize >= tcb.mss {
+                    tcb.send_ack(&key);
+                }
+                return Poll::Ready(Ok(length));
+            }
+            if let Some(error) = tcb.error {
+                return Poll::Ready(Err(error));
+            }
+            if tcb.fin_received || tcb.state == TcpState::Closed {
+                return Poll::Ready(Ok(0));
+            }
+            tcb.reader = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Waits for room in the send buffer and queues as much of `data` as fits
+    ///
+    /// Returns the number of bytes queued for sending.
+    pub async fn write(&self, data: &[u8]) -> Result<usize, &'static str> {
+        let key = self.key;
+        poll_fn(|cx| {
+            let mut connections = CONNECTIONS.lock();
+            let tcb = match connections.get_mut(&key) {
+                Some(tcb) => tcb,
+                None => return Poll::Ready(Err("connection closed")),
+            };
+            if let Some(error) = tcb.error {
+                return Poll::Ready(Err(error));
+            }
+            if tcb.fin_queued || !matches!(tcb.state, TcpState::Established | TcpState::CloseWait) {
+                return Poll::Ready(Err("connection closed for sending"));
+            }
+
+            let room = SEND_BUFFER_SIZE - tcb.send_buffer.len();
+            if room == 0 {
+                tcb.writer = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let length = min(room, data.len());
+            tcb.send_buffer.extend(data[..length].iter());
+            tcb.output(&key);
+            Poll::Ready(Ok(length))
+        })
+        .await
+    }
+
+    /// Queues all of `data` for sending, waiting for room in the send buffer as needed
+    pub async fn write_all(&self, mut data: &[u8]) -> Result<(), &'static str> {
+        while !data.is_empty() {
+            let written = self.write(data).await?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+
+    /// Closes the connection for sending, after all queued data has been sent
+    ///
+    /// Received data can still be read until the remote host closes its side.
+    pub fn close(&self) {
+        if let Some(tcb) = CONNECTIONS.lock().get_mut(&self.key) {
+            close_connection(&self.key, tcb);
+        }
+    }
+}
+
+/// Queues a FIN behind the data of the connection, or forgets it if no SYN was answered yet
+fn close_connection(key: &ConnectionKey, tcb: &mut Tcb) {
+    match tcb.state {
+        TcpState::SynSent => tcb.state = TcpState::Closed,
+        TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+            tcb.fin_queued = true;
+            tcb.output(key);
+        }
+        _ => {}
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock();
+        if let Some(tcb) = connections.get_mut(&self.key) {
+            close_connection(&self.key, tcb);
+            tcb.detached = true;
+            if tcb.state == TcpState::Closed {
+                connections.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_segment_round_trip() {
+    let src = [10, 0, 2, 15];
+    let dst = [10, 0, 2, 2];
+    let header = TcpHeader {
+        src_port: 49152,
+        dst_port: 80,
+        seq: 0xfffffff0,
+        ack: 0,
+        flags: SYN,
+        window: RECEIVE_BUFFER_SIZE as u16,
+        urgent_pointer: 0,
+        mss: Some(MSS as u16),
+    };
+    let segment = header.to_bytes(src, dst, b"data");
+    assert_eq!(TcpHeader::from_bytes(src, dst, &segment), Ok((header, &b"data"[..])));
+    assert_eq!(header.segment_length(4), 5);
+}
+
+#[test_case]
+fn test_sequence_number_comparison() {
+    assert!(seq_lt(1, 2));
+    assert!(seq_lt(0xffff_fff0, 0x10));
+    assert!(!seq_lt(0x10, 0xffff_fff0));
+    assert!(seq_le(7, 7));
+}
+
+#[test_case]
+fn test_zero_window_probe_accepted() {
+    let key = ConnectionKey { local_port: 49152, remote_addr: [10, 0, 2, 2], remote_port: 80 };
+    let mut tcb = Tcb::new(TcpState::Established, None);
+    tcb.send_buffer.extend(b"abc");
+    tcb.on_timeout(&key);
+    assert_eq!(tcb.snd_nxt, tcb.snd_una);
+    assert!(tcb.probe_outstanding);
+
+    // the peer's window reopened and it took the probe byte
+    let header = TcpHeader {
+        src_port: key.remote_port,
+        dst_port: key.local_port,
+        seq: tcb.rcv_nxt,
+        ack: tcb.iss.wrapping_add(1),
+        flags: ACK,
+        window: 2,
+        urgent_pointer: 0,
+        mss: None,
+    };
+    tcb.on_segment(&key, &header, &[]);
+    assert_eq!(tcb.snd_una, tcb.iss.wrapping_add(1));
+    assert_eq!(tcb.snd_wnd, 2);
+    assert!(!tcb.probe_outstanding);
+    assert_eq!(tcb.send_buffer, b"bc");
+    // the rest fits into the window and was sent
+    assert_eq!(tcb.snd_nxt, tcb.iss.wrapping_add(3));
+}
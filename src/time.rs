@@ -0,0 +1,147 @@
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::{
+    future::{self, Either},
+    pin_mut,
+};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// Frequency the PIT is programmed to, so one tick is one millisecond
+pub const TICKS_PER_SECOND: u64 = 1000;
+
+// Programmable Interval Timer
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 0, lobyte/hibyte access, mode 3 (square wave generator)
+const PIT_SQUARE_WAVE: u8 = 0x36;
+
+// Timer interrupts since the PIT was programmed
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Ids, deadlines and wakers of all pending Sleep futures
+//
+// Sleep futures remove their entry when dropped, so the wakers in here are never
+// the last reference to a task and the timer interrupt never frees memory.
+static SLEEPERS: Mutex<Vec<(u64, u64, Waker)>> = Mutex::new(Vec::new());
+static NEXT_SLEEPER: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 of the PIT to fire TICKS_PER_SECOND timer interrupts
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_SQUARE_WAVE);
+        let mut channel_0 = Port::<u8>::new(PIT_CHANNEL_0);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called by the timer interrupt handler
+///
+/// Must not block or allocate.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        sleepers.retain(|(_, deadline, waker)| {
+            if *deadline <= now {
+                waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Returns the number of timer ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts milliseconds into timer ticks
+pub fn ticks_from_millis(millis: u64) -> u64 {
+    millis * TICKS_PER_SECOND / 1000
+}
+
+/// Converts timer ticks into milliseconds
+pub fn millis_from_ticks(ticks: u64) -> u64 {
+    ticks * 1000 / TICKS_PER_SECOND
+}
+
+/// Future that completes once the given tick count is reached
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+    registered: bool,
+}
+
+/// Returns a future that completes after `ticks` timer ticks
+pub fn sleep(ticks: u64) -> Sleep {
+    sleep_until(self::ticks() + ticks)
+}
+
+/// Returns a future that completes once `ticks()` reaches `deadline`
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        id: NEXT_SLEEPER.fetch_add(1, Ordering::Relaxed),
+        deadline,
+        registered: false,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        if !self.registered {
+            let (id, deadline) = (self.id, self.deadline);
+            interrupts::without_interrupts(|| {
+                SLEEPERS.lock().push((id, deadline, cx.waker().clone()));
+            });
+            self.registered = true;
+        }
+
+        // the deadline might have passed while the waker was registered
+        if ticks() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    /// Removes the waker if the timer interrupt has not woken it yet,
+    /// e.g. when a `timeout` completes early
+    fn drop(&mut self) {
+        if self.registered {
+            let id = self.id;
+            interrupts::without_interrupts(|| {
+                SLEEPERS.lock().retain(|(sleeper, _, _)| *sleeper != id);
+            });
+        }
+    }
+}
+
+/// Runs `future` for at most `ticks` timer ticks
+///
+/// Returns `None` if the future did not complete in time.
+pub async fn timeout<F: Future>(ticks: u64, future: F) -> Option<F::Output> {
+    let sleep = sleep(ticks);
+    pin_mut!(future);
+    match future::select(future, sleep).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{icmp, ipv4, ipv4::Ipv4Header, netconfig, netstat, println};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU16, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+/// Size of the UDP header in bytes
+const HEADER_SIZE: usize = 8;
+/// Largest payload that fits into a single IPv4 datagram
+pub const MAX_PAYLOAD_SIZE: usize = 65535 - ipv4::HEADER_SIZE - HEADER_SIZE;
+/// Number of datagrams a socket holds before further ones are dropped
+const MAX_QUEUED_DATAGRAMS: usize = 32;
+
+// Ports handed out to sockets bound to port 0
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(FIRST_EPHEMERAL_PORT);
+
+/// Port the echo server listens on, matching the `hostfwd` of the run-args in Cargo.toml
+pub const ECHO_PORT: u16 = 8822;
+
+/// UDP header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// Length of header and payload in bytes
+    pub length: u16,
+    pub checksum: u16,
+}
+
+impl UdpHeader {
+    // returns the header in bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::with_capacity(HEADER_SIZE);
+        result.extend_from_slice(&self.src_port.to_be_bytes());
+        result.extend_from_slice(&self.dst_port.to_be_bytes());
+        result.extend_from_slice(&self.length.to_be_bytes());
+        result.extend_from_slice(&self.checksum.to_be_bytes());
+
+        result
+    }
+
+    /// Parses a UDP header and validates the checksum of the datagram,
+    /// if the sender computed one
+    ///
+    /// Returns the header and the payload behind it.
+    pub fn from_bytes(src: [u8; 4], dst: [u8; 4], bytes: &[u8]) -> Result<(Self, &[u8]), &'static str> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("UDP datagram too short");
+        }
+        let header = UdpHeader {
+            src_port: u16::from_be_bytes([bytes[0], bytes[1]]),
+            dst_port: u16::from_be_bytes([bytes[2], bytes[3]]),
+            length: u16::from_be_bytes([bytes[4], bytes[5]]),
+            checksum: u16::from_be_bytes([bytes[6], bytes[7]]),
+        };
+        let length = header.length as usize;
+        if length < HEADER_SIZE || length > bytes.len() {
+            return Err("invalid UDP length");
+        }
+        if header.checksum != 0
+            && ipv4::pseudo_header_checksum(src, dst, ipv4::PROTOCOL_UDP, &bytes[..length]) != 0
+        {
+            return Err("invalid UDP checksum");
+        }
+        Ok((header, &bytes[HEADER_SIZE..length]))
+    }
+}
+
+/// Builds a complete UDP datagram including its checksum
+pub fn build_datagram(src: [u8; 4], src_port: u16, dst: [u8; 4], dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let header = UdpHeader {
+        src_port,
+        dst_port,
+        length: (HEADER_SIZE + payload.len()) as u16,
+        checksum: 0,
+    };
+    let mut datagram = header.to_bytes();
+    datagram.extend_from_slice(payload);
+
+    // a computed checksum of 0 is sent as all ones, as 0 means "no checksum"
+    let checksum = match ipv4::pseudo_header_checksum(src, dst, ipv4::PROTOCOL_UDP, &datagram) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+/// Returns the next port of the ephemeral range, wrapping around at its end
+fn next_ephemeral_port() -> u16 {
+    let port = NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed);
+    if port < FIRST_EPHEMERAL_PORT {
+        NEXT_EPHEMERAL_PORT.store(FIRST_EPHEMERAL_PORT + 1, Ordering::Relaxed);
+        FIRST_EPHEMERAL_PORT
+    } else {
+        port
+    }
+}
+
+/// Datagram received on a bound port
+struct Datagram {
+    payload: Vec<u8>,
+    src: [u8; 4],
+    src_port: u16,
+}
+
+/// Receive state of a bound port
+struct Socket {
+    datagrams: VecDeque<Datagram>,
+    waker: Option<Waker>,
+}
+
+// Bound ports and their received datagrams
+static SOCKETS: Mutex<BTreeMap<u16, Socket>> = Mutex::new(BTreeMap::new());
+
+/// Handles a UDP datagram received in an IPv4 datagram
+/// by queueing it on the socket bound to its destination port
+pub fn handle_packet(header: &Ipv4Header, payload: &[u8]) {
+    let (udp_header, data) = match UdpHeader::from_bytes(header.src, header.dst, payload) {
+        Ok(datagram) => datagram,
+        Err(_) => {
+            netstat::UDP.count_dropped();
+            return;
+        }
+    };
+
+    let mut sockets = SOCKETS.lock();
+    match sockets.get_mut(&udp_header.dst_port) {
+        Some(socket) => {
+            if socket.datagrams.len() < MAX_QUEUED_DATAGRAMS {
+                socket.datagrams.push_back(Datagram {
+                    payload: Vec::from(data),
+                    src: header.src,
+                    src_port: udp_header.src_port,
+                });
+                netstat::UDP.count_received();
+            } else {
+                netstat::UDP.count_dropped();
+            }
+            if let Some(waker) = socket.waker.take() {
+                waker.wake();
+            }
+        }
+        None => {
+            drop(sockets);
+            netstat::UDP.count_dropped();
+            icmp::send_destination_unreachable(header, payload, icmp::CODE_PORT_UNREACHABLE);
+        }
+    }
+}
+
+/// UDP socket bound to a local port
+///
+/// The port is released when the socket is dropped.
+#[derive(Debug)]
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds a socket to the given port, or to a free ephemeral port if `port` is 0
+    pub fn bind(port: u16) -> Result<UdpSocket, &'static str> {
+        let mut sockets = SOCKETS.lock();
+        let port = if port == 0 {
+            let ephemeral_port_count = u16::MAX - FIRST_EPHEMERAL_PORT + 1;
+            (0..ephemeral_port_count)
+                .map(|_| next_ephemeral_port())
+                .find(|port| !sockets.contains_key(port))
+                .ok_or("no free ephemeral port")?
+        } else if sockets.contains_key(&port) {
+            return Err("port already in use");
+        } else {
+            port
+        };
+
+        sockets.insert(port, Socket {
+            datagrams: VecDeque::new(),
+            waker: None,
+        });
+        Ok(UdpSocket { port })
+    }
+
+    /// Returns the local port this socket is bound to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends the payload to the given address and port
+    ///
+    /// Waits until the MAC address of the next hop is resolved
+    /// and the interface has room for every fragment.
+    pub async fn send_to(&self, payload: &[u8], addr: [u8; 4], port: u16) -> Result<(), &'static str> {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err("payload too large for a UDP datagram");
+        }
+        let datagram = build_datagram(netconfig::ipv4_address(), self.port, addr, port, payload);
+        ipv4::send_packet_waiting(addr, ipv4::PROTOCOL_UDP, &datagram).await
+    }
+
+    /// Waits for the next datagram on this socket
+    ///
+    /// Returns its payload and the address and port it was sent from.
+    pub async fn recv_from(&self) -> (Vec<u8>, [u8; 4], u16) {
+        let datagram = RecvFrom { port: self.port }.await;
+        (datagram.payload, datagram.src, datagram.src_port)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Future that completes with the next datagram received on the given port
+struct RecvFrom {
+    port: u16,
+}
+
+impl Future for RecvFrom {
+    type Output = Datagram;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Datagram> {
+        let mut sockets = SOCKETS.lock();
+        let socket = sockets
+            .get_mut(&self.port)
+            .expect("receiving on a port that is not bound");
+        match socket.datagrams.pop_front() {
+            Some(datagram) => Poll::Ready(datagram),
+            None => {
+                socket.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Sends every datagram received on the given port back to its sender,
+/// e.g. for testing with `nc -u localhost 8822` on the host
+pub async fn echo_server(port: u16) {
+    let socket = match UdpSocket::bind(port) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("UDP echo server: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        let (payload, addr, port) = socket.recv_from().await;
+        if let Err(err) = socket.send_to(&payload, addr, port).await {
+            println!("UDP echo server: {}", err);
+        }
+    }
+}
+
+#[test_case]
+fn test_datagram_round_trip() {
+    let src = [10, 0, 2, 15];
+    let dst = [10, 0, 2, 2];
+    let datagram = build_datagram(src, 1234, dst, ECHO_PORT, b"hello");
+
+    let (header, payload) = UdpHeader::from_bytes(src, dst, &datagram).unwrap();
+    assert_eq!(header.src_port, 1234);
+    assert_eq!(header.dst_port, ECHO_PORT);
+    assert_eq!(payload, b"hello");
+    assert!(UdpHeader::from_bytes(src, [10, 0, 2, 3], &datagram).is_err());
+}
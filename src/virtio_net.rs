@@ -0,0 +1,524 @@
+#![allow(dead_code)]
+
+use crate::{
+    println,
+    pci,
+    memory::DmaBuffer,
+    interrupts,
+    netdev::{self, FrameQueue, FrameStream, LinkStatus, NetDevice, NetDeviceStats, TransmitQueue},
+};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{fence, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+use spin::Mutex;
+
+// Register of the legacy interface in the I/O space
+const DEVICE_FEATURES: u8 = 0x00;
+const DRIVER_FEATURES: u8 = 0x04;
+const QUEUE_ADDRESS: u8 = 0x08;
+const QUEUE_SIZE: u8 = 0x0c;
+const QUEUE_SELECT: u8 = 0x0e;
+const QUEUE_NOTIFY: u8 = 0x10;
+const DEVICE_STATUS: u8 = 0x12;
+const ISR_STATUS: u8 = 0x13;
+// Device specific configuration, directly behind the common registers while MSI-X is disabled
+const CONFIG_MAC: u8 = 0x14;
+const CONFIG_STATUS: u8 = 0x1a;
+
+// DeviceStatus
+const ACKNOWLEDGE: u8 = 0x01;
+const DRIVER: u8 = 0x02;
+const DRIVER_OK: u8 = 0x04;
+const FAILED: u8 = 0x80;
+
+// Feature
+/// The device has a MAC address in its configuration
+const FEATURE_MAC: u32 = 1 << 5;
+/// The device reports the link status in its configuration
+const FEATURE_STATUS: u32 = 1 << 16;
+
+// IsrStatus
+const ISR_QUEUE: u8 = 0x01;
+const ISR_CONFIG_CHANGE: u8 = 0x02;
+
+// ConfigStatus
+const LINK_UP: u16 = 0x0001;
+
+// DescriptorFlag
+/// The device writes into the buffer instead of reading it
+const DESCRIPTOR_WRITE: u16 = 0x0002;
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+/// Device id of a transitional virtio-net device, which offers the legacy interface
+const VIRTIO_NET_DEVICE_ID: u16 = 0x1000;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+/// The legacy interface places the used ring on its own page
+const QUEUE_ALIGNMENT: usize = 4096;
+/// Size of the virtio_net_hdr in front of every frame, without merged receive buffers
+const NET_HEADER_SIZE: usize = 10;
+/// Size of every receive and transmit buffer, a frame and its virtio_net_hdr fit into it
+const BUFFER_SIZE: usize = 2048;
+/// Largest number of buffers per queue, queues the device offers may be larger
+const MAX_BUFFERS: u16 = 64;
+
+/// Number of received frames that can wait in the frame queue for a task to pick them up
+const FRAME_QUEUE_SIZE: usize = 32;
+/// Largest frame (without CRC) that is passed on to the frame queue
+const MAX_FRAME_SIZE: usize = 1514;
+/// Smallest frame (without CRC) that is passed on to the frame queue
+const MIN_RECEIVE_SIZE: usize = 14;
+
+// The initialized virtio-net device, if one was found
+static DEVICE: OnceCell<VirtioNet> = OnceCell::uninit();
+
+/// Entry of the descriptor table of a virtqueue
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Virtqueue in the legacy layout: descriptor table and available ring,
+/// followed by the used ring on the next page boundary
+///
+/// Every descriptor owns one buffer of BUFFER_SIZE bytes.
+struct Virtqueue {
+    index: u16,
+    size: u16,
+    ring: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    buffers: DmaBuffer,
+    buffer_count: u16,
+    // Index of the next entry of the available ring
+    next_available: u16,
+    // Index of the next entry of the used ring to be processed
+    next_used: u16,
+}
+
+impl Virtqueue {
+    /// Allocates the rings for a queue of `size` entries and `buffer_count` buffers
+    fn new(index: u16, size: u16, buffer_count: u16, flags: u16) -> Option<Virtqueue> {
+        let avail_offset = size as usize * core::mem::size_of::<Descriptor>();
+        let used_offset = align_up(avail_offset + 6 + 2 * size as usize);
+        let ring = DmaBuffer::new(used_offset + align_up(6 + 8 * size as usize))?;
+        let buffers = DmaBuffer::new(buffer_count as usize * BUFFER_SIZE)?;
+
+        let mut queue = Virtqueue {
+            index,
+            size,
+            ring,
+            avail_offset,
+            used_offset,
+            buffers,
+            buffer_count,
+            next_available: 0,
+            next_used: 0,
+        };
+        for id in 0..buffer_count {
+            queue.set_descriptor(id, BUFFER_SIZE as u32, flags);
+        }
+        Some(queue)
+    }
+
+    /// Returns the page frame number the device expects in QUEUE_ADDRESS
+    fn page_frame_number(&self) -> u32 {
+        (self.ring.phys_addr().as_u64() / QUEUE_ALIGNMENT as u64) as u32
+    }
+
+    fn ring_ptr(&mut self, offset: usize) -> *mut u8 {
+        self.ring.as_mut_slice()[offset..].as_mut_ptr()
+    }
+
+    /// Points the descriptor to its buffer
+    fn set_descriptor(&mut self, id: u16, len: u32, flags: u16) {
+        let addr = self.buffers.phys_addr().as_u64() + id as u64 * BUFFER_SIZE as u64;
+        let descriptor = self.ring_ptr(id as usize * core::mem::size_of::<Descriptor>()) as *mut Descriptor;
+        unsafe { write_volatile(descriptor, Descriptor { addr, len, flags, next: 0 }) };
+    }
+
+    /// Adds the descriptor to the available ring, the device sees it after `publish`
+    fn make_available(&mut self, id: u16) {
+        let entry = self.avail_offset + 4 + 2 * (self.next_available % self.size) as usize;
+        unsafe { write_volatile(self.ring_ptr(entry) as *mut u16, id) };
+        self.next_available = self.next_available.wrapping_add(1);
+    }
+
+    /// Hands the descriptors added with `make_available` to the device
+    fn publish(&mut self) {
+        let next_available = self.next_available;
+        let index = self.ring_ptr(self.avail_offset + 2) as *mut u16;
+        // the entries must be visible before the index that covers them
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(index, next_available) };
+        fence(Ordering::SeqCst);
+    }
+
+    /// Returns the id of the next descriptor the device is done with
+    /// and the number of bytes it wrote into the buffer
+    fn pop_used(&mut self) -> Option<(u16, usize)> {
+        let used_index = unsafe { read_volatile(self.ring_ptr(self.used_offset + 2) as *const u16) };
+        if used_index == self.next_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let entry = self.used_offset + 4 + 8 * (self.next_used % self.size) as usize;
+        let id = unsafe { read_volatile(self.ring_ptr(entry) as *const u32) } as u16;
+        let len = unsafe { read_volatile(self.ring_ptr(entry + 4) as *const u32) } as usize;
+        self.next_used = self.next_used.wrapping_add(1);
+        Some((id, len))
+    }
+
+    fn buffer(&self, id: u16) -> &[u8] {
+        let start = id as usize * BUFFER_SIZE;
+        &self.buffers.as_slice()[start..start + BUFFER_SIZE]
+    }
+
+    fn buffer_mut(&mut self, id: u16) -> &mut [u8] {
+        let start = id as usize * BUFFER_SIZE;
+        &mut self.buffers.as_mut_slice()[start..start + BUFFER_SIZE]
+    }
+}
+
+/// Rounds up to the next QUEUE_ALIGNMENT boundary
+fn align_up(size: usize) -> usize {
+    (size + QUEUE_ALIGNMENT - 1) & !(QUEUE_ALIGNMENT - 1)
+}
+
+/// Virtio network card, driven through the legacy interface of a transitional device
+pub struct VirtioNet {
+    io_base: u16,
+    mac_address: [u8; 6],
+    // Whether the device reports its link status
+    has_link_status: bool,
+    // Only locked by the interrupt handler once the device is running
+    receive_queue: Mutex<Virtqueue>,
+    transmitter: Mutex<Transmitter>,
+    // Frames waiting for a transmit descriptor
+    transmit_queue: TransmitQueue,
+    // Received frames waiting for the receive stream
+    frames: FrameQueue,
+    transmitted_frames: AtomicU64,
+    transmitted_bytes: AtomicU64,
+    transmit_errors: AtomicU64,
+    receive_errors: AtomicU64,
+}
+
+/// Transmit queue and the descriptors that are not in use
+struct Transmitter {
+    queue: Virtqueue,
+    // Descriptors not handed to the device, with room for all of them
+    free: Vec<u16>,
+    // Tasks waiting for a free descriptor
+    waiters: Vec<Waker>,
+}
+
+/// Initializes the virtio-net device, if it exists, with:
+/// - Bus Mastering and I/O-Space-Access
+/// - Reset and feature negotiation
+/// - Setting up the receive and transmit queues
+/// - Reading the MAC address
+/// - Registering its Interrupt Line for the IDT
+/// - Registering it as a network interface
+pub fn init() {
+    let pci_device = match pci::get_pci_device_id(VIRTIO_VENDOR_ID, VIRTIO_NET_DEVICE_ID) {
+        Some(pci_device) => pci_device,
+        None => return,
+    };
+    println!("Beginning initialisation of virtio-net!");
+
+    let io_base = match pci_device.determine_iobase(0) {
+        Ok(io_base) => io_base as u16,
+        Err(err) => {
+            println!("Aborting virtio-net initialisation: {}", err);
+            return;
+        }
+    };
+    pci_device.pci_set_command_register_bit(pci::BUS_MASTER);
+    pci_device.pci_set_command_register_bit(pci::IO_SPACE);
+
+    let io_write_8 = |offset: u8, value: u8| unsafe { Port::new(io_base + offset as u16).write(value) };
+    let io_read_8 = |offset: u8| -> u8 { unsafe { Port::new(io_base + offset as u16).read() } };
+
+    // writing 0 resets the device
+    io_write_8(DEVICE_STATUS, 0);
+    io_write_8(DEVICE_STATUS, ACKNOWLEDGE);
+    io_write_8(DEVICE_STATUS, ACKNOWLEDGE | DRIVER);
+
+    let device_features: u32 = unsafe { Port::new(io_base + DEVICE_FEATURES as u16).read() };
+    let features = device_features & (FEATURE_MAC | FEATURE_STATUS);
+    unsafe { Port::new(io_base + DRIVER_FEATURES as u16).write(features) };
+
+    let queues = (
+        setup_queue(io_base, RECEIVE_QUEUE, DESCRIPTOR_WRITE),
+        setup_queue(io_base, TRANSMIT_QUEUE, 0),
+    );
+    let (mut receive_queue, transmit_queue) = match queues {
+        (Some(receive_queue), Some(transmit_queue)) => (receive_queue, transmit_queue),
+        _ => {
+            io_write_8(DEVICE_STATUS, FAILED);
+            println!("Aborting virtio-net initialisation: could not set up the virtqueues");
+            return;
+        }
+    };
+
+    let mut mac_address = [0; 6];
+    if (features & FEATURE_MAC) != 0 {
+        for (i, byte) in mac_address.iter_mut().enumerate() {
+            *byte = io_read_8(CONFIG_MAC + i as u8);
+        }
+    } else {
+        // locally administered address
+        mac_address = [0x02, 0x00, 0x00, 0x00, 0x00, 0x02];
+    }
+
+    for id in 0..receive_queue.buffer_count {
+        receive_queue.make_available(id);
+    }
+    receive_queue.publish();
+
+    let free = (0..transmit_queue.buffer_count).rev().collect();
+    let device = VirtioNet {
+        io_base,
+        mac_address,
+        has_link_status: (features & FEATURE_STATUS) != 0,
+        receive_queue: Mutex::new(receive_queue),
+        transmitter: Mutex::new(Transmitter {
+            queue: transmit_queue,
+            free,
+            waiters: Vec::new(),
+        }),
+        transmit_queue: TransmitQueue::new(),
+        frames: FrameQueue::new(FRAME_QUEUE_SIZE, MAX_FRAME_SIZE),
+        transmitted_frames: AtomicU64::new(0),
+        transmitted_bytes: AtomicU64::new(0),
+        transmit_errors: AtomicU64::new(0),
+        receive_errors: AtomicU64::new(0),
+    };
+    interrupts::regiser_interrupt("VirtioNet", pci_device.int_line);
+
+    io_write_8(DEVICE_STATUS, ACKNOWLEDGE | DRIVER | DRIVER_OK);
+    device.notify(RECEIVE_QUEUE);
+
+    let device = DEVICE.get_or_init(move || device);
+    let id = netdev::register(device);
+    println!("virtio-net init complete, registered as interface {}", id);
+}
+
+/// Allocates the given virtqueue and tells the device where it is
+fn setup_queue(io_base: u16, index: u16, flags: u16) -> Option<Virtqueue> {
+    unsafe { Port::new(io_base + QUEUE_SELECT as u16).write(index) };
+    let size: u16 = unsafe { Port::new(io_base + QUEUE_SIZE as u16).read() };
+    if size == 0 {
+        return None;
+    }
+
+    let queue = Virtqueue::new(index, size, size.min(MAX_BUFFERS), flags)?;
+    unsafe { Port::new(io_base + QUEUE_ADDRESS as u16).write(queue.page_frame_number()) };
+    Some(queue)
+}
+
+/// Returns the initialized virtio-net device, if one was found
+pub fn device() -> Option<&'static VirtioNet> {
+    DEVICE.try_get().ok()
+}
+
+/// Handles the interrupt of the virtio-net device, if it was initialized
+/// To be called by a handler function in interrupts.rs
+pub fn handle_interrupt() {
+    if let Some(device) = device() {
+        device.handle_interrupt();
+    }
+}
+
+impl VirtioNet {
+    fn handle_interrupt(&self) {
+        // reading the ISR status acknowledges the interrupt
+        let status = self.io_read_8(ISR_STATUS);
+
+        if (status & ISR_QUEUE) != 0 {
+            self.receive_frames();
+            self.release_descriptors();
+        }
+        if (status & ISR_CONFIG_CHANGE) != 0 {
+            netdev::notify_link_change();
+        }
+    }
+
+    /// Copies the frames the device received into the frame queue
+    /// and hands their buffers back to the device
+    fn receive_frames(&self) {
+        let mut queue = self.receive_queue.lock();
+        let mut returned = false;
+
+        while let Some((id, len)) = queue.pop_used() {
+            if len < NET_HEADER_SIZE + MIN_RECEIVE_SIZE || len > BUFFER_SIZE {
+                self.receive_errors.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.frames.push(&queue.buffer(id)[NET_HEADER_SIZE..len]);
+            }
+            queue.make_available(id);
+            returned = true;
+        }
+
+        if returned {
+            queue.publish();
+            self.notify(RECEIVE_QUEUE);
+        }
+    }
+
+    /// Takes back the transmit descriptors the device is done with
+    fn release_descriptors(&self) {
+        let mut transmitter = self.transmitter.lock();
+        while let Some((id, _)) = transmitter.queue.pop_used() {
+            transmitter.free.push(id);
+        }
+        for waker in transmitter.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Sends a frame, waiting for room in the transmit queue while all transmit descriptors are in use
+    pub async fn send(&self, frame: &[u8]) -> Result<(), &'static str> {
+        netdev::send_frame(self, frame).await
+    }
+
+    /// Copies the frame behind an empty virtio_net_hdr into a free transmit buffer
+    /// and hands it to the device
+    ///
+    /// Returns false if all transmit descriptors are in use,
+    /// after registering the waker to be woken once one is released.
+    fn try_send(&self, frame: &[u8], waker: Option<&Waker>) -> Result<bool, &'static str> {
+        if frame.len() > BUFFER_SIZE - NET_HEADER_SIZE {
+            return Err("frame too large for the transmit buffer");
+        }
+
+        without_interrupts(|| {
+            let mut transmitter = self.transmitter.lock();
+            // descriptors may be done without an interrupt having been handled yet
+            while let Some((id, _)) = transmitter.queue.pop_used() {
+                transmitter.free.push(id);
+            }
+
+            let id = match transmitter.free.pop() {
+                Some(id) => id,
+                None => {
+                    if let Some(waker) = waker {
+                        if !transmitter.waiters.iter().any(|waiter| waiter.will_wake(waker)) {
+                            transmitter.waiters.push(waker.clone());
+                        }
+                    }
+                    return Ok(false);
+                }
+            };
+
+            let length = NET_HEADER_SIZE + frame.len();
+            let buffer = transmitter.queue.buffer_mut(id);
+            buffer[..NET_HEADER_SIZE].fill(0);
+            buffer[NET_HEADER_SIZE..length].copy_from_slice(frame);
+            transmitter.queue.set_descriptor(id, length as u32, 0);
+            transmitter.queue.make_available(id);
+            transmitter.queue.publish();
+            self.notify(TRANSMIT_QUEUE);
+
+            self.transmitted_frames.fetch_add(1, Ordering::Relaxed);
+            self.transmitted_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+            Ok(true)
+        })
+    }
+
+    /// Tells the device that the given queue has new available buffers
+    fn notify(&self, queue: u16) {
+        self.io_write_16(QUEUE_NOTIFY, queue);
+    }
+
+    // Returns 8-Bit data from the specified offset inside the IO-Space of the device
+    fn io_read_8(&self, offset: u8) -> u8 {
+        unsafe { Port::new(self.io_base + offset as u16).read() }
+    }
+
+    // Returns 16-Bit data from the specified offset inside the IO-Space of the device
+    fn io_read_16(&self, offset: u8) -> u16 {
+        unsafe { Port::new(self.io_base + offset as u16).read() }
+    }
+
+    // Writes 16-Bit data to the specified offset inside the IO-Space of the device
+    fn io_write_16(&self, offset: u8, value: u16) {
+        unsafe { Port::new(self.io_base + offset as u16).write(value) }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    /// Sends the frame if a transmit descriptor is free, queues it otherwise
+    fn send_frame(&self, frame: &[u8]) -> Result<(), &'static str> {
+        let result = self.transmit_queue.send(frame, |frame, waker| self.try_send(frame, waker));
+        if result.is_err() {
+            self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Sends the frame if a transmit descriptor is free, queues it otherwise,
+    /// pending while the transmit queue is full
+    fn poll_send_frame(&self, frame: &[u8], cx: &mut Context) -> Poll<Result<(), &'static str>> {
+        let result = self.transmit_queue.poll_send(frame, cx, |frame, waker| self.try_send(frame, waker));
+        if let Poll::Ready(Err(_)) = result {
+            self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Sends queued frames as the interrupt handler releases transmit descriptors
+    fn flush_transmit_queue(&self, waker: &Waker) {
+        self.transmit_queue.flush(
+            waker,
+            |frame, waker| self.try_send(frame, waker),
+            |_| {
+                self.transmit_errors.fetch_add(1, Ordering::Relaxed);
+            },
+        );
+    }
+
+    fn receive_stream(&'static self) -> FrameStream {
+        self.frames.stream()
+    }
+
+    /// Without the status feature the link is always up
+    fn link_status(&self) -> LinkStatus {
+        if !self.has_link_status || (self.io_read_16(CONFIG_STATUS) & LINK_UP) != 0 {
+            LinkStatus::Up
+        } else {
+            LinkStatus::Down
+        }
+    }
+
+    fn stats(&self) -> NetDeviceStats {
+        let mut stats = NetDeviceStats {
+            tx_frames: self.transmitted_frames.load(Ordering::Relaxed),
+            tx_bytes: self.transmitted_bytes.load(Ordering::Relaxed),
+            tx_errors: self.transmit_errors.load(Ordering::Relaxed),
+            rx_errors: self.receive_errors.load(Ordering::Relaxed),
+            ..NetDeviceStats::default()
+        };
+        self.frames.add_stats(&mut stats);
+        stats
+    }
+}
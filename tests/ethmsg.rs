@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::{
+    allocator,
+    ethernet::{self, EthernetFrameRef},
+    ethmsg::{self, Message, Packet},
+    loopback,
+    netdev::{self, FrameStream, NetDevice},
+    time,
+};
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use futures_util::{future::FutureExt, stream::StreamExt};
+use spin::Mutex;
+
+entry_point!(main);
+
+// Receive stream of the loopback interface, shared by all tests
+static FRAMES: OnceCell<Mutex<FrameStream>> = OnceCell::uninit();
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init(boot_info);
+    let id = loopback::init();
+    let device: &'static dyn NetDevice = netdev::interface(id).unwrap();
+    FRAMES.init_once(|| Mutex::new(device.receive_stream()));
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Returns the next received frame, if one is queued
+fn receive() -> Option<Vec<u8>> {
+    FRAMES.try_get().unwrap().lock().next().now_or_never().flatten()
+}
+
+/// Sends the payload as a request over the loopback interface,
+/// lets `ethmsg` answer it and returns the decoded response
+fn query(payload: Vec<u8>) -> Option<Packet> {
+    ethernet::send_payload(ethernet::BROADCAST_MAC, ethmsg::ETHERTYPE_ETHMSG, payload).unwrap();
+    let request = receive().unwrap();
+    ethmsg::handle_frame(&EthernetFrameRef::parse(&request).unwrap());
+
+    let response = receive()?;
+    let frame = EthernetFrameRef::parse(&response).unwrap();
+    assert_eq!(frame.header.dst_mac, loopback::MAC_ADDRESS);
+    assert_eq!(frame.header.protocol, ethmsg::ETHERTYPE_ETHMSG);
+    Some(Packet::from_bytes(frame.payload).unwrap())
+}
+
+#[test_case]
+fn uptime_response() {
+    let before = time::millis_from_ticks(time::ticks());
+    let response = query(Packet::new(1, Message::UptimeRequest).to_bytes()).unwrap();
+    assert_eq!(response.id, 1);
+    match response.message {
+        Message::Uptime { millis } => assert!(millis >= before),
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[test_case]
+fn heap_usage_response() {
+    let response = query(Packet::new(2, Message::HeapUsageRequest).to_bytes()).unwrap();
+    assert_eq!(response.id, 2);
+    match response.message {
+        Message::HeapUsage { size, used } => {
+            assert_eq!(size, allocator::HEAP_SIZE as u64);
+            assert!(used > 0 && used < size);
+        }
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[test_case]
+fn pci_devices_response() {
+    let response = query(Packet::new(3, Message::PciDevicesRequest).to_bytes()).unwrap();
+    assert_eq!(response.id, 3);
+    match response.message {
+        // the host bridge of QEMU's i440FX machine is always present
+        Message::PciDevices(devices) => assert!(devices
+            .iter()
+            .any(|device| device.vendor_id == 0x8086 && device.device_id == 0x1237)),
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[test_case]
+fn unknown_request_answered_with_error() {
+    let response = query(alloc::vec![ethmsg::VERSION, 0x42, 0, 4, 0, 0]).unwrap();
+    assert_eq!(response, Packet::new(4, Message::Error(ethmsg::ERROR_UNKNOWN_REQUEST)));
+}
+
+#[test_case]
+fn malformed_request_answered_with_error() {
+    // uptime requests have no body
+    let response = query(alloc::vec![ethmsg::VERSION, ethmsg::KIND_UPTIME, 0, 5, 0, 1, 0xff]).unwrap();
+    assert_eq!(response, Packet::new(5, Message::Error(ethmsg::ERROR_MALFORMED)));
+}
+
+#[test_case]
+fn responses_and_empty_frames_ignored() {
+    assert_eq!(query(Packet::new(6, Message::Uptime { millis: 1 }).to_bytes()), None);
+    assert_eq!(query(Vec::new()), None);
+}
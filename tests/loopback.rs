@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::{
+    ethernet,
+    loopback,
+    netdev::{self, FrameStream, InterfaceId, LinkStatus, NetDevice},
+};
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use futures_util::{future::FutureExt, stream::StreamExt};
+use spin::Mutex;
+
+entry_point!(main);
+
+// The loopback interface and its receive stream, shared by all tests
+static INTERFACE: OnceCell<InterfaceId> = OnceCell::uninit();
+static FRAMES: OnceCell<Mutex<FrameStream>> = OnceCell::uninit();
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init(boot_info);
+    let id = loopback::init();
+    INTERFACE.init_once(|| id);
+    FRAMES.init_once(|| Mutex::new(device().receive_stream()));
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn device() -> &'static dyn NetDevice {
+    netdev::interface(*INTERFACE.try_get().unwrap()).unwrap()
+}
+
+/// Returns the next received frame, if one is queued
+fn receive() -> Option<Vec<u8>> {
+    FRAMES.try_get().unwrap().lock().next().now_or_never().flatten()
+}
+
+#[test_case]
+fn registered_as_default_interface() {
+    // the test-args of Cargo.toml disable network cards, so the loopback device is the only interface
+    let default = netdev::default_interface().unwrap();
+    assert_eq!(default.name(), "loopback");
+    assert_eq!(default.mac_address(), loopback::MAC_ADDRESS);
+    assert_eq!(default.link_status(), LinkStatus::Up);
+}
+
+#[test_case]
+fn nothing_received_before_sending() {
+    assert_eq!(receive(), None);
+}
+
+#[test_case]
+fn raw_frame_round_trip() {
+    let frame: Vec<u8> = (0..64).collect();
+    device().send_frame(&frame).unwrap();
+    assert_eq!(receive(), Some(frame));
+    assert_eq!(receive(), None);
+}
+
+#[test_case]
+fn ethernet_frame_round_trip() {
+    let payload: Vec<u8> = b"hello, loopback".iter().copied().collect();
+    ethernet::send_payload(ethernet::BROADCAST_MAC, 0x1234, payload.clone()).unwrap();
+
+    let frame = receive().unwrap();
+    // short frames are padded to the minimum frame size
+    assert_eq!(frame.len(), ethernet::MIN_FRAME_SIZE);
+    let parsed = ethernet::EthernetFrameRef::parse(&frame).unwrap();
+    assert_eq!(parsed.header.dst_mac, ethernet::BROADCAST_MAC);
+    assert_eq!(parsed.header.src_mac, loopback::MAC_ADDRESS);
+    assert_eq!(parsed.header.vlan, None);
+    assert_eq!(parsed.header.protocol, 0x1234);
+    assert_eq!(parsed.payload[..payload.len()], payload[..]);
+    assert!(parsed.payload[payload.len()..].iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn frames_received_in_order() {
+    for i in 0..8u8 {
+        device().send_frame(&[i; 60]).unwrap();
+    }
+    for i in 0..8u8 {
+        assert_eq!(receive(), Some(alloc::vec![i; 60]));
+    }
+}
+
+#[test_case]
+fn oversized_frame_rejected() {
+    let frame = alloc::vec![0; netdev::ETHERNET_MTU + ethernet::HEADER_SIZE + 1];
+    assert!(device().send_frame(&frame).is_err());
+    assert_eq!(receive(), None);
+}
+
+#[test_case]
+fn full_queue_drops_frames() {
+    let before = device().stats();
+    for _ in 0..loopback::FRAME_QUEUE_SIZE + 1 {
+        device().send_frame(&[0xab; 60]).unwrap();
+    }
+    let after = device().stats();
+    assert_eq!(after.tx_frames - before.tx_frames, loopback::FRAME_QUEUE_SIZE as u64 + 1);
+    assert_eq!(after.rx_frames - before.rx_frames, loopback::FRAME_QUEUE_SIZE as u64);
+    assert_eq!(after.rx_dropped - before.rx_dropped, 1);
+
+    for _ in 0..loopback::FRAME_QUEUE_SIZE {
+        assert!(receive().is_some());
+    }
+    assert_eq!(receive(), None);
+}